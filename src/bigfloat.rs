@@ -0,0 +1,859 @@
+#[cfg(test)]
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+#[cfg(test)]
+use super::expr::Var;
+use super::expr::{parse_vars, Env, Expr, C};
+#[cfg(test)]
+use super::parse::*;
+use super::scalar::Scalar;
+use num_bigint::BigInt;
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, One, Signed, ToPrimitive, Zero};
+use std::cell::Cell;
+use std::f64::consts::LN_2;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+// 既定の精度(bit)
+pub const DEFAULT_PREC: u32 = 256;
+// eval_digits で上げる精度の上限
+pub const MAX_PREC: u32 = 1 << 13;
+// 初等関数の途中計算で足す bit
+const GUARD: u32 = 32;
+
+thread_local! {
+    // 定数を変換するときの精度. Scalar::from_rat は精度を受け取れないのでここに置く
+    static PREC: Cell<u32> = const { Cell::new(DEFAULT_PREC) };
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Finite,
+    // 符号は mant の符号
+    Inf,
+    NaN,
+}
+
+// mant 2^exp. mant は prec bit 以下に丸め, 末尾の0を落としてある
+#[derive(Debug, Clone)]
+pub struct BigFloat {
+    mant: BigInt,
+    exp: i64,
+    prec: u32,
+    kind: Kind,
+    // Scalar::from_rat で作った定数の元の有理数. 精度の高い値と演算するときに丸め直す
+    exact: Option<C>,
+}
+
+// 絶対値を最も近い prec bit に丸める
+fn finite(mant: BigInt, exp: i64, prec: u32) -> BigFloat {
+    let neg = mant.is_negative();
+    let mut mag = mant.abs();
+    let mut exp = exp;
+    let bits = mag.bits();
+    if (prec as u64) < bits {
+        let shift = (bits - prec as u64) as usize;
+        let half = (&mag >> (shift - 1)) % 2u32;
+        mag = (mag >> shift) + half;
+        exp += shift as i64;
+    }
+    if mag.is_zero() {
+        return BigFloat::zero_prec(prec);
+    }
+    let tz = mag.trailing_zeros().unwrap() as usize;
+    mag >>= tz;
+    BigFloat {
+        mant: if neg { -mag } else { mag },
+        exp: exp + tz as i64,
+        prec,
+        kind: Kind::Finite,
+        exact: None,
+    }
+}
+
+// n / d 2^exp. 切り捨てた余りは最下位bitに残して丸めに使う
+fn quotient(n: &BigInt, d: &BigInt, exp: i64, prec: u32) -> BigFloat {
+    let neg = n.is_negative() != d.is_negative();
+    let (n, d) = (n.abs(), d.abs());
+    if n.is_zero() {
+        return BigFloat::zero_prec(prec);
+    }
+    let s = (prec as i64 + 2 + d.bits() as i64 - n.bits() as i64).max(0) as usize;
+    let num = n << s;
+    let q = &num / &d;
+    let sticky: u32 = if &q * &d == num { 0 } else { 1 };
+    let mant: BigInt = (q << 1usize) + sticky;
+    finite(if neg { -mant } else { mant }, exp - s as i64 - 1, prec)
+}
+
+// 途中のオーバーフローを避けて x 2^e
+fn ldexp(x: f64, e: i64) -> f64 {
+    let mut x = x;
+    let mut e = e.clamp(-2200, 2200) as i32;
+    while 1000 < e {
+        x *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        x *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    x * 2f64.powi(e)
+}
+
+// atanh(1/n) 2^w
+fn atanh_inv(n: u32, w: usize) -> BigInt {
+    let n2 = BigInt::from(n * n);
+    let mut term = (BigInt::one() << w) / n;
+    let mut sum = term.clone();
+    let mut k = 1u32;
+    loop {
+        term /= &n2;
+        if term.is_zero() {
+            break;
+        }
+        sum += &term / (2 * k + 1);
+        k += 1;
+    }
+    sum
+}
+
+// atan(1/n) 2^w
+fn atan_inv(n: u32, w: usize) -> BigInt {
+    let n2 = BigInt::from(n * n);
+    let mut term = (BigInt::one() << w) / n;
+    let mut sum = term.clone();
+    let mut k = 1u32;
+    loop {
+        term /= &n2;
+        if term.is_zero() {
+            break;
+        }
+        if k % 2 == 1 {
+            sum -= &term / (2 * k + 1);
+        } else {
+            sum += &term / (2 * k + 1);
+        }
+        k += 1;
+    }
+    sum
+}
+
+impl BigFloat {
+    fn zero_prec(prec: u32) -> Self {
+        BigFloat {
+            mant: BigInt::zero(),
+            exp: 0,
+            prec,
+            kind: Kind::Finite,
+            exact: None,
+        }
+    }
+
+    fn nan() -> Self {
+        BigFloat {
+            mant: BigInt::zero(),
+            exp: 0,
+            prec: 0,
+            kind: Kind::NaN,
+            exact: None,
+        }
+    }
+
+    fn inf(neg: bool) -> Self {
+        BigFloat {
+            mant: if neg { -BigInt::one() } else { BigInt::one() },
+            exp: 0,
+            prec: 0,
+            kind: Kind::Inf,
+            exact: None,
+        }
+    }
+
+    // 有理数を prec bit に正しく丸める
+    pub fn from_rat(c: C, prec: u32) -> Self {
+        quotient(
+            &BigInt::from(*c.numer()),
+            &BigInt::from(*c.denom()),
+            0,
+            prec,
+        )
+    }
+
+    // 有理数を覚えておく定数. 二項演算で精度の高い方に合わせて丸め直す
+    fn constant(c: C, prec: u32) -> Self {
+        let mut r = BigFloat::from_rat(c, prec);
+        r.exact = Some(c);
+        r
+    }
+
+    // 定数なら prec bit で丸め直す
+    fn lift(self, prec: u32) -> Self {
+        match self.exact {
+            Some(c) if self.prec < prec => BigFloat::constant(c, prec),
+            _ => self,
+        }
+    }
+
+    // 定数どうしは有理数のまま計算し, あふれたら丸めた値で計算する
+    fn binop(
+        self,
+        other: BigFloat,
+        op: fn(&C, &C) -> Option<C>,
+        f: fn(&BigFloat, &BigFloat, u32) -> BigFloat,
+    ) -> Self {
+        let p = self.prec.max(other.prec);
+        if let (Some(a), Some(b)) = (self.exact, other.exact) {
+            if let Some(c) = op(&a, &b) {
+                return BigFloat::constant(c, p);
+            }
+        }
+        f(&self.lift(p), &other.lift(p), p)
+    }
+
+    // f64 は2進の有理数なので prec が53以上なら正確に変換できる
+    pub fn from_f64(x: f64, prec: u32) -> Self {
+        if x.is_nan() {
+            return BigFloat::nan();
+        }
+        if x.is_infinite() {
+            return BigFloat::inf(x < 0.);
+        }
+        let bits = x.to_bits();
+        let field = ((bits >> 52) & 0x7ff) as i64;
+        let frac = bits & ((1 << 52) - 1);
+        let (m, e) = if field == 0 {
+            (frac, -1074)
+        } else {
+            (frac | (1 << 52), field - 1075)
+        };
+        let m = BigInt::from(m);
+        finite(if x < 0. { -m } else { m }, e, prec)
+    }
+
+    pub fn from_int(n: i64, prec: u32) -> Self {
+        finite(BigInt::from(n), 0, prec)
+    }
+
+    pub fn pi(prec: u32) -> Self {
+        let w = (prec + GUARD) as usize;
+        let v = (atan_inv(5, w) << 4usize) - (atan_inv(239, w) << 2usize);
+        finite(v, -(w as i64), prec)
+    }
+
+    pub fn ln2(prec: u32) -> Self {
+        let w = (prec + GUARD) as usize;
+        finite(atanh_inv(3, w) << 1usize, -(w as i64), prec)
+    }
+
+    pub fn prec(&self) -> u32 {
+        self.prec
+    }
+
+    pub fn is_nan(&self) -> bool {
+        self.kind == Kind::NaN
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.kind == Kind::Finite
+    }
+
+    fn is_zero(&self) -> bool {
+        self.kind == Kind::Finite && self.mant.is_zero()
+    }
+
+    fn is_negative(&self) -> bool {
+        self.kind != Kind::NaN && self.mant.is_negative()
+    }
+
+    // |x| < 2^top
+    fn top(&self) -> i64 {
+        self.exp + self.mant.bits() as i64
+    }
+
+    // 精度を変えて丸め直す
+    pub fn round(&self, prec: u32) -> Self {
+        match self.kind {
+            Kind::Finite => finite(self.mant.clone(), self.exp, prec),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn abs(&self) -> Self {
+        let mut r = self.clone();
+        r.mant = r.mant.abs();
+        r.exact = None;
+        r
+    }
+
+    // 末尾の0を落としてあるので整数なら exp >= 0
+    fn to_integer(&self) -> Option<BigInt> {
+        match self.kind {
+            Kind::Finite if 0 <= self.exp => Some(&self.mant << self.exp as usize),
+            _ => None,
+        }
+    }
+
+    fn round_to_integer(&self) -> BigInt {
+        match self.to_integer() {
+            Some(n) => n,
+            None => {
+                let s = (-self.exp) as usize;
+                let mag = (self.mant.abs() + (BigInt::one() << (s - 1))) >> s;
+                if self.mant.is_negative() {
+                    -mag
+                } else {
+                    mag
+                }
+            }
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self.kind {
+            Kind::NaN => f64::NAN,
+            Kind::Inf if self.mant.is_negative() => f64::NEG_INFINITY,
+            Kind::Inf => f64::INFINITY,
+            Kind::Finite => {
+                let shift = self.mant.bits().saturating_sub(64) as usize;
+                let m = (self.mant.abs() >> shift).to_f64().unwrap();
+                let v = ldexp(m, self.exp + shift as i64);
+                if self.mant.is_negative() {
+                    -v
+                } else {
+                    v
+                }
+            }
+        }
+    }
+
+    // 無限大やNaNが絡むときは f64 と同じ規則に従う. 有限の値は符号と0かどうかだけ使う
+    fn special(&self, other: &BigFloat, f: fn(f64, f64) -> f64) -> Self {
+        let clip = |x: &BigFloat| match x.kind {
+            Kind::Finite if x.is_zero() => 0.,
+            Kind::Finite if x.is_negative() => -1.,
+            Kind::Finite => 1.,
+            _ => x.to_f64(),
+        };
+        BigFloat::from_f64(f(clip(self), clip(other)), self.prec.max(other.prec))
+    }
+
+    pub fn add_prec(&self, other: &BigFloat, prec: u32) -> Self {
+        if !self.is_finite() || !other.is_finite() {
+            return self.special(other, |a, b| a + b);
+        }
+        if self.is_zero() {
+            return other.round(prec);
+        }
+        if other.is_zero() {
+            return self.round(prec);
+        }
+        let (big, small) = if other.top() < self.top() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        // 桁が離れていれば小さい方は丸めの向きにしか効かない
+        if prec as i64 + 2 < big.top() - small.top() {
+            let k = (prec as i64 + 2 - big.mant.bits() as i64).max(0) as usize + 1;
+            let nudge = if small.is_negative() { -1 } else { 1 };
+            return finite((&big.mant << k) + nudge, big.exp - k as i64, prec);
+        }
+        let e = self.exp.min(other.exp);
+        let m = (&self.mant << (self.exp - e) as usize) + (&other.mant << (other.exp - e) as usize);
+        finite(m, e, prec)
+    }
+
+    pub fn sub_prec(&self, other: &BigFloat, prec: u32) -> Self {
+        self.add_prec(&-other.clone(), prec)
+    }
+
+    pub fn mul_prec(&self, other: &BigFloat, prec: u32) -> Self {
+        if !self.is_finite() || !other.is_finite() {
+            return self.special(other, |a, b| a * b);
+        }
+        finite(&self.mant * &other.mant, self.exp + other.exp, prec)
+    }
+
+    pub fn div_prec(&self, other: &BigFloat, prec: u32) -> Self {
+        if !self.is_finite() || !other.is_finite() || other.is_zero() {
+            return self.special(other, |a, b| a / b);
+        }
+        quotient(&self.mant, &other.mant, self.exp - other.exp, prec)
+    }
+
+    // x = k log 2 + r と分け, r / 2^s の級数を s 回二乗する
+    pub fn exp_prec(&self, prec: u32) -> Self {
+        match self.kind {
+            Kind::NaN => return BigFloat::nan(),
+            Kind::Inf if self.is_negative() => return BigFloat::zero_prec(prec),
+            Kind::Inf => return BigFloat::inf(false),
+            Kind::Finite => (),
+        }
+        let xf = self.to_f64();
+        if 2f64.powi(40) < xf.abs() {
+            return if 0. < xf {
+                BigFloat::inf(false)
+            } else {
+                BigFloat::zero_prec(prec)
+            };
+        }
+        let k = (xf / LN_2).round() as i64;
+        let s = ((prec as f64).sqrt() as u32).max(4);
+        let wp = prec + GUARD + s + 64 - k.unsigned_abs().leading_zeros();
+        let kl = BigFloat::ln2(wp).mul_prec(&BigFloat::from_int(k, wp), wp);
+        let mut r = self.sub_prec(&kl, wp);
+        r.exp -= s as i64;
+        let one = BigFloat::from_int(1, wp);
+        let (mut sum, mut term) = (one.clone(), one);
+        for n in 1.. {
+            term = term
+                .mul_prec(&r, wp)
+                .div_prec(&BigFloat::from_int(n, wp), wp);
+            if term.is_zero() || term.top() < sum.top() - wp as i64 - 2 {
+                break;
+            }
+            sum = sum.add_prec(&term, wp);
+        }
+        for _ in 0..s {
+            sum = sum.mul_prec(&sum, wp);
+        }
+        sum.exp += k;
+        sum.round(prec)
+    }
+
+    // x = m 2^e (1/√2 <= m < √2) として log m = 2 atanh((m - 1) / (m + 1))
+    pub fn ln_prec(&self, prec: u32) -> Self {
+        match self.kind {
+            Kind::NaN => return BigFloat::nan(),
+            Kind::Inf if self.is_negative() => return BigFloat::nan(),
+            Kind::Inf => return BigFloat::inf(false),
+            Kind::Finite => (),
+        }
+        if self.is_zero() {
+            return BigFloat::inf(true);
+        }
+        if self.is_negative() {
+            return BigFloat::nan();
+        }
+        let mut e = self.top();
+        let mut m = self.round(self.prec);
+        m.exp -= e;
+        if m.to_f64() < std::f64::consts::FRAC_1_SQRT_2 {
+            m.exp += 1;
+            e -= 1;
+        }
+        let wp = prec + GUARD + 64 - e.unsigned_abs().leading_zeros();
+        let one = BigFloat::from_int(1, wp);
+        let z = m.sub_prec(&one, wp).div_prec(&m.add_prec(&one, wp), wp);
+        let z2 = z.mul_prec(&z, wp);
+        let (mut sum, mut zp) = (z.clone(), z);
+        for k in 1.. {
+            zp = zp.mul_prec(&z2, wp);
+            let term = zp.div_prec(&BigFloat::from_int(2 * k + 1, wp), wp);
+            if term.is_zero() || term.top() < sum.top() - wp as i64 - 2 {
+                break;
+            }
+            sum = sum.add_prec(&term, wp);
+        }
+        sum.exp += 1;
+        let el = BigFloat::ln2(wp).mul_prec(&BigFloat::from_int(e, wp), wp);
+        sum.add_prec(&el, wp).round(prec)
+    }
+
+    // x = k pi/2 + r (|r| <= pi/4) と分けて級数で求める
+    fn sin_cos(&self, prec: u32) -> (BigFloat, BigFloat) {
+        if !self.is_finite() {
+            return (BigFloat::nan(), BigFloat::nan());
+        }
+        if self.is_zero() {
+            return (BigFloat::zero_prec(prec), BigFloat::from_int(1, prec));
+        }
+        // 大きい x では pi の誤差が x 倍されるので桁を足す
+        let wp = prec + GUARD + self.top().max(0) as u32;
+        let mut half_pi = BigFloat::pi(wp);
+        half_pi.exp -= 1;
+        let k = self.div_prec(&half_pi, wp).round_to_integer();
+        let kf = finite(k.clone(), 0, wp.max(k.bits() as u32));
+        let r = self.sub_prec(&half_pi.mul_prec(&kf, wp), wp);
+        let r2 = r.mul_prec(&r, wp);
+        let series = |first: BigFloat, off: i64| {
+            let (mut sum, mut term) = (first.clone(), first);
+            for n in 1.. {
+                let d = BigFloat::from_int((2 * n + off - 1) * (2 * n + off), wp);
+                term = -term.mul_prec(&r2, wp).div_prec(&d, wp);
+                if term.is_zero() || term.top() < sum.top() - wp as i64 - 2 {
+                    break;
+                }
+                sum = sum.add_prec(&term, wp);
+            }
+            sum
+        };
+        let (s, c) = if r.is_zero() {
+            (r.clone(), BigFloat::from_int(1, wp))
+        } else {
+            (series(r.clone(), 1), series(BigFloat::from_int(1, wp), 0))
+        };
+        let q = ((k % 4u32) + 4u32) % 4u32;
+        let (s, c) = match q.to_u32().unwrap() {
+            0 => (s, c),
+            1 => (c, -s),
+            2 => (-s, -c),
+            _ => (-c, s),
+        };
+        (s.round(prec), c.round(prec))
+    }
+
+    pub fn sin_prec(&self, prec: u32) -> Self {
+        self.sin_cos(prec).0
+    }
+
+    pub fn cos_prec(&self, prec: u32) -> Self {
+        self.sin_cos(prec).1
+    }
+
+    pub fn tan_prec(&self, prec: u32) -> Self {
+        let (s, c) = self.sin_cos(prec + GUARD);
+        s.div_prec(&c, prec)
+    }
+
+    fn powi_prec(&self, n: &BigInt, prec: u32) -> Self {
+        let wp = prec + GUARD + 64 - (n.bits() as u32).leading_zeros();
+        let mut base = self.round(wp);
+        let mut res = BigFloat::from_int(1, wp);
+        let mut k = n.abs();
+        while !k.is_zero() {
+            if &k % 2u32 == BigInt::one() {
+                res = res.mul_prec(&base, wp);
+            }
+            k >>= 1usize;
+            if !k.is_zero() {
+                base = base.mul_prec(&base, wp);
+            }
+        }
+        if n.is_negative() {
+            res = BigFloat::from_int(1, wp).div_prec(&res, wp);
+        }
+        res.round(prec)
+    }
+
+    // 整数乗は負の底でもよい. それ以外は exp(y log x)
+    pub fn pow_prec(&self, other: &BigFloat, prec: u32) -> Self {
+        if let Some(n) = other.to_integer() {
+            if self.is_finite() && n.bits() <= 62 {
+                return self.powi_prec(&n, prec);
+            }
+        }
+        if !self.is_finite() || !other.is_finite() {
+            return self.special(other, f64::powf);
+        }
+        if self.is_zero() {
+            return if other.is_negative() {
+                BigFloat::inf(false)
+            } else {
+                BigFloat::zero_prec(prec)
+            };
+        }
+        if self.is_negative() {
+            return BigFloat::nan();
+        }
+        // exp の引数の絶対誤差が相対誤差になるので, 引数の大きさだけ桁を足す
+        let t = other.mul_prec(&self.ln_prec(prec + GUARD), prec + GUARD);
+        let wp = prec + GUARD + t.top().max(0) as u32;
+        other.mul_prec(&self.ln_prec(wp), wp).exp_prec(prec)
+    }
+
+    // 有効数字 n 桁の 1.234e5 の形
+    pub fn to_string_digits(&self, n: usize) -> String {
+        match self.kind {
+            Kind::NaN => return String::from("NaN"),
+            Kind::Inf if self.is_negative() => return String::from("-inf"),
+            Kind::Inf => return String::from("inf"),
+            Kind::Finite if self.is_zero() => return String::from("0"),
+            Kind::Finite => (),
+        }
+        let n = n.max(1);
+        let shift = self.mant.bits().saturating_sub(64) as usize;
+        let m = (self.mant.abs() >> shift).to_f64().unwrap();
+        let mut e10 = (m.log10() + (self.exp + shift as i64) as f64 * 2f64.log10()).floor() as i64;
+        let ten = BigInt::from(10);
+        let lo = ten.pow(n as u32 - 1);
+        let hi = &lo * 10u32;
+        // 見積もりが1桁ずれていたら直す
+        let q = loop {
+            let k = n as i64 - 1 - e10;
+            let (mut num, mut den) = (self.mant.abs(), BigInt::one());
+            if 0 <= k {
+                num *= ten.pow(k as u32);
+            } else {
+                den *= ten.pow((-k) as u32);
+            }
+            if 0 <= self.exp {
+                num <<= self.exp as usize;
+            } else {
+                den <<= (-self.exp) as usize;
+            }
+            let q = (&num * 2u32 + &den) / (&den * 2u32);
+            if hi <= q {
+                e10 += 1;
+            } else if q < lo {
+                e10 -= 1;
+            } else {
+                break q;
+            }
+        };
+        let digits = q.to_string();
+        let sign = if self.is_negative() { "-" } else { "" };
+        if n == 1 {
+            format!("{}{}e{}", sign, digits, e10)
+        } else {
+            format!("{}{}.{}e{}", sign, &digits[..1], &digits[1..], e10)
+        }
+    }
+
+    // f の中での定数の変換に bits を使う
+    pub fn with_precision<R>(bits: u32, f: impl FnOnce() -> R) -> R {
+        let old = PREC.with(|p| p.replace(bits));
+        let res = f();
+        PREC.with(|p| p.set(old));
+        res
+    }
+}
+
+// 値として比べる. NaN はどれとも等しくない
+impl PartialEq for BigFloat {
+    fn eq(&self, other: &BigFloat) -> bool {
+        self.kind != Kind::NaN
+            && self.kind == other.kind
+            && self.mant == other.mant
+            && self.exp == other.exp
+    }
+}
+
+// 精度から決まる桁数で書く
+impl fmt::Display for BigFloat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = (self.prec as f64 * 2f64.log10()).floor() as usize;
+        write!(f, "{}", self.to_string_digits(digits))
+    }
+}
+
+// 二項演算は精度の高い方に合わせる. 定数は丸め直してから計算する
+impl Add for BigFloat {
+    type Output = BigFloat;
+    fn add(self, other: BigFloat) -> BigFloat {
+        self.binop(other, C::checked_add, BigFloat::add_prec)
+    }
+}
+
+impl Sub for BigFloat {
+    type Output = BigFloat;
+    fn sub(self, other: BigFloat) -> BigFloat {
+        self.binop(other, C::checked_sub, BigFloat::sub_prec)
+    }
+}
+
+impl Neg for BigFloat {
+    type Output = BigFloat;
+    fn neg(mut self) -> BigFloat {
+        self.mant = -self.mant;
+        self.exact = self.exact.and_then(|c| C::zero().checked_sub(&c));
+        self
+    }
+}
+
+impl Mul for BigFloat {
+    type Output = BigFloat;
+    fn mul(self, other: BigFloat) -> BigFloat {
+        self.binop(other, C::checked_mul, BigFloat::mul_prec)
+    }
+}
+
+impl Div for BigFloat {
+    type Output = BigFloat;
+    fn div(self, other: BigFloat) -> BigFloat {
+        self.binop(other, C::checked_div, BigFloat::div_prec)
+    }
+}
+
+// 定数はまず with_precision で決めた精度(既定は DEFAULT_PREC)で丸めるが,
+// 有理数を覚えておき, 二項演算で精度の高い値と出会ったらその精度で丸め直す.
+// ただし sin(1/3) のように定数だけの初等関数はその時点の精度で計算される
+impl Scalar for BigFloat {
+    fn from_rat(c: C) -> Self {
+        BigFloat::constant(c, PREC.with(|p| p.get()))
+    }
+    fn sin(&self) -> Self {
+        self.sin_prec(self.prec)
+    }
+    fn cos(&self) -> Self {
+        self.cos_prec(self.prec)
+    }
+    fn tan(&self) -> Self {
+        self.tan_prec(self.prec)
+    }
+    fn ln(&self) -> Self {
+        self.ln_prec(self.prec)
+    }
+    fn exp(&self) -> Self {
+        self.exp_prec(self.prec)
+    }
+    fn pow(&self, other: &Self) -> Self {
+        let p = self.prec.max(other.prec);
+        self.clone().lift(p).pow_prec(&other.clone().lift(p), p)
+    }
+}
+
+impl Expr {
+    // 値は有理数で与え, 定数と一緒に prec bit に丸める
+    pub fn eval_bigfloat(&self, vars: &str, vals: &[C], prec: u32, e: &Env) -> BigFloat {
+        let parsed = if vars.trim().is_empty() {
+            vec![]
+        } else {
+            parse_vars(vars, e)
+        };
+        let vals: Vec<BigFloat> = vals.iter().map(|c| BigFloat::from_rat(*c, prec)).collect();
+        BigFloat::with_precision(prec, || self.eval_generic_internal(&parsed, &vals))
+    }
+
+    // 精度を倍にした値と有効数字 digits 桁まで一致するまで精度を上げる.
+    // MAX_PREC でも一致しない(値が0の場合など)ときや有限でないときは None
+    pub fn eval_digits(&self, vars: &str, vals: &[C], digits: usize, e: &Env) -> Option<BigFloat> {
+        let need = (digits as f64 * 10f64.log2()).ceil() as i64;
+        let mut prec = need as u32 + GUARD;
+        let mut prev = self.eval_bigfloat(vars, vals, prec, e);
+        while prec < MAX_PREC {
+            prec *= 2;
+            let cur = self.eval_bigfloat(vars, vals, prec, e);
+            if !cur.is_finite() || !prev.is_finite() {
+                return None;
+            }
+            let diff = prev.sub_prec(&cur, prec);
+            if !cur.is_zero() && (diff.is_zero() || diff.top() + need + 2 <= cur.top()) {
+                return Some(cur);
+            }
+            prev = cur;
+        }
+        None
+    }
+}
+
+#[test]
+fn bigfloat_arithmetic() {
+    let p = DEFAULT_PREC;
+    let rat = |n: i64, d: i64| BigFloat::from_rat(C::new(n, d), p);
+    // 定数
+    assert_eq!(
+        BigFloat::pi(p).to_string_digits(70),
+        "3.141592653589793238462643383279502884197169399375105820974944592307816e0"
+    );
+    assert_eq!(
+        rat(1, 1).exp_prec(p).to_string_digits(40),
+        "2.718281828459045235360287471352662497757e0"
+    );
+    assert_eq!(
+        BigFloat::ln2(p).to_string_digits(40),
+        "6.931471805599453094172321214581765680755e-1"
+    );
+    assert_eq!(rat(3, 4).to_f64(), 0.75);
+    assert_eq!(rat(-3, 4).to_string_digits(3), "-7.50e-1");
+    assert_eq!(BigFloat::from_f64(0.1, p).to_f64(), 0.1);
+
+    // 恒等式が精度の分だけ成り立つ
+    let small = |a: BigFloat, b: BigFloat, bits: i64| {
+        let d = a.sub_prec(&b, p);
+        d.is_zero() || d.top() < b.top() - bits
+    };
+    let x = rat(7, 3);
+    let (s, c) = (x.sin_prec(p), x.cos_prec(p));
+    assert!(small(
+        s.clone() * s.clone() + c.clone() * c.clone(),
+        rat(1, 1),
+        250
+    ));
+    assert!(small(x.tan_prec(p), s / c, 250));
+    let y = rat(355, 113);
+    assert!(small(y.ln_prec(p).exp_prec(p), y.clone(), 250));
+    assert!(small(
+        rat(2, 1).pow_prec(&rat(1, 2), p).pow_prec(&rat(2, 1), p),
+        rat(2, 1),
+        250
+    ));
+    assert!(small(rat(1, 3) * rat(3, 1), rat(1, 1), 254));
+    // 大きい引数の周期の還元
+    let v = rat(355, 1).sin_prec(p).to_f64();
+    assert!((v - 355f64.sin()).abs() < 1e-15 * v.abs(), "{}", v);
+    let v = BigFloat::from_f64(1e22, p).cos_prec(p).to_f64();
+    assert!((v - 1e22f64.cos()).abs() < 1e-15, "{}", v);
+
+    // 負の底と特殊な値
+    assert_eq!(rat(-2, 1).pow_prec(&rat(3, 1), p), rat(-8, 1));
+    assert!(rat(-8, 1).pow_prec(&rat(1, 3), p).is_nan());
+    assert_eq!((rat(1, 1) / rat(0, 1)).to_f64(), f64::INFINITY);
+    assert_eq!(rat(0, 1).ln_prec(p).to_f64(), f64::NEG_INFINITY);
+    assert!(rat(-1, 1).ln_prec(p).is_nan());
+    assert_eq!(
+        format!("{}", BigFloat::from_rat(C::new(1, 3), 24)),
+        "3.333333e-1"
+    );
+}
+
+#[test]
+fn bigfloat_cancellation() {
+    let e = &Environment::new();
+    // f64 では 1 - cos x が 0 になる
+    let f = parse_expr("(1 - cos(x)) / x^2", e);
+    let x = C::new(1, 100_000_000);
+    assert_eq!(f.eval("x", &vec![1e-8], e), 0.);
+    let v = f.eval_digits("x", &[x], 30, e).unwrap();
+    assert_eq!(v.to_string_digits(30), "4.99999999999999995833333333333e-1");
+
+    // 導関数 exp(x) - 1 の桁落ち
+    let d = parse_expr("exp(x) - x", e).diff("x", e).reduce(e);
+    let x = C::new(1, 10_000_000_000);
+    assert!((d.eval("x", &vec![1e-10], e) / 1e-10 - 1.).abs() > 1e-8);
+    let v = d.eval_digits("x", &[x], 20, e).unwrap();
+    assert_eq!(v.to_string_digits(20), "1.0000000000500000000e-10");
+    // 値が0だと相対的な桁は決まらない
+    assert!(parse_expr("x - x", e)
+        .eval_digits("x", &[x], 10, e)
+        .is_none());
+
+    // 微分グラフも同じ精度で評価できる
+    let g = parse_expr("x^2 * y + sin(x * y) / 3", e);
+    let vars: Vec<Var> = parse_vars("x y", e);
+    let deriv = Deriv::new(g, e, "x");
+    let exact = deriv.backward_grad("x y", &vec![0.5, -1.25], e);
+    let big = BigFloat::with_precision(200, || {
+        let vals = [BigFloat::from_f64(0.5, 200), BigFloat::from_f64(-1.25, 200)];
+        deriv.backward_grad_generic(&vars, &vals)
+    });
+    for (b, x) in big.iter().zip(&exact) {
+        assert!(b.prec() == 200 && (b.to_f64() - x).abs() < 1e-15);
+    }
+}
+
+#[test]
+fn bigfloat_constant_precision() {
+    let e = &Environment::new();
+    let p = 1024;
+    let vars: Vec<Var> = parse_vars("x", e);
+    let one = [BigFloat::from_int(1, p)];
+    // with_precision なしでも定数は値の精度で丸め直される
+    let f = parse_expr("x * (1 / 3)", e);
+    let third = BigFloat::from_rat(C::new(1, 3), p);
+    for g in [f.clone(), f.reduce(e)].iter() {
+        let v = g.eval_generic_internal(&vars, &one);
+        assert!(v.prec() == p && v == third, "{:?}", g);
+    }
+    let v = parse_expr("x ^ (1 / 3)", e).eval_generic_internal(&vars, &[BigFloat::from_int(8, p)]);
+    assert_eq!(v, BigFloat::from_int(2, p));
+    // 定数だけの式は定数の精度のまま
+    let v = parse_expr("(1 / 3) - x", e).eval_generic_internal(&vars, &[BigFloat::from_int(0, 64)]);
+    assert_eq!(v, BigFloat::from_rat(C::new(1, 3), DEFAULT_PREC));
+    // 定数だけの初等関数はその時点の精度で計算される
+    let v = parse_expr("x * sin(1 / 3)", e).eval_generic_internal(&vars, &one);
+    let s = third.sin_prec(p);
+    assert!(v != s && v == s.round(DEFAULT_PREC).round(p));
+}
@@ -0,0 +1,367 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Bop, Env, Expr, Rc, Var, C};
+#[cfg(test)]
+use super::parse::*;
+use super::scalar::Scalar;
+#[cfg(test)]
+use std::f64::consts::PI;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub const I: Complex = Complex { re: 0., im: 1. };
+
+    pub fn new(re: f64, im: f64) -> Self {
+        Complex { re, im }
+    }
+
+    pub fn real(re: f64) -> Self {
+        Complex { re, im: 0. }
+    }
+
+    pub fn abs(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    // 偏角は (-pi, pi]
+    pub fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn conj(self) -> Self {
+        Complex::new(self.re, -self.im)
+    }
+
+    pub fn is_finite(self) -> bool {
+        self.re.is_finite() && self.im.is_finite()
+    }
+
+    pub fn exp(self) -> Self {
+        let r = self.re.exp();
+        Complex::new(r * self.im.cos(), r * self.im.sin())
+    }
+
+    // 主値. 負の実軸が分枝切断で, その上では偏角 pi の側を取る
+    pub fn ln(self) -> Self {
+        Complex::new(self.abs().ln(), self.arg())
+    }
+
+    pub fn sin(self) -> Self {
+        Complex::new(
+            self.re.sin() * self.im.cosh(),
+            self.re.cos() * self.im.sinh(),
+        )
+    }
+
+    pub fn cos(self) -> Self {
+        Complex::new(
+            self.re.cos() * self.im.cosh(),
+            -self.re.sin() * self.im.sinh(),
+        )
+    }
+
+    // sin/cos だと虚部が大きいとき inf / inf になる
+    pub fn tan(self) -> Self {
+        let (a, b) = (2. * self.re, 2. * self.im);
+        if 20. < b.abs() {
+            return Complex::new(0., b.signum());
+        }
+        let d = a.cos() + b.cosh();
+        Complex::new(a.sin() / d, b.sinh() / d)
+    }
+
+    pub fn powi(self, n: i32) -> Self {
+        let mut base = if n < 0 {
+            Complex::real(1.) / self
+        } else {
+            self
+        };
+        let mut k = n.unsigned_abs();
+        let mut res = Complex::real(1.);
+        while 0 < k {
+            if k & 1 == 1 {
+                res = res * base;
+            }
+            base = base * base;
+            k >>= 1;
+        }
+        res
+    }
+
+    // 主値 exp(w log z). 整数乗は掛け算で済ませる
+    pub fn pow(self, w: Complex) -> Self {
+        if w.im == 0. && w.re.fract() == 0. && w.re.abs() <= 64. {
+            return self.powi(w.re as i32);
+        }
+        if self.re == 0. && self.im == 0. {
+            return if 0. < w.re {
+                Complex::real(0.)
+            } else {
+                Complex::new(f64::NAN, f64::NAN)
+            };
+        }
+        (w * self.ln()).exp()
+    }
+}
+
+impl From<f64> for Complex {
+    fn from(re: f64) -> Self {
+        Complex::real(re)
+    }
+}
+
+// 1 + 2i, 1 - 2i, 2i, 1 のように書く
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.im == 0. {
+            write!(f, "{}", self.re)
+        } else if self.re == 0. {
+            write!(f, "{}i", self.im)
+        } else if self.im < 0. {
+            write!(f, "{} - {}i", self.re, -self.im)
+        } else {
+            write!(f, "{} + {}i", self.re, self.im)
+        }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl Scalar for Complex {
+    fn from_rat(c: C) -> Self {
+        Complex::real(*c.numer() as f64 / *c.denom() as f64)
+    }
+    fn sin(&self) -> Self {
+        Complex::sin(*self)
+    }
+    fn cos(&self) -> Self {
+        Complex::cos(*self)
+    }
+    fn tan(&self) -> Self {
+        Complex::tan(*self)
+    }
+    fn ln(&self) -> Self {
+        Complex::ln(*self)
+    }
+    fn exp(&self) -> Self {
+        Complex::exp(*self)
+    }
+    fn pow(&self, other: &Self) -> Self {
+        Complex::pow(*self, *other)
+    }
+}
+
+// Smithの方法. |c|, |d| の大きい方で割ってオーバーフローを避ける
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, other: Complex) -> Complex {
+        let (a, b, c, d) = (self.re, self.im, other.re, other.im);
+        if d.abs() <= c.abs() {
+            let r = d / c;
+            let den = c + d * r;
+            Complex::new((a + b * r) / den, (b - a * r) / den)
+        } else {
+            let r = c / d;
+            let den = c * r + d;
+            Complex::new((a * r + b) / den, (b * r - a) / den)
+        }
+    }
+}
+
+// 虚数単位 i を値に含めた変数列. i は env で虚数単位にしていれば足す
+pub(crate) fn bind_imag(vars: &str, vals: &[Complex], e: &Env) -> (Vec<Var>, Vec<Complex>) {
+    // 定数だけの式は変数なしで評価する
+    let parsed = if vars.trim().is_empty() {
+        vec![]
+    } else {
+        parse_vars(vars, e)
+    };
+    assert!(
+        parsed.len() == vals.len(),
+        "number of variables and values differ"
+    );
+    let mut pairs: Vec<(Var, Complex)> = parsed.into_iter().zip(vals.iter().cloned()).collect();
+    let imag = e.borrow().imag;
+    if let Some(i) = imag {
+        if pairs.iter().all(|(v, _)| *v != i) {
+            pairs.push((i, Complex::I));
+            pairs.sort_by_key(|(v, _)| *v);
+        }
+    }
+    pairs.into_iter().unzip()
+}
+
+impl Expr {
+    // re + im i
+    pub fn new_complex(re: C, im: C, env: &Env) -> Rc<Expr> {
+        let im = Expr::new_binop(
+            Bop::Mul,
+            Expr::new_num_from_rat(im, env),
+            Expr::imag(env),
+            env,
+        );
+        Expr::new_binop(Bop::Add, Expr::new_num_from_rat(re, env), im, env).reduce(env)
+    }
+
+    // 変数も値も複素数として評価する. log, pow は主値
+    pub fn eval_complex(&self, vars: &str, vals: &[Complex], e: &Env) -> Complex {
+        let (vars, vals) = bind_imag(vars, vals, e);
+        self.eval_complex_internal(&vars, &vals)
+    }
+
+    pub fn eval_complex_internal(&self, vars: &[Var], vals: &[Complex]) -> Complex {
+        self.eval_generic_internal(vars, vals)
+    }
+}
+
+impl Deriv {
+    // 複素数での勾配. 正則な式なら複素微分になる. 結果は変数のidの昇順
+    pub fn backward_grad_complex(&self, vars: &str, vals: &[Complex], env: &Env) -> Vec<Complex> {
+        let (bound, bvals) = bind_imag(vars, vals, env);
+        let g = self.grad_complex(&bound, &bvals);
+        parse_vars(vars, env)
+            .iter()
+            .map(|v| g[bound.binary_search(v).unwrap()])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+fn close(a: Complex, b: Complex, tol: f64) -> bool {
+    (a - b).abs() <= tol * b.abs().max(1.)
+}
+
+#[test]
+fn complex_arithmetic() {
+    let z = Complex::new(3., -4.);
+    assert_eq!(z.abs(), 5.);
+    assert_eq!(z * z.conj(), Complex::real(25.));
+    assert!(close(z / z, Complex::real(1.), 1e-15));
+    assert!(close(Complex::new(0., PI).exp(), Complex::real(-1.), 1e-15));
+    // 主値
+    assert_eq!(Complex::real(-1.).ln(), Complex::new(0., PI));
+    assert!(close(
+        Complex::real(-4.).pow(Complex::real(0.5)),
+        Complex::new(0., 2.),
+        1e-15
+    ));
+    assert!(close(
+        Complex::I.pow(Complex::I),
+        Complex::real((-PI / 2.).exp()),
+        1e-15
+    ));
+    assert_eq!(Complex::I.powi(-3), Complex::I);
+    assert!(close(Complex::new(0.5, 100.).tan(), Complex::I, 1e-15));
+    let w = Complex::new(0.3, 0.8);
+    let s = w.sin();
+    let c = w.cos();
+    assert!(close(s * s + c * c, Complex::real(1.), 1e-15));
+    assert!(close(w.tan(), s / c, 1e-15));
+
+    assert_eq!(format!("{}", Complex::new(1., 2.)), "1 + 2i");
+    assert_eq!(format!("{}", Complex::new(1., -2.)), "1 - 2i");
+    assert_eq!(format!("{}", Complex::new(0., -0.5)), "-0.5i");
+    assert_eq!(format!("{}", Complex::real(3.)), "3");
+
+    // 虚数単位と定数
+    let e = &Environment::new();
+    e.borrow_mut().use_imag();
+    assert_eq!(parse_expr("i * i", e).reduce(e), Expr::new_num(-1, e));
+    assert_eq!(
+        parse_expr("i ^ 7", e).reduce(e),
+        parse_expr("-i", e).reduce(e)
+    );
+    assert!(parse_expr("i", e).is_imag(e) && !parse_expr("i", e).is_real(e));
+    let c = Expr::new_complex(C::new(1, 2), C::new(-3, 1), e);
+    assert_eq!(c.eval_complex("", &[], e), Complex::new(0.5, -3.));
+    let f = parse_expr("(1 + 2 * i) * (3 - i) / z", e);
+    assert!(close(
+        f.eval_complex("z", &[Complex::I], e),
+        Complex::new(5., -5.),
+        1e-15
+    ));
+    let f = parse_expr("exp(i * t)", e);
+    let v = f.eval_complex("t", &[Complex::real(1.)], e);
+    assert!(close(v, Complex::new(1f64.cos(), 1f64.sin()), 1e-15));
+}
+
+#[test]
+fn complex_derivatives_holomorphic() {
+    let e = &Environment::new();
+    e.borrow_mut().use_imag();
+    let f = parse_expr(
+        "exp(z^2) * sin(z) / (z + 2) + log(z) * z^(1 / 3) + tan(z) - cos(i * z)",
+        e,
+    );
+    let fd = f.diff("z", e).reduce(e);
+    let d = Deriv::new(f.clone(), e, "z");
+    let mut dr = d.clone();
+    dr.reduce(e);
+    for z in [
+        Complex::new(0.7, 0.4),
+        Complex::new(-1.2, 0.9),
+        Complex::new(0.3, -1.1),
+    ] {
+        let sym = fd.eval_complex("z", &[z], e);
+        let g = d.backward_grad_complex("z", &[z], e);
+        let gr = dr.backward_grad_complex("z", &[z], e);
+        assert!(
+            close(g[0], sym, 1e-12) && close(gr[0], sym, 1e-12),
+            "{} {}",
+            g[0],
+            sym
+        );
+        // 実軸方向と虚軸方向の差分が一致する (Cauchy-Riemann)
+        let h = 1e-6;
+        let fz = |w: Complex| f.eval_complex("z", &[w], e);
+        for dir in [Complex::real(h), Complex::new(0., h)] {
+            let num = (fz(z + dir) - fz(z - dir)) / (dir * Complex::real(2.));
+            assert!(close(num, sym, 1e-7), "{} {} {}", z, num, sym);
+        }
+    }
+
+    // 二変数. d/dy x exp(i y) = i x exp(i y)
+    let f = parse_expr("x * exp(i * y) + x^2", e);
+    let (x, y) = (Complex::new(1., 2.), Complex::new(0.5, -0.25));
+    let g = Deriv::new(f, e, "x").backward_grad_complex("x y", &[x, y], e);
+    let eiy = (Complex::I * y).exp();
+    assert!(close(g[0], eiy + Complex::real(2.) * x, 1e-15));
+    assert!(close(g[1], Complex::I * x * eiy, 1e-15));
+}
@@ -1,6 +1,12 @@
-use super::expr::{Env, Environment, Expr, Var};
+use super::complex::Complex;
+use super::dual::Dual;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Env, Expr, Var};
+use super::interval::Interval;
 use super::parse::*;
-use std::collections::{HashMap, HashSet};
+use super::scalar::Scalar;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::rc::Rc;
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -16,6 +22,8 @@ let reverse_graph: Vec<Vec<Edge>> = vec![vec![]; nodesize];
 pub struct Deriv {
     size: usize,
     pub root: usize,
+    // 各出力のid
+    pub roots: Vec<usize>,
     leafs: HashMap<usize, Option<Var>>,
     pub vars: HashMap<Var, usize>,
     pub graph: Vec<Vec<Edge>>,
@@ -24,27 +32,37 @@ pub struct Deriv {
 
 impl Deriv {
     pub fn new(expr: Rc<Expr>, e: &Env, v: &str) -> Self {
+        Deriv::new_multi(vec![expr], e, v)
+    }
+    // 複数の出力を一つのグラフにまとめる. 共通部分式は共有される
+    pub fn new_multi(exprs: Vec<Rc<Expr>>, e: &Env, v: &str) -> Self {
         let mut m = HashMap::new();
-        let expr = expr.reduce(e);
-        expr.post_index(&mut 0, &mut m);
+        let exprs: Vec<Rc<Expr>> = exprs.iter().map(|expr| expr.reduce(e)).collect();
+        let mut i = 0;
+        for expr in &exprs {
+            expr.post_index(&mut i, &mut m);
+        }
         let size = m.len();
         let (mut graph, mut reverse_graph) = (vec![vec![]; size], vec![vec![]; size]);
         let mut leafs = HashMap::new();
         let mut memo = HashSet::new();
-        Deriv::construct(
-            &(*expr),
-            e,
-            v,
-            &m,
-            &mut graph,
-            &mut reverse_graph,
-            &mut leafs,
-            &mut memo,
-        );
+        for expr in &exprs {
+            Deriv::construct(
+                expr,
+                e,
+                v,
+                &m,
+                &mut graph,
+                &mut reverse_graph,
+                &mut leafs,
+                &mut memo,
+            );
+        }
         // ここでLeafも計算はできる.
         Deriv {
             size,
             root: size - 1,
+            roots: exprs.iter().map(|expr| m[&**expr]).collect(),
             leafs: leafs.clone(),
             vars: leafs
                 .into_iter()
@@ -55,6 +73,7 @@ impl Deriv {
             reverse_graph,
         }
     }
+    #[allow(clippy::too_many_arguments)]
     fn construct(
         expr: &Expr,
         e: &Env,
@@ -69,14 +88,14 @@ impl Deriv {
         // 辺を追加する
         match expr {
             Expr::UnOp { exp, .. } => {
-                let parent_id = postids[&expr];
-                let child_id = postids[&(*exp)];
+                let parent_id = postids[expr];
+                let child_id = postids[exp];
                 if memo.contains(&(parent_id, child_id)) {
                     return;
                 } else {
                     memo.insert((parent_id, child_id));
                 }
-                Deriv::construct(&(*exp), e, v, postids, graph, reverse_graph, leafs, memo);
+                Deriv::construct(exp, e, v, postids, graph, reverse_graph, leafs, memo);
                 // diffじゃだめで, 一段だけやらなきゃ
                 let mut v = expr.diff_comp(v, e);
                 assert!(v.len() == 1);
@@ -93,16 +112,16 @@ impl Deriv {
                 reverse_graph[child_id].push(redge);
             }
             Expr::BinOp { exp1, exp2, .. } => {
-                let parent_id = postids[&expr];
-                let child1_id = postids[&(*exp1)];
-                let child2_id = postids[&(*exp2)];
+                let parent_id = postids[expr];
+                let child1_id = postids[exp1];
+                let child2_id = postids[exp2];
                 if memo.contains(&(parent_id, child1_id)) {
                     return;
                 } else {
                     memo.insert((parent_id, child1_id));
                 }
-                Deriv::construct(&(*exp1), e, v, postids, graph, reverse_graph, leafs, memo);
-                Deriv::construct(&(*exp2), e, v, postids, graph, reverse_graph, leafs, memo);
+                Deriv::construct(exp1, e, v, postids, graph, reverse_graph, leafs, memo);
+                Deriv::construct(exp2, e, v, postids, graph, reverse_graph, leafs, memo);
                 let mut v = expr.diff_comp(v, e);
                 assert!(v.len() == 2);
                 let d2 = v.pop().expect("");
@@ -133,7 +152,7 @@ impl Deriv {
         }
     }
 
-    fn intersect(mut b1: usize, mut b2: usize, doms: &Vec<Option<usize>>) -> usize {
+    fn intersect(mut b1: usize, mut b2: usize, doms: &[Option<usize>]) -> usize {
         while b1 != b2 {
             while b1 < b2 {
                 b1 = doms[b1].expect("dominator intersection failure");
@@ -153,10 +172,10 @@ impl Deriv {
         while changed {
             changed = false;
             for u in (0..self.size - 1).rev() {
-                let mut new_idom = std::usize::MAX;
+                let mut new_idom = usize::MAX;
                 for &Edge { to: v, .. } in &self.reverse_graph[u] {
                     if let Some(_i) = doms[v] {
-                        if new_idom == std::usize::MAX {
+                        if new_idom == usize::MAX {
                             new_idom = v;
                         } else {
                             new_idom = Deriv::intersect(v, new_idom, &doms);
@@ -171,12 +190,12 @@ impl Deriv {
         }
         let domtree = doms;
         let mut res: Vec<HashSet<usize>> = vec![HashSet::new(); self.size];
-        for i in 0..self.size {
-            res[i].insert(i);
+        for (i, r) in res.iter_mut().enumerate() {
+            r.insert(i);
             let mut cur = i;
             while cur != domtree[cur].unwrap() {
                 let dom = domtree[cur].unwrap();
-                res[i].insert(dom);
+                r.insert(dom);
                 cur = dom;
             }
         }
@@ -185,12 +204,15 @@ impl Deriv {
 
     // 逆支配関係を求める.(pdomされてるのが入ってる)
     // さっきと逆
-    fn intersect_p(mut b1: usize, mut b2: usize, pdoms: &Vec<Option<usize>>) -> usize {
+    // super_rootは葉より先にあるものとして順序を付ける
+    fn intersect_p(mut b1: usize, mut b2: usize, pdoms: &[Option<usize>]) -> usize {
+        let sink = pdoms.len() - 1;
+        let rank = |b: usize| if b == sink { -1 } else { b as isize };
         while b1 != b2 {
-            while b2 < b1 || b2 == pdoms.len() - 1 {
+            while rank(b2) < rank(b1) {
                 b1 = pdoms[b1].expect("dominator intersection failure");
             }
-            while b1 < b2 || b1 == pdoms.len() - 1 {
+            while rank(b1) < rank(b2) {
                 b2 = pdoms[b2].expect("dominator intersection failure");
             }
         }
@@ -199,17 +221,22 @@ impl Deriv {
     // 逆支配関係を求める.(pdomされてるのが入ってる)
     // self.sizeがsuper_root
     fn pdom_rel(&self) -> Vec<HashSet<usize>> {
-        let mut pdoms: Vec<Option<usize>> = (0..self.size + 1).map(|e| None).collect();
+        let mut pdoms: Vec<Option<usize>> = (0..self.size + 1).map(|_e| None).collect();
         pdoms[self.size] = Some(self.size);
 
         let mut changed = true;
         while changed {
             changed = false;
             for u in 0..self.size {
-                let mut new_idom = std::usize::MAX;
+                // 葉の後にはsuper_rootだけがある
+                let mut new_idom = if self.graph[u].is_empty() {
+                    self.size
+                } else {
+                    usize::MAX
+                };
                 for &Edge { to: v, .. } in &self.graph[u] {
                     if let Some(_i) = pdoms[v] {
-                        if new_idom == std::usize::MAX {
+                        if new_idom == usize::MAX {
                             new_idom = v;
                         } else {
                             new_idom = Deriv::intersect_p(v, new_idom, &pdoms);
@@ -226,17 +253,15 @@ impl Deriv {
         for (&i, &_v) in &self.leafs {
             pdoms[i] = Some(i);
         }
-        for i in 0..pdoms.len() - 1 {
-            assert!(pdoms[i] != Some(self.size));
-        }
         let pdomtree = pdoms;
         let mut res: Vec<HashSet<usize>> = vec![HashSet::new(); self.size];
-        for i in 0..self.size {
-            res[i].insert(i);
+        for (i, r) in res.iter_mut().enumerate() {
+            r.insert(i);
             let mut cur = i;
-            while cur != pdomtree[cur].unwrap() {
+            // 変数が複数あれば葉の手前で止まる
+            while cur != pdomtree[cur].unwrap() && pdomtree[cur] != Some(self.size) {
                 let pdom = pdomtree[cur].unwrap();
-                res[i].insert(pdom);
+                r.insert(pdom);
                 cur = pdom;
             }
         }
@@ -245,8 +270,8 @@ impl Deriv {
 
     fn factor_subgraphs(
         &self,
-        doms: &Vec<HashSet<usize>>,
-        pdoms: &Vec<HashSet<usize>>,
+        doms: &[HashSet<usize>],
+        pdoms: &[HashSet<usize>],
     ) -> Vec<(usize, usize)> {
         let mut factor_dom_nodes: HashSet<usize> = HashSet::new();
         let mut factor_pdom_nodes: HashSet<usize> = HashSet::new();
@@ -263,30 +288,23 @@ impl Deriv {
         let mut res = vec![];
         // domなら fd > n
         for fd in factor_dom_nodes {
-            for n in 0..self.size {
-                if n != fd && doms[n].contains(&fd) && 2 <= self.reverse_graph[n].len() {
+            for (n, dom) in doms.iter().enumerate() {
+                if n != fd && dom.contains(&fd) && 2 <= self.reverse_graph[n].len() {
                     res.push((fd, n));
                 }
             }
         }
         // pdomなら fpd < n
         for fpd in factor_pdom_nodes {
-            for n in 0..self.size {
-                if n != fpd && pdoms[n].contains(&fpd) && 2 <= self.graph[n].len() {
+            for (n, pdom) in pdoms.iter().enumerate() {
+                if n != fpd && pdom.contains(&fpd) && 2 <= self.graph[n].len() {
                     res.push((fpd, n));
                 }
             }
         }
         res.sort_by(|(x1, y1), (x2, y2)| {
-            let absdiff = |x, y| {
-                if x > y {
-                    x - y
-                } else {
-                    y - x
-                }
-            };
-            let diff1 = absdiff(x1, y1);
-            let diff2 = absdiff(x2, y2);
+            let diff1 = x1.abs_diff(*y1);
+            let diff2 = x2.abs_diff(*y2);
             diff1
                 .cmp(&diff2)
                 .then_with(|| std::cmp::min(x1, y1).cmp(std::cmp::min(x2, y2)))
@@ -297,8 +315,8 @@ impl Deriv {
     fn shrink(
         &mut self,
         fsub: (usize, usize),
-        doms: &Vec<HashSet<usize>>,
-        pdoms: &Vec<HashSet<usize>>,
+        doms: &[HashSet<usize>],
+        pdoms: &[HashSet<usize>],
         env: &Env,
     ) {
         let (start, goal) = (std::cmp::max(fsub.0, fsub.1), std::cmp::min(fsub.0, fsub.1));
@@ -307,8 +325,7 @@ impl Deriv {
         }
         let mut stack = vec![(start, vec![])];
         let mut paths = vec![];
-        while 0 < stack.len() {
-            let (cur, path) = stack.pop().unwrap();
+        while let Some((cur, path)) = stack.pop() {
             if cur == goal {
                 paths.push(path);
                 continue;
@@ -381,16 +398,20 @@ impl Deriv {
     }
 
     pub fn reduce(&mut self, env: &Env) {
+        // 支配関係は単一の根でしか考えられない
+        if 1 < self.roots.len() {
+            return;
+        }
         let doms = self.dom_rel();
         let pdoms = self.pdom_rel();
         let factor_subgraphs = self.factor_subgraphs(&doms, &pdoms);
-        let mut cnt = 0;
         for fsub in factor_subgraphs {
             self.shrink(fsub, &doms, &pdoms, env);
         }
     }
     // diff by v をvalsで評価(forward)
     // vはVarなの？？型ごちゃごちゃすぎん
+    #[allow(clippy::ptr_arg)]
     pub fn forward_eval(&self, v: Var, vars: &str, vals: &Vec<f64>, env: &Env) -> f64 {
         let mut varvec: Vec<Var>;
         match variables().parse(vars, env) {
@@ -408,19 +429,24 @@ impl Deriv {
         varvec.sort();
         self.forward_eval_internal(self.vars[&v], &varvec, vals)
     }
-    fn forward_eval_internal(&self, cur: usize, vars: &Vec<Var>, vals: &Vec<f64>) -> f64 {
+    // 辺の偏微分を vals の型で評価して, 根から v までの経路について足し合わせる
+    pub fn forward_eval_generic<T: Scalar>(&self, v: Var, vars: &[Var], vals: &[T]) -> T {
+        self.forward_eval_internal(self.vars[&v], vars, vals)
+    }
+    fn forward_eval_internal<T: Scalar>(&self, cur: usize, vars: &[Var], vals: &[T]) -> T {
         if cur == self.root {
-            1.
+            T::one()
         } else {
-            let mut res = 0.;
+            let mut res = T::zero();
             for Edge { to: next, exp } in &self.reverse_graph[cur] {
-                let temp = exp.eval_internal(vars, vals);
-                res += self.forward_eval_internal(*next, vars, vals) * temp;
+                let temp = exp.eval_generic_internal(vars, vals);
+                res = res + self.forward_eval_internal(*next, vars, vals) * temp;
             }
             res
         }
     }
 
+    #[allow(clippy::ptr_arg)]
     pub fn backward_grad(&self, vars: &str, vals: &Vec<f64>, env: &Env) -> Vec<f64> {
         let mut varvec: Vec<Var>;
         match variables().parse(vars, env) {
@@ -438,29 +464,191 @@ impl Deriv {
         varvec.sort();
         self.backward_grad_internal(&varvec, vals)
     }
-    fn backward_grad_internal(&self, vars: &Vec<Var>, vals: &Vec<f64>) -> Vec<f64> {
-        let mut res = vec![std::f64::NAN];
-        let mut stack = vec![(self.root, 1.)];
-        let mut paths: Vec<f64> = vec![];
-        while 0 < stack.len() {
-            let (cur, path) = stack.pop().unwrap();
-            if self.leafs.contains_key(&cur) {
-                match self.leafs[&cur] {
-                    Some(v) => match vars.binary_search(&v) {
-                        Ok(i) => res[i] = path,
-                        Err(_) => panic!("no value is given"),
-                    },
-                    _ => continue,
+    fn backward_grad_internal(&self, vars: &[Var], vals: &[f64]) -> Vec<f64> {
+        self.backward_grad_generic(vars, vals)
+    }
+    // 辺の偏微分を vals の型で評価して逆向きに流す
+    pub fn backward_grad_generic<T: Scalar>(&self, vars: &[Var], vals: &[T]) -> Vec<T> {
+        self.reverse_sweep(&[(self.root, T::one())], vars, vals)
+    }
+
+    // 葉にtangentを置いてidの昇順に流す. 各ノードでの方向微分を返す
+    fn forward_sweep<T: Scalar>(&self, vars: &[Var], vals: &[T], tangent: &[T]) -> Vec<T> {
+        let mut dot = vec![T::zero(); self.size];
+        for cur in 0..self.size {
+            dot[cur] = match self.leafs.get(&cur) {
+                Some(Some(v)) => match vars.binary_search(v) {
+                    Ok(i) => tangent[i].clone(),
+                    Err(_) => panic!("no value is given"),
+                },
+                Some(None) => T::zero(),
+                None => self.graph[cur]
+                    .iter()
+                    .fold(T::zero(), |acc, Edge { to, exp }| {
+                        acc + exp.eval_generic_internal(vars, vals) * dot[*to].clone()
+                    }),
+            };
+        }
+        dot
+    }
+
+    // seedsの随伴をidの降順に流し, varsの順に葉の随伴を返す
+    fn reverse_sweep<T: Scalar>(&self, seeds: &[(usize, T)], vars: &[Var], vals: &[T]) -> Vec<T> {
+        let zero = T::zero();
+        let mut bar = vec![zero.clone(); self.size];
+        for (r, c) in seeds {
+            bar[*r] = bar[*r].clone() + c.clone();
+        }
+        let mut res = vec![zero.clone(); vars.len()];
+        for cur in (0..self.size).rev() {
+            if bar[cur] == zero {
+                continue;
+            }
+            match self.leafs.get(&cur) {
+                Some(Some(v)) => match vars.binary_search(v) {
+                    Ok(i) => res[i] = res[i].clone() + bar[cur].clone(),
+                    Err(_) => panic!("no value is given"),
+                },
+                Some(None) => (),
+                None => {
+                    for Edge { to, exp } in &self.graph[cur] {
+                        let d = bar[cur].clone() * exp.eval_generic_internal(vars, vals);
+                        bar[*to] = bar[*to].clone() + d;
+                    }
                 }
-            } else {
-                for Edge { to: next, exp } in &self.graph[cur] {
-                    stack.push((*next, path * exp.eval_internal(vars, vals)));
+            }
+        }
+        res
+    }
+
+    // J tangent. tangentはvarsの順, 結果はrootsの順
+    pub fn jvp(&self, vars: &str, vals: &[f64], tangent: &[f64], env: &Env) -> Vec<f64> {
+        self.jvp_internal(&parse_vars(vars, env), vals, tangent)
+    }
+    pub fn jvp_internal(&self, vars: &[Var], vals: &[f64], tangent: &[f64]) -> Vec<f64> {
+        assert!(
+            tangent.len() == vars.len(),
+            "tangent length differs from vars"
+        );
+        let dot = self.forward_sweep(vars, vals, tangent);
+        self.roots.iter().map(|r| dot[*r]).collect()
+    }
+
+    // cotangent^T J. cotangentはrootsの順, 結果はvarsの順
+    pub fn vjp(&self, vars: &str, vals: &[f64], cotangent: &[f64], env: &Env) -> Vec<f64> {
+        self.vjp_internal(&parse_vars(vars, env), vals, cotangent)
+    }
+    pub fn vjp_internal(&self, vars: &[Var], vals: &[f64], cotangent: &[f64]) -> Vec<f64> {
+        assert!(
+            cotangent.len() == self.roots.len(),
+            "cotangent length differs from roots"
+        );
+        let seeds: Vec<(usize, f64)> = self.roots.iter().cloned().zip(cotangent.to_vec()).collect();
+        self.reverse_sweep(&seeds, vars, vals)
+    }
+
+    // 各ノードが依存する変数のvarsでの添字
+    fn var_deps(&self, vars: &[Var]) -> Vec<BTreeSet<usize>> {
+        let mut deps: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); self.size];
+        for cur in 0..self.size {
+            if let Some(Some(v)) = self.leafs.get(&cur) {
+                match vars.binary_search(v) {
+                    Ok(i) => drop(deps[cur].insert(i)),
+                    Err(_) => panic!("no value is given"),
+                }
+            }
+            for Edge { to, .. } in &self.graph[cur] {
+                let child = deps[*to].clone();
+                deps[cur].extend(child);
+            }
+        }
+        deps
+    }
+
+    // Jacobianの非零パターン. i行目はroots[i]が依存する変数
+    pub fn jacobian_pattern(&self, vars: &[Var]) -> Vec<BTreeSet<usize>> {
+        let deps = self.var_deps(vars);
+        self.roots.iter().map(|r| deps[*r].clone()).collect()
+    }
+
+    // rootのHessianの非零パターン
+    // 葉の随伴が依存する変数を, 根から辺の式が依存する変数を集めながら求める
+    pub fn hessian_pattern(&self, vars: &[Var]) -> Vec<BTreeSet<usize>> {
+        let mut reached = vec![false; self.size];
+        let mut deps: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); self.size];
+        reached[self.root] = true;
+        let mut res = vec![BTreeSet::new(); vars.len()];
+        for cur in (0..self.size).rev() {
+            if !reached[cur] {
+                continue;
+            }
+            if let Some(Some(v)) = self.leafs.get(&cur) {
+                match vars.binary_search(v) {
+                    Ok(i) => res[i].extend(deps[cur].iter().cloned()),
+                    Err(_) => panic!("no value is given"),
+                }
+            }
+            for Edge { to, exp } in &self.graph[cur] {
+                reached[*to] = true;
+                let mut d = deps[cur].clone();
+                d.extend(
+                    exp.free_vars()
+                        .iter()
+                        .filter_map(|v| vars.binary_search(v).ok()),
+                );
+                deps[*to].extend(d);
+            }
+        }
+        // 対称にする
+        for i in 0..res.len() {
+            for j in res[i].clone() {
+                res[j].insert(i);
+            }
+        }
+        res
+    }
+
+    // rootのHessianとdirの積. 随伴にdir方向の微分係数を乗せて逆向きに一度流す
+    pub fn hvp(&self, vars: &str, vals: &[f64], dir: &[f64], env: &Env) -> Vec<f64> {
+        self.hvp_internal(&parse_vars(vars, env), vals, dir)
+    }
+    pub fn hvp_internal(&self, vars: &[Var], vals: &[f64], dir: &[f64]) -> Vec<f64> {
+        let mut bar = vec![Dual { re: 0., du: 0. }; self.size];
+        bar[self.root].re = 1.;
+        let mut res = vec![0.; vars.len()];
+        for cur in (0..self.size).rev() {
+            let b = bar[cur];
+            if b.re == 0. && b.du == 0. {
+                continue;
+            }
+            match self.leafs.get(&cur) {
+                Some(Some(v)) => match vars.binary_search(v) {
+                    Ok(i) => res[i] += b.du,
+                    Err(_) => panic!("no value is given"),
+                },
+                Some(None) => (),
+                None => {
+                    for Edge { to, exp } in &self.graph[cur] {
+                        let d = exp.dual_eval_internal(vars, vals, dir);
+                        bar[*to].re += b.re * d.re;
+                        bar[*to].du += b.re * d.du + b.du * d.re;
+                    }
                 }
             }
         }
         res
     }
 
+    // 各変数が区間を動くときの勾配を含む区間. 辺の偏微分を区間で評価して逆向きに流す
+    pub fn grad_interval(&self, vars: &[Var], vals: &[Interval]) -> Vec<Interval> {
+        self.backward_grad_generic(vars, vals)
+    }
+
+    // 辺の偏微分を複素数で評価して逆向きに流す
+    pub fn grad_complex(&self, vars: &[Var], vals: &[Complex]) -> Vec<Complex> {
+        self.backward_grad_generic(vars, vals)
+    }
+
     pub fn forward_eval_dp(&self, v: Var, vars: &str, vals: &Vec<f64>, env: &Env) -> f64 {
         let mut varvec: Vec<Var>;
         match variables().parse(vars, env) {
@@ -540,3 +728,103 @@ fn create_diff_graph() {
         Err(_) => panic!(""),
     }
 }
+
+#[test]
+fn jvp_and_vjp() {
+    let e = &Environment::new();
+    let close = |a: f64, b: f64| (a - b).abs() <= 1e-10 * (1. + b.abs());
+    let vars = "x y z";
+    let vals = vec![0.4, 1.2, -0.7];
+    let fs: Vec<Rc<Expr>> = [
+        "sin(x * y) + cos(x * y) * z",
+        "exp(x * y) / (1 + z^2)",
+        "log(y) * z + x",
+    ]
+    .iter()
+    .map(|s| parse_expr(s, e))
+    .collect();
+    let jac: Vec<Vec<f64>> = fs.iter().map(|f| f.dual_grad(vars, &vals, e)).collect();
+    let d = Deriv::new_multi(fs.clone(), e, "x");
+
+    let tangent = [1., -0.5, 2.];
+    let jv = d.jvp(vars, &vals, &tangent, e);
+    for (row, r) in jac.iter().zip(&jv) {
+        let expected: f64 = row.iter().zip(&tangent).map(|(a, b)| a * b).sum();
+        assert!(close(*r, expected), "{} {}", r, expected);
+    }
+
+    let cotangent = [0.3, -2., 1.5];
+    let vj = d.vjp(vars, &vals, &cotangent, e);
+    for (j, r) in vj.iter().enumerate() {
+        let expected: f64 = jac.iter().zip(&cotangent).map(|(row, c)| row[j] * c).sum();
+        assert!(close(*r, expected), "{} {}", r, expected);
+    }
+
+    // backward_gradは勾配
+    let g = Deriv::new(fs[0].clone(), e, "x").backward_grad(vars, &vals, e);
+    for (a, b) in g.iter().zip(&jac[0]) {
+        assert!(close(*a, *b), "{} {}", a, b);
+    }
+
+    // reduceしても変わらない
+    let f = parse_expr("sin(sin(x) + cos(x)) + cos(sin(x) + cos(x))", e);
+    let mut d = Deriv::new(f.clone(), e, "x");
+    d.reduce(e);
+    let expected = f.dual_eval("x", &[0.3], &[1.], e).du;
+    assert!(close(d.jvp("x", &[0.3], &[1.], e)[0], expected));
+    assert!(close(d.vjp("x", &[0.3], &[1.], e)[0], expected));
+}
+
+#[test]
+#[should_panic(expected = "cotangent length differs from roots")]
+fn vjp_short_cotangent() {
+    let e = &Environment::new();
+    let fs = vec![parse_expr("x * y", e), parse_expr("x + y", e)];
+    let d = Deriv::new_multi(fs, e, "x");
+    d.vjp("x y", &[1., 2.], &[1.], e);
+}
+
+#[test]
+fn hessian_vector_product() {
+    let e = &Environment::new();
+    let n = 40;
+    let names: Vec<String> = (0..n).map(|i| format!("p{}", i)).collect();
+    let vars = names.join(" ");
+    // Σ (p_i - p_{i+1})^2 sin(p_i) + exp(p_0 p_{n-1})
+    let mut src = format!("exp({} * {})", names[0], names[n - 1]);
+    for i in 0..n - 1 {
+        src = format!(
+            "{} + ({} - {})^2 * sin({})",
+            src,
+            names[i],
+            names[i + 1],
+            names[i]
+        );
+    }
+    let f = match expr().parse(&src, e) {
+        Ok((_, _, (expr, _))) => expr,
+        Err(_) => panic!("failed to parse {}", src),
+    };
+    let d = Deriv::new(f, e, &names[0]);
+    // 変数はid順に並ぶ
+    let vals: Vec<f64> = (0..n).map(|i| (i as f64 * 0.37).sin() * 0.5).collect();
+    let dir: Vec<f64> = (0..n).map(|i| (i as f64 * 1.3).cos()).collect();
+    let hv = d.hvp(&vars, &vals, &dir, e);
+
+    let h = 1e-5;
+    let shifted = |s: f64| -> Vec<f64> {
+        let xs = vals.iter().zip(&dir).map(|(x, v)| x + s * v).collect();
+        d.backward_grad(&vars, &xs, e)
+    };
+    let (gp, gm) = (shifted(h), shifted(-h));
+    for i in 0..n {
+        let fd = (gp[i] - gm[i]) / (2. * h);
+        assert!(
+            (hv[i] - fd).abs() < 1e-6 * (1. + fd.abs()),
+            "{}: {} {}",
+            i,
+            hv[i],
+            fd
+        );
+    }
+}
@@ -0,0 +1,287 @@
+#[cfg(test)]
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Bop, Env, Expr, HashMap, Uop, Var, C};
+#[cfg(test)]
+use super::parse::*;
+use super::scalar::Scalar;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+// 値 re と一方向の微分係数 du
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Dual {
+    pub re: f64,
+    pub du: f64,
+}
+
+// 値 re と複数方向の微分係数 du[k]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DualVec {
+    pub re: f64,
+    pub du: Vec<f64>,
+}
+
+// 局所的な偏微分 d から連鎖律で微分係数を伝える
+trait Tangent: Sized + Clone {
+    fn re(&self) -> f64;
+    fn chain(&self, re: f64, d: f64) -> Self;
+    fn chain2(&self, other: &Self, re: f64, d1: f64, d2: f64) -> Self;
+}
+
+// 0 * inf や 0 * NaN で微分係数が壊れないようにする
+fn scale(d: f64, du: f64) -> f64 {
+    if du == 0. {
+        0.
+    } else {
+        d * du
+    }
+}
+
+impl Tangent for Dual {
+    fn re(&self) -> f64 {
+        self.re
+    }
+    fn chain(&self, re: f64, d: f64) -> Self {
+        Dual {
+            re,
+            du: scale(d, self.du),
+        }
+    }
+    fn chain2(&self, other: &Self, re: f64, d1: f64, d2: f64) -> Self {
+        Dual {
+            re,
+            du: scale(d1, self.du) + scale(d2, other.du),
+        }
+    }
+}
+
+impl Tangent for DualVec {
+    fn re(&self) -> f64 {
+        self.re
+    }
+    fn chain(&self, re: f64, d: f64) -> Self {
+        DualVec {
+            re,
+            du: self.du.iter().map(|du| scale(d, *du)).collect(),
+        }
+    }
+    fn chain2(&self, other: &Self, re: f64, d1: f64, d2: f64) -> Self {
+        DualVec {
+            re,
+            du: self
+                .du
+                .iter()
+                .zip(&other.du)
+                .map(|(a, b)| scale(d1, *a) + scale(d2, *b))
+                .collect(),
+        }
+    }
+}
+
+fn unop<T: Tangent>(op: Uop, a: &T) -> T {
+    let x = a.re();
+    match op {
+        Uop::Sin => a.chain(x.sin(), x.cos()),
+        Uop::Cos => a.chain(x.cos(), -x.sin()),
+        Uop::Tan => a.chain(x.tan(), 1. / x.cos().powi(2)),
+        Uop::Log => a.chain(x.ln(), 1. / x),
+        Uop::Exp => a.chain(x.exp(), x.exp()),
+        Uop::Neg => a.chain(-x, -1.),
+    }
+}
+
+fn binop<T: Tangent>(op: Bop, a: &T, b: &T, const_exp: bool) -> T {
+    let (x, y) = (a.re(), b.re());
+    match op {
+        Bop::Add => a.chain2(b, x + y, 1., 1.),
+        Bop::Sub => a.chain2(b, x - y, 1., -1.),
+        Bop::Mul => a.chain2(b, x * y, y, x),
+        Bop::Div => a.chain2(b, x / y, 1. / y, -x / (y * y)),
+        // 定数冪ではlogを取らない. 負の底でもよい
+        Bop::Pow if const_exp => a.chain(x.powf(y), y * x.powf(y - 1.)),
+        Bop::Pow => {
+            let p = x.powf(y);
+            a.chain2(b, p, y * x.powf(y - 1.), p * x.ln())
+        }
+    }
+}
+
+impl Add for Dual {
+    type Output = Dual;
+    fn add(self, other: Dual) -> Dual {
+        binop(Bop::Add, &self, &other, false)
+    }
+}
+
+impl Sub for Dual {
+    type Output = Dual;
+    fn sub(self, other: Dual) -> Dual {
+        binop(Bop::Sub, &self, &other, false)
+    }
+}
+
+impl Neg for Dual {
+    type Output = Dual;
+    fn neg(self) -> Dual {
+        unop(Uop::Neg, &self)
+    }
+}
+
+impl Mul for Dual {
+    type Output = Dual;
+    fn mul(self, other: Dual) -> Dual {
+        binop(Bop::Mul, &self, &other, false)
+    }
+}
+
+impl Div for Dual {
+    type Output = Dual;
+    fn div(self, other: Dual) -> Dual {
+        binop(Bop::Div, &self, &other, false)
+    }
+}
+
+// 微分係数が0の指数は定数冪として扱う
+impl Scalar for Dual {
+    fn from_rat(c: C) -> Self {
+        Dual {
+            re: *c.numer() as f64 / *c.denom() as f64,
+            du: 0.,
+        }
+    }
+    fn sin(&self) -> Self {
+        unop(Uop::Sin, self)
+    }
+    fn cos(&self) -> Self {
+        unop(Uop::Cos, self)
+    }
+    fn tan(&self) -> Self {
+        unop(Uop::Tan, self)
+    }
+    fn ln(&self) -> Self {
+        unop(Uop::Log, self)
+    }
+    fn exp(&self) -> Self {
+        unop(Uop::Exp, self)
+    }
+    fn pow(&self, other: &Self) -> Self {
+        binop(Bop::Pow, self, other, other.du == 0.)
+    }
+}
+
+impl Expr {
+    fn tangent_eval<T: Tangent>(
+        &self,
+        vars: &[Var],
+        seed: &dyn Fn(Option<usize>) -> T,
+        memo: &mut HashMap<*const Expr, T>,
+    ) -> T {
+        let ptr = self as *const Expr;
+        if let Some(t) = memo.get(&ptr) {
+            return t.clone();
+        }
+        let res = match self {
+            Expr::UnOp { op, exp } => unop(*op, &exp.tangent_eval(vars, seed, memo)),
+            Expr::BinOp { op, exp1, exp2 } => {
+                let a = exp1.tangent_eval(vars, seed, memo);
+                let b = exp2.tangent_eval(vars, seed, memo);
+                binop(*op, &a, &b, exp2.is_const())
+            }
+            Expr::Var(vt) => match vars.binary_search(vt) {
+                Ok(i) => seed(Some(i)),
+                Err(_) => panic!("var {} is not specified", vt.id),
+            },
+            // 定数は微分係数0
+            Expr::Num(n) => seed(None).chain(*n.numer() as f64 / *n.denom() as f64, 0.),
+        };
+        memo.insert(ptr, res.clone());
+        res
+    }
+
+    // 方向 dir (varsの順) への方向微分を値と一緒に求める
+    pub fn dual_eval_internal(&self, vars: &[Var], vals: &[f64], dir: &[f64]) -> Dual {
+        let seed = |i: Option<usize>| match i {
+            None => Dual { re: 0., du: 0. },
+            Some(i) => Dual {
+                re: vals[i],
+                du: dir[i],
+            },
+        };
+        self.tangent_eval(vars, &seed, &mut HashMap::new())
+    }
+
+    pub fn dual_eval(&self, vars: &str, vals: &[f64], dir: &[f64], e: &Env) -> Dual {
+        self.dual_eval_internal(&parse_vars(vars, e), vals, dir)
+    }
+
+    // dirs[k] 方向への方向微分をまとめて求める
+    pub fn dual_vec_eval_internal(&self, vars: &[Var], vals: &[f64], dirs: &[Vec<f64>]) -> DualVec {
+        let seed = |i: Option<usize>| match i {
+            None => DualVec {
+                re: 0.,
+                du: vec![0.; dirs.len()],
+            },
+            Some(i) => DualVec {
+                re: vals[i],
+                du: dirs.iter().map(|d| d[i]).collect(),
+            },
+        };
+        self.tangent_eval(vars, &seed, &mut HashMap::new())
+    }
+
+    pub fn dual_vec_eval(&self, vars: &str, vals: &[f64], dirs: &[Vec<f64>], e: &Env) -> DualVec {
+        self.dual_vec_eval_internal(&parse_vars(vars, e), vals, dirs)
+    }
+
+    // 全変数方向をまとめて流して勾配を求める
+    pub fn dual_grad(&self, vars: &str, vals: &[f64], e: &Env) -> Vec<f64> {
+        let dirs: Vec<Vec<f64>> = (0..vals.len())
+            .map(|i| {
+                (0..vals.len())
+                    .map(|j| if i == j { 1. } else { 0. })
+                    .collect()
+            })
+            .collect();
+        self.dual_vec_eval(vars, vals, &dirs, e).du
+    }
+}
+
+#[test]
+fn dual_matches_deriv() {
+    let e = &Environment::new();
+    let close = |a: f64, b: f64| (a - b).abs() <= 1e-10 * (1. + b.abs());
+    let vars = "x y z";
+    let vals = vec![0.3, 1.7, -0.4];
+    let sorted = parse_vars(vars, e);
+    for s in [
+        "sin(x * y) + cos(z) * x",
+        "exp(x / y) * log(y) - tan(z)",
+        "x ^ y + y ^ 3 + (x * z) ^ 2",
+        "sin(sin(x) + cos(x)) + cos(sin(x) + cos(x)) * y / z",
+    ]
+    .iter()
+    {
+        let f = parse_expr(s, e);
+        let d = Deriv::new(f.clone(), e, "x");
+        let g = f.dual_grad(vars, &vals, e);
+        for (i, v) in sorted.iter().enumerate() {
+            let expected = match d.vars.get(v) {
+                Some(_) => d.forward_eval(*v, vars, &vals, e),
+                None => 0.,
+            };
+            assert!(close(g[i], expected), "{}: {} {}", s, g[i], expected);
+        }
+
+        // 方向微分は勾配との内積
+        let dir = vec![0.5, -1., 2.];
+        let dd = f.dual_eval(vars, &vals, &dir, e);
+        assert!(close(dd.re, f.eval(vars, &vals, e)));
+        let expected: f64 = g.iter().zip(&dir).map(|(a, b)| a * b).sum();
+        assert!(close(dd.du, expected), "{}: {} {}", s, dd.du, expected);
+    }
+
+    // 負の底の定数冪
+    let d = parse_expr("x^3", e).dual_eval("x", &[-2.], &[1.], e);
+    assert_eq!(d, Dual { re: -8., du: 12. });
+}
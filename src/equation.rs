@@ -0,0 +1,151 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Bop, Env, Expr, Rc, Uop};
+#[cfg(test)]
+use super::parse::*;
+
+// lhs = rhs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Equation {
+    pub lhs: Rc<Expr>,
+    pub rhs: Rc<Expr>,
+}
+
+impl Equation {
+    pub fn new(lhs: Rc<Expr>, rhs: Rc<Expr>) -> Self {
+        Equation { lhs, rhs }
+    }
+
+    // F = lhs - rhs として F = 0 の形にする
+    pub fn residual(&self, env: &Env) -> Rc<Expr> {
+        Expr::new_binop(Bop::Sub, self.lhs.clone(), self.rhs.clone(), env).reduce(env)
+    }
+
+    // F(x, y) = 0 で y を x の関数とみたときの dy/dx = -F_x / F_y
+    pub fn implicit_diff(&self, y: &str, x: &str, env: &Env) -> Rc<Expr> {
+        let f = self.residual(env);
+        let fx = f.diff(x, env).reduce(env);
+        let fy = f.diff(y, env).reduce(env);
+        let q = Expr::new_binop(Bop::Div, fx, fy, env);
+        Expr::new_unop(Uop::Neg, q, env).reduce(env)
+    }
+
+    // d^n y/dx^n. 全微分 d/dx = ∂/∂x + y' ∂/∂y を繰り返し適用する
+    pub fn implicit_diff_n(&self, y: &str, x: &str, n: usize, env: &Env) -> Rc<Expr> {
+        if n == 0 {
+            return Expr::new_var(String::from(y), env);
+        }
+        let y1 = self.implicit_diff(y, x, env);
+        let mut res = y1.clone();
+        for _ in 1..n {
+            let gx = res.diff(x, env);
+            let gy = res.diff(y, env);
+            let chain = Expr::new_binop(Bop::Mul, gy, y1.clone(), env);
+            res = Expr::new_binop(Bop::Add, gx, chain, env).reduce(env);
+        }
+        res
+    }
+}
+
+// 連立方程式 F_i(x, y) = 0 で ys を xs の関数とみたときの dy_i/dx_j
+// 陰関数定理により dy/dx = -(∂F/∂y)^{-1} ∂F/∂x. ys, xs は与えた順に並ぶ
+pub fn implicit_jacobian(eqs: &[Equation], ys: &str, xs: &str, env: &Env) -> Vec<Vec<Rc<Expr>>> {
+    let ys: Vec<&str> = ys.split_whitespace().collect();
+    let xs: Vec<&str> = xs.split_whitespace().collect();
+    assert!(
+        eqs.len() == ys.len(),
+        "number of equations and unknowns differ"
+    );
+    let fs: Vec<Rc<Expr>> = eqs.iter().map(|eq| eq.residual(env)).collect();
+    // [∂F/∂y | -∂F/∂x] を掃き出す
+    let mut m: Vec<Vec<Rc<Expr>>> = fs
+        .iter()
+        .map(|f| {
+            let mut row: Vec<Rc<Expr>> = ys.iter().map(|y| f.diff(y, env).reduce(env)).collect();
+            row.extend(
+                xs.iter()
+                    .map(|x| Expr::new_unop(Uop::Neg, f.diff(x, env), env).reduce(env)),
+            );
+            row
+        })
+        .collect();
+    let n = ys.len();
+    for col in 0..n {
+        let pivot = match (col..n).find(|r| !m[*r][col].is_zero()) {
+            Some(r) => r,
+            None => panic!("implicit function theorem does not apply: singular dF/dy"),
+        };
+        m.swap(col, pivot);
+        let p = m[col][col].clone();
+        m[col] = m[col]
+            .iter()
+            .map(|a| Expr::new_binop(Bop::Div, a.clone(), p.clone(), env).reduce(env))
+            .collect();
+        for r in (0..n).filter(|r| *r != col) {
+            let k = m[r][col].clone();
+            if k.is_zero() {
+                continue;
+            }
+            m[r] = m[r]
+                .iter()
+                .zip(&m[col])
+                .map(|(a, b)| {
+                    let kb = Expr::new_binop(Bop::Mul, k.clone(), b.clone(), env);
+                    Expr::new_binop(Bop::Sub, a.clone(), kb, env).reduce(env)
+                })
+                .collect();
+        }
+    }
+    m.into_iter().map(|row| row[n..].to_vec()).collect()
+}
+
+#[cfg(test)]
+fn parse_equation(s: &str, e: &Env) -> Equation {
+    match equation().parse(s, e) {
+        Ok((_, _, eq)) => eq,
+        Err(_) => panic!("failed to parse {}", s),
+    }
+}
+
+#[test]
+fn implicit_circle() {
+    let e = &Environment::new();
+    let eq = parse_equation("x^2 + y^2 = 1", e);
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9 * (1. + b.abs());
+    let at = |f: &Rc<Expr>| f.eval("x y", &vec![0.6, 0.8], e);
+    assert!(close(at(&eq.implicit_diff("y", "x", e)), -0.75));
+    assert!(close(at(&eq.implicit_diff_n("y", "x", 2, e)), -1. / 0.512));
+
+    // 陽に解いた y = (1 - x^2)^(1/2) の微分と一致する
+    let explicit = match expr().parse("(1 - x^2) ^ (1/2)", e) {
+        Ok((_, _, (expr, _))) => expr,
+        Err(_) => panic!(""),
+    };
+    let ds = explicit.taylor_eval("x", 4, "x", &vec![0.6], e).unwrap();
+    for (n, d) in ds.iter().enumerate().skip(1) {
+        let g = eq.implicit_diff_n("y", "x", n, e);
+        assert!(close(at(&g), *d), "{}: {} {}", n, at(&g), d);
+    }
+}
+
+#[test]
+fn implicit_system() {
+    let e = &Environment::new();
+    // 極座標 r, t を x, y の関数とみる
+    let eqs = vec![
+        parse_equation("x = r * cos(t)", e),
+        parse_equation("y = r * sin(t)", e),
+    ];
+    let jac = implicit_jacobian(&eqs, "r t", "x y", e);
+    let (r, t) = (2f64, 0.5f64);
+    // 変数は x, r, t, y の順に作られている
+    let vars = "x r t y";
+    let vals = vec![r * t.cos(), r, t, r * t.sin()];
+    let expected = [[t.cos(), t.sin()], [-t.sin() / r, t.cos() / r]];
+    for i in 0..2 {
+        for j in 0..2 {
+            let v = jac[i][j].eval(vars, &vals, e);
+            assert!((v - expected[i][j]).abs() < 1e-12, "{} {}: {}", i, j, v);
+        }
+    }
+}
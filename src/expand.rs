@@ -0,0 +1,353 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{checked_powi, Bop, Env, Expr, One, Uop, Zero, C};
+#[cfg(test)]
+use super::parse::*;
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+// 単項式: (因子, 指数)の列. 因子はVarやsin(..)のようにこれ以上展開できない式
+type Monomial = Vec<(Rc<Expr>, i64)>;
+// 単項式 -> 係数
+type Terms = BTreeMap<Monomial, C>;
+
+fn constant(c: C) -> Terms {
+    let mut t = Terms::new();
+    if !c.is_zero() {
+        t.insert(vec![], c);
+    }
+    t
+}
+
+fn atom(a: Rc<Expr>, k: i64) -> Terms {
+    match *a {
+        Expr::Num(c) if k == 1 => constant(c),
+        _ => {
+            let mut t = Terms::new();
+            t.insert(vec![(a, k)], C::one());
+            t
+        }
+    }
+}
+
+// 指数があふれたらNone
+fn mono_mul(a: &[(Rc<Expr>, i64)], b: &[(Rc<Expr>, i64)]) -> Option<Monomial> {
+    let mut res = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if j == b.len() || (i < a.len() && a[i].0 < b[j].0) {
+            res.push(a[i].clone());
+            i += 1;
+        } else if i == a.len() || b[j].0 < a[i].0 {
+            res.push(b[j].clone());
+            j += 1;
+        } else {
+            let k = a[i].1.checked_add(b[j].1)?;
+            if k != 0 {
+                res.push((a[i].0.clone(), k));
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    Some(res)
+}
+
+// 以下, 係数がi64からあふれたらNone
+fn terms_add(a: &mut Terms, b: Terms) -> Option<()> {
+    for (m, c) in b {
+        let s = a.get(&m).unwrap_or(&C::zero()).checked_add(&c)?;
+        if s.is_zero() {
+            a.remove(&m);
+        } else {
+            a.insert(m, s);
+        }
+    }
+    Some(())
+}
+
+fn terms_scale(a: Terms, c: C) -> Option<Terms> {
+    if c.is_zero() {
+        return Some(Terms::new());
+    }
+    a.into_iter()
+        .map(|(m, d)| Some((m, d.checked_mul(&c)?)))
+        .collect()
+}
+
+fn terms_mul(a: &Terms, b: &Terms) -> Option<Terms> {
+    let mut res = Terms::new();
+    for (m1, c1) in a {
+        for (m2, c2) in b {
+            terms_add(&mut res, [(mono_mul(m1, m2)?, c1.checked_mul(c2)?)].into())?;
+        }
+    }
+    Some(res)
+}
+
+pub(crate) fn binomial(n: u32, k: u32) -> Option<C> {
+    let mut res = C::one();
+    for i in 0..k {
+        res = res.checked_mul(&C::new(i64::from(n - i), i64::from(i + 1)))?;
+    }
+    Some(res)
+}
+
+// 二項定理で展開する. (a + rest)^n = Σ nCk a^k rest^(n-k)
+fn terms_pow(t: &Terms, n: u32) -> Option<Terms> {
+    let mut it = t.iter();
+    let (m, c) = match it.next() {
+        Some(first) => first,
+        None => {
+            return Some(if n == 0 {
+                constant(C::one())
+            } else {
+                Terms::new()
+            })
+        }
+    };
+    // 単項式なら指数を掛けるだけ
+    if t.len() == 1 {
+        let m = m
+            .iter()
+            .map(|(a, k)| Some((a.clone(), i64::checked_mul(*k, i64::from(n))?)))
+            .collect::<Option<Monomial>>()?;
+        let c = checked_powi(*c, i64::from(n))?;
+        return Some([(m, c)].into());
+    }
+    // 真ん中の二項係数があふれるなら展開しない
+    binomial(n, n / 2)?;
+    let rest: Terms = it.map(|(m, c)| (m.clone(), *c)).collect();
+    let mut rest_pows = vec![constant(C::one())];
+    for i in 0..n as usize {
+        let next = terms_mul(&rest_pows[i], &rest)?;
+        rest_pows.push(next);
+    }
+    let mut res = Terms::new();
+    let mut head = constant(C::one());
+    for k in 0..=n {
+        let term = terms_mul(&head, &rest_pows[(n - k) as usize])?;
+        terms_add(&mut res, terms_scale(term, binomial(n, k)?)?)?;
+        head = terms_mul(&head, &[(m.clone(), *c)].into())?;
+    }
+    Some(res)
+}
+
+// 単項式の逆数. 和なら全体を一つの因子として扱う
+fn terms_inv(t: Terms, env: &Env) -> Option<Terms> {
+    if t.len() == 1 {
+        let (m, c) = t.into_iter().next().unwrap();
+        let m = m
+            .into_iter()
+            .map(|(a, k)| Some((a, k.checked_neg()?)))
+            .collect::<Option<Monomial>>()?;
+        Some([(m, C::one().checked_div(&c)?)].into())
+    } else if t.is_empty() {
+        panic!("zero div")
+    } else {
+        Some(atom(terms_to_expr(&t, env), -1))
+    }
+}
+
+fn mono_to_expr(m: &[(Rc<Expr>, i64)], env: &Env) -> Rc<Expr> {
+    let power = |a: &Rc<Expr>, k: i64| {
+        if k == 1 {
+            a.clone()
+        } else {
+            Expr::new_binop(Bop::Pow, a.clone(), Expr::new_num(k, env), env)
+        }
+    };
+    let mut num: Option<Rc<Expr>> = None;
+    let mut den: Option<Rc<Expr>> = None;
+    for (a, k) in m {
+        let (acc, f) = if *k > 0 {
+            (&mut num, power(a, *k))
+        } else {
+            (&mut den, power(a, -*k))
+        };
+        *acc = Some(match acc.take() {
+            Some(p) => Expr::new_binop(Bop::Mul, p, f, env),
+            None => f,
+        });
+    }
+    let num = num.unwrap_or_else(|| Expr::new_num(1, env));
+    match den {
+        Some(d) => Expr::new_binop(Bop::Div, num, d, env),
+        None => num,
+    }
+}
+
+fn terms_to_expr(t: &Terms, env: &Env) -> Rc<Expr> {
+    let mut res: Option<Rc<Expr>> = None;
+    // 次数の高い方から並べる
+    for (m, c) in t.iter().rev() {
+        let neg = *c < C::zero() && res.is_some();
+        let c = if neg { -*c } else { *c };
+        let term = if m.is_empty() {
+            Expr::new_num_from_rat(c, env)
+        } else if c.is_one() {
+            mono_to_expr(m, env)
+        } else if c == -C::one() {
+            Expr::new_unop(Uop::Neg, mono_to_expr(m, env), env)
+        } else {
+            let c = Expr::new_num_from_rat(c, env);
+            Expr::new_binop(Bop::Mul, c, mono_to_expr(m, env), env)
+        };
+        res = Some(match res {
+            Some(r) if neg => Expr::new_binop(Bop::Sub, r, term, env),
+            Some(r) => Expr::new_binop(Bop::Add, r, term, env),
+            None => term,
+        });
+    }
+    res.unwrap_or_else(|| Expr::new_num(0, env))
+}
+
+impl Expr {
+    fn expand_terms(&self, env: &Env) -> Terms {
+        match self {
+            Expr::Num(c) => constant(*c),
+            Expr::Var(_) => atom(env.borrow_mut().extend_expr(self.clone()), 1),
+            Expr::UnOp { op: Uop::Neg, exp } => {
+                let t = exp.expand_terms(env);
+                terms_scale(t.clone(), -C::one()).unwrap_or_else(|| {
+                    atom(Expr::new_unop(Uop::Neg, terms_to_expr(&t, env), env), 1)
+                })
+            }
+            Expr::UnOp { op, exp } => {
+                atom(Expr::new_unop(*op, exp.expand(env), env).reduce(env), 1)
+            }
+            Expr::BinOp {
+                op: Bop::Pow,
+                exp1,
+                exp2,
+            } => {
+                let left = exp1.expand_terms(env);
+                let ex = exp2.expand(env);
+                let res = match *ex {
+                    Expr::Num(n) if n.is_integer() && 0 <= *n.numer() => u32::try_from(*n.numer())
+                        .ok()
+                        .and_then(|k| terms_pow(&left, k)),
+                    Expr::Num(n) if n.is_integer() => n
+                        .numer()
+                        .checked_neg()
+                        .and_then(|k| u32::try_from(k).ok())
+                        .and_then(|k| terms_pow(&terms_inv(left.clone(), env)?, k)),
+                    _ => None,
+                };
+                // 展開できない冪はそのまま残す
+                res.unwrap_or_else(|| {
+                    let base = terms_to_expr(&left, env);
+                    atom(Expr::new_binop(Bop::Pow, base, ex, env).reduce(env), 1)
+                })
+            }
+            Expr::BinOp { op, exp1, exp2 } => {
+                let (left, right) = (exp1.expand_terms(env), exp2.expand_terms(env));
+                let res = match op {
+                    Bop::Add | Bop::Sub => {
+                        let right = match op {
+                            Bop::Add => Some(right.clone()),
+                            _ => terms_scale(right.clone(), -C::one()),
+                        };
+                        let mut res = left.clone();
+                        right.and_then(|r| terms_add(&mut res, r)).map(|_| res)
+                    }
+                    Bop::Mul => terms_mul(&left, &right),
+                    _ => terms_inv(right.clone(), env).and_then(|r| terms_mul(&left, &r)),
+                };
+                // 係数があふれたら展開せずに一つの因子にする
+                res.unwrap_or_else(|| {
+                    let (l, r) = (terms_to_expr(&left, env), terms_to_expr(&right, env));
+                    atom(Expr::new_binop(*op, l, r, env), 1)
+                })
+            }
+        }
+    }
+
+    // 積を和に分配し, 整数冪を二項定理で展開して同類項をまとめる
+    pub fn expand(&self, env: &Env) -> Rc<Expr> {
+        terms_to_expr(&self.expand_terms(env), env)
+    }
+
+    // 展開した上で v の冪ごとに係数をまとめる
+    pub fn collect(&self, v: &str, env: &Env) -> Rc<Expr> {
+        let var = match env.borrow().search_var(&String::from(v)) {
+            Some(var) => var,
+            None => return self.expand(env),
+        };
+        let var = env.borrow_mut().extend_expr(Expr::Var(var));
+        let mut by_degree: BTreeMap<i64, Terms> = BTreeMap::new();
+        for (m, c) in self.expand_terms(env) {
+            let k = m.iter().find(|(a, _)| *a == var).map_or(0, |(_, k)| *k);
+            let rest = m.into_iter().filter(|(a, _)| *a != var).collect();
+            // 同じ次数で残りが同じ単項式はないので足し算は起きない
+            terms_add(by_degree.entry(k).or_default(), [(rest, c)].into()).unwrap();
+        }
+        let mut res: Option<Rc<Expr>> = None;
+        for (k, coef) in by_degree.into_iter().rev() {
+            let coef = terms_to_expr(&coef, env);
+            let term = match k {
+                0 => coef,
+                _ => {
+                    let p = mono_to_expr(&[(var.clone(), k)], env);
+                    Expr::new_binop(Bop::Mul, coef, p, env).reduce(env)
+                }
+            };
+            res = Some(match res {
+                Some(r) => Expr::new_binop(Bop::Add, r, term, env),
+                None => term,
+            });
+        }
+        res.unwrap_or_else(|| Expr::new_num(0, env))
+    }
+}
+
+#[test]
+fn expand_binomial() {
+    let e = &Environment::new();
+    let lhs = parse_expr("(x + 1) ^ 5", e).expand(e);
+    let rhs = parse_expr("x^5 + 5*x^4 + 10*x^3 + 10*x^2 + 5*x + 1", e).expand(e);
+    assert_eq!(lhs, rhs);
+    assert_eq!(lhs, lhs.expand(e));
+    let x = String::from("x");
+    assert_eq!(243., lhs.eval(&x, &vec![2.], e));
+
+    let lhs = parse_expr("(x + y) * (x - y) - x^2", e).expand(e);
+    assert_eq!(lhs, parse_expr("-(y^2)", e).reduce(e));
+    let lhs = parse_expr("(x + 1) ^ 2 - x ^ 2 - 2 * x", e).expand(e);
+    assert_eq!(lhs, Expr::new_num(1, e));
+    let lhs = parse_expr("(x^2 + x) / x", e).expand(e);
+    assert_eq!(lhs, parse_expr("x + 1", e));
+    let lhs = parse_expr("(x + y + 1) ^ 3", e).expand(e);
+    let v = String::from("x y");
+    assert_eq!(64., lhs.eval(&v, &vec![1., 2.], e));
+
+    // 指数や係数があふれるときは冪のまま残す
+    for s in [
+        "(x + 1) ^ 4294967297",
+        "(x + 1) ^ 70",
+        "(x + 1) ^ (0 - 4294967297)",
+    ]
+    .iter()
+    {
+        let f = parse_expr(s, e);
+        let g = f.expand(e);
+        assert!(matches!(*g, Expr::BinOp { op: Bop::Pow, .. }), "{}", s);
+        assert_eq!(g.eval(&x, &vec![1.], e), f.eval(&x, &vec![1.], e), "{}", s);
+    }
+    let lhs = parse_expr("x * (x + 1) ^ 70 + 1", e).expand(e);
+    assert_eq!(lhs.eval(&x, &vec![1.], e), 2f64.powi(70) + 1.);
+    assert_eq!(
+        parse_expr("x ^ 4294967297", e).expand(e),
+        parse_expr("x ^ 4294967297", e)
+    );
+}
+
+#[test]
+fn collect_powers() {
+    let e = &Environment::new();
+    let lhs = parse_expr("x*y + x + y*x^2 + 3 + sin(z) * x", e).collect("x", e);
+    let rhs = parse_expr("y*x^2 + (sin(z) + y + 1)*x + 3", e);
+    assert_eq!(lhs, rhs);
+}
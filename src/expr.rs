@@ -1,8 +1,11 @@
 use super::parse::*;
+use super::poly::Poly;
 pub use num_rational::Rational64;
 pub use num_traits::identities::{One, Zero};
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub};
 pub use std::cell::RefCell;
-pub use std::collections::HashMap;
+pub use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
 pub use std::rc::Rc;
 pub type C = Rational64;
 
@@ -59,15 +62,11 @@ impl Expr {
     }
 
     pub fn new_binop(op: Bop, one: Rc<Expr>, other: Rc<Expr>, env: &Env) -> Rc<Expr> {
-        let exp1;
-        let exp2;
-        if one < other {
-            exp1 = one;
-            exp2 = other;
-        } else {
-            exp1 = other;
-            exp2 = one;
-        }
+        // 可換な演算だけ並べ替える
+        let (exp1, exp2) = match op {
+            Bop::Add | Bop::Mul if other < one => (other, one),
+            _ => (one, other),
+        };
         let e = Expr::BinOp { op, exp1, exp2 };
         env.borrow_mut().extend_expr(e)
     }
@@ -83,6 +82,20 @@ impl Expr {
         let pden = Expr::new_num(583658233, env);
         Expr::new_binop(Bop::Div, pnum, pden, env).reduce(env)
     }
+    // 虚数単位. 名前が i の変数として扱い, 複素数で評価するときに値を入れる
+    // 作ると以後 env では i が虚数単位になる
+    pub fn imag(env: &Env) -> Rc<Expr> {
+        let v = env.borrow_mut().use_imag();
+        env.borrow_mut().extend_expr(Expr::Var(v))
+    }
+
+    pub fn is_imag(&self, env: &Env) -> bool {
+        match self {
+            Expr::Var(v) => env.borrow().imag == Some(*v),
+            _ => false,
+        }
+    }
+
     pub fn sqrt(expr: Rc<Expr>, env: &Env) -> Rc<Expr> {
         let pnum = Expr::new_num(1, env);
         let pden = Expr::new_num(2, env);
@@ -96,62 +109,165 @@ impl Expr {
         env.borrow_mut().extend_expr(e)
     }
 
+    // 有理数があふれるときは畳み込まずに式のまま残す
     fn new_num_from_op(op: Bop, left: Rc<Expr>, right: Rc<Expr>, env: &Env) -> Rc<Expr> {
-        match *left {
-            Expr::Num(n) => match *right {
-                Expr::Num(m) => match op {
-                    Bop::Add => Rc::new(Expr::Num(n + m)),
-                    Bop::Sub => Rc::new(Expr::Num(n - m)),
-                    Bop::Mul => Rc::new(Expr::Num(n * m)),
-                    Bop::Div => Rc::new(Expr::Num(n / m)),
-                    // Powは無理(無理数)
-                    Bop::Pow => unimplemented!(),
-                },
-                _ => unreachable!(),
+        let r = match (&*left, &*right) {
+            (Expr::Num(n), Expr::Num(m)) => match op {
+                Bop::Add => n.checked_add(m),
+                Bop::Sub => n.checked_sub(m),
+                Bop::Mul => n.checked_mul(m),
+                Bop::Div => n.checked_div(m),
+                // Powは無理(無理数)
+                Bop::Pow => unimplemented!(),
             },
             _ => unreachable!(),
+        };
+        match r {
+            Some(c) => Expr::new_num_from_rat(c, env),
+            None => Expr::new_binop(op, left, right, env),
         }
     }
 
-    fn new_num_from_rat(c: C, env: &Env) -> Rc<Expr> {
+    pub fn new_num_from_rat(c: C, env: &Env) -> Rc<Expr> {
         let e = Expr::Num(c);
         env.borrow_mut().extend_expr(e)
     }
 
-    fn is_const(&self) -> bool {
-        match self {
-            Expr::Num(_n) => true,
-            _ => false,
-        }
+    pub fn is_const(&self) -> bool {
+        matches!(self, Expr::Num(_))
     }
 
-    fn is_zero(&self) -> bool {
+    pub fn is_zero(&self) -> bool {
         match self {
             Expr::Num(n) => n.is_zero(),
             _ => false,
         }
     }
 
-    fn is_one(&self) -> bool {
+    pub fn is_one(&self) -> bool {
         match self {
             Expr::Num(n) => *n == C::one(),
             _ => false,
         }
     }
 
+    // 式に現れる変数
+    pub fn free_vars(&self) -> BTreeSet<Var> {
+        let mut res = BTreeSet::new();
+        // 共有された部分式は一度だけ見る
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![self];
+        while let Some(e) = stack.pop() {
+            if !seen.insert(e as *const Expr) {
+                continue;
+            }
+            match e {
+                Expr::Var(v) => drop(res.insert(*v)),
+                Expr::Num(_) => (),
+                Expr::UnOp { exp, .. } => stack.push(exp),
+                Expr::BinOp { exp1, exp2, .. } => {
+                    stack.push(exp1);
+                    stack.push(exp2);
+                }
+            }
+        }
+        res
+    }
+
     fn is_minus_one(&self) -> bool {
         match self {
             Expr::Num(n) => *n == -C::one(),
             _ => false,
         }
     }
+    // Environmentの仮定から実数かどうか判定する. falseは「わからない」も含む
+    pub fn is_real(&self, e: &Env) -> bool {
+        match self {
+            Expr::Num(_) => true,
+            Expr::Var(v) => e.borrow().assumption(*v).real,
+            Expr::UnOp { op, exp } => match op {
+                Uop::Log => exp.is_positive(e),
+                _ => exp.is_real(e),
+            },
+            Expr::BinOp { op, exp1, exp2 } => match op {
+                Bop::Add | Bop::Sub | Bop::Mul => exp1.is_real(e) && exp2.is_real(e),
+                Bop::Div => exp1.is_real(e) && exp2.is_real(e) && exp2.is_nonzero(e),
+                Bop::Pow => {
+                    (exp1.is_positive(e) && exp2.is_real(e))
+                        || (exp1.is_real(e) && exp2.is_integer(e) && !exp2.is_negative_const())
+                        || (exp1.is_real(e) && exp1.is_nonzero(e) && exp2.is_integer(e))
+                }
+            },
+        }
+    }
+
+    pub fn is_positive(&self, e: &Env) -> bool {
+        match self {
+            Expr::Num(n) => *n > C::zero(),
+            Expr::Var(v) => e.borrow().assumption(*v).positive,
+            Expr::UnOp { op, exp } => match op {
+                Uop::Exp => exp.is_real(e),
+                _ => false,
+            },
+            Expr::BinOp { op, exp1, exp2 } => match op {
+                Bop::Add | Bop::Mul | Bop::Div => exp1.is_positive(e) && exp2.is_positive(e),
+                Bop::Sub => false,
+                Bop::Pow => exp1.is_positive(e) && exp2.is_real(e),
+            },
+        }
+    }
+
+    pub fn is_nonzero(&self, e: &Env) -> bool {
+        match self {
+            Expr::Num(n) => !n.is_zero(),
+            Expr::Var(v) => e.borrow().assumption(*v).nonzero,
+            Expr::UnOp { op, exp } => match op {
+                Uop::Exp => exp.is_real(e),
+                Uop::Neg => exp.is_nonzero(e),
+                _ => false,
+            },
+            Expr::BinOp { op, exp1, exp2 } => match op {
+                Bop::Add => exp1.is_positive(e) && exp2.is_positive(e),
+                Bop::Sub => false,
+                Bop::Mul | Bop::Div => exp1.is_nonzero(e) && exp2.is_nonzero(e),
+                Bop::Pow => exp1.is_positive(e) && exp2.is_real(e),
+            },
+        }
+    }
+
+    pub fn is_integer(&self, e: &Env) -> bool {
+        match self {
+            Expr::Num(n) => n.is_integer(),
+            Expr::Var(v) => e.borrow().assumption(*v).integer,
+            Expr::UnOp { op, exp } => match op {
+                Uop::Neg => exp.is_integer(e),
+                _ => false,
+            },
+            Expr::BinOp { op, exp1, exp2 } => match op {
+                Bop::Add | Bop::Sub | Bop::Mul => exp1.is_integer(e) && exp2.is_integer(e),
+                Bop::Div => false,
+                Bop::Pow => {
+                    exp1.is_integer(e)
+                        && matches!(**exp2, Expr::Num(n) if n.is_integer() && n >= C::zero())
+                }
+            },
+        }
+    }
+
+    fn is_negative_const(&self) -> bool {
+        match self {
+            Expr::Num(n) => *n < C::zero(),
+            _ => false,
+        }
+    }
+
     // post-orderでIndexを振る
     pub fn post_index(&self, i: &mut usize, postids: &mut HashMap<Expr, usize>) {
         match self {
             Expr::UnOp { exp, .. } => {
                 exp.post_index(i, postids);
                 match postids.get(self) {
-                    Some(_v) => return,
+                    Some(_v) => (),
                     None => {
                         postids.insert(self.clone(), *i);
                         *i += 1;
@@ -162,7 +278,7 @@ impl Expr {
                 exp1.post_index(i, postids);
                 exp2.post_index(i, postids);
                 match postids.get(self) {
-                    Some(_v) => return,
+                    Some(_v) => (),
                     None => {
                         postids.insert(self.clone(), *i);
                         *i += 1;
@@ -170,7 +286,7 @@ impl Expr {
                 }
             }
             _ => match postids.get(self) {
-                Some(_v) => return,
+                Some(_v) => (),
                 None => {
                     postids.insert(self.clone(), *i);
                     *i += 1;
@@ -182,7 +298,11 @@ impl Expr {
     pub fn diff(&self, v: &str, e: &Env) -> Rc<Expr> {
         let var = e.borrow().search_var(&String::from(v));
         match var {
-            Some(v) => self.diff_internal(v, e),
+            // 小さい多項式ならPolyで微分する. 大きいものは展開せずに微分する
+            Some(v) => match Poly::from_expr_small(self) {
+                Some(p) => p.derivative(v).to_expr(e),
+                None => self.diff_internal(v, e),
+            },
             None => {
                 // unreachable!();
                 Rc::new(Expr::Num(C::new(0, 1)))
@@ -249,6 +369,13 @@ impl Expr {
                             e,
                         );
                     }
+                    Bop::Pow if exp2.is_const() => {
+                        // 定数冪は n x^(n-1). logを作らない
+                        let n1 = Expr::new_binop(Bop::Sub, exp2.clone(), Expr::new_num(1, e), e);
+                        let p = Expr::new_binop(Bop::Pow, exp1.clone(), n1, e);
+                        factor_left = Expr::new_binop(Bop::Mul, exp2.clone(), p, e);
+                        factor_right = Expr::new_num(0, e);
+                    }
                     Bop::Pow => {
                         let factor1 = Expr::new_binop(Bop::Div, exp2.clone(), exp1.clone(), e);
                         let factor2 = Expr::new_unop(Uop::Log, exp1.clone(), e);
@@ -300,12 +427,11 @@ impl Expr {
                     let factor = Expr::new_binop(Bop::Div, Expr::new_num(1, e), inexp.clone(), e);
                     Expr::new_binop(Bop::Mul, factor, inexp.diff_internal(v, e), e)
                 }
-                Uop::Exp => Expr::new_binop(
-                    Bop::Mul,
-                    e.borrow_mut().extend_expr(self.clone()),
-                    inexp.diff_internal(v, e),
-                    e,
-                ),
+                Uop::Exp => {
+                    // borrow_mutを引数の評価中に持ち越さない
+                    let s = e.borrow_mut().extend_expr(self.clone());
+                    Expr::new_binop(Bop::Mul, s, inexp.diff_internal(v, e), e)
+                }
                 Uop::Neg => Expr::new_unop(Uop::Neg, inexp.diff_internal(v, e), e),
             },
             Expr::BinOp { op, exp1, exp2 } => {
@@ -334,6 +460,13 @@ impl Expr {
                             e,
                         );
                     }
+                    Bop::Pow if exp2.is_const() => {
+                        // 定数冪は n x^(n-1). logを作らない
+                        let n1 = Expr::new_binop(Bop::Sub, exp2.clone(), Expr::new_num(1, e), e);
+                        let p = Expr::new_binop(Bop::Pow, exp1.clone(), n1, e);
+                        factor_left = Expr::new_binop(Bop::Mul, exp2.clone(), p, e);
+                        factor_right = Expr::new_num(0, e);
+                    }
                     Bop::Pow => {
                         let factor1 = Expr::new_binop(Bop::Div, exp2.clone(), exp1.clone(), e);
                         let factor2 = Expr::new_unop(Uop::Log, exp1.clone(), e);
@@ -389,28 +522,25 @@ impl Expr {
                     }
                 }
                 Uop::Log => {
-                    // TODO: log x ^ e = e * log x ??
-                    // TODO: log e = 1 ??
                     let inexp = inexp.reduce(e);
                     if inexp.is_one() {
                         Expr::new_num(0, e)
                     } else {
-                        Expr::new_unop(Uop::Log, inexp, e)
+                        Expr::reduce_log(inexp, e)
                     }
                 }
                 Uop::Exp => {
-                    // TODO: e log x = x
                     let inexp = inexp.reduce(e);
                     if inexp.is_zero() {
                         Expr::new_num(1, e)
                     } else {
-                        Expr::new_unop(Uop::Exp, inexp, e)
+                        Expr::reduce_exp(inexp, e)
                     }
                 }
                 Uop::Neg => {
                     let inexp = inexp.reduce(e);
                     match *inexp {
-                        Expr::Num(n) => Rc::new(Expr::Num(-n)),
+                        Expr::Num(n) => Expr::new_num_from_rat(-n, e),
                         _ => Expr::new_unop(Uop::Neg, inexp, e),
                     }
                 }
@@ -434,7 +564,7 @@ impl Expr {
                     }
                     Bop::Sub => {
                         if left.is_zero() {
-                            right
+                            Expr::new_unop(Uop::Neg, right, e).reduce(e)
                         } else if right.is_zero() {
                             left
                         } else if left.is_const() && right.is_const() {
@@ -459,14 +589,16 @@ impl Expr {
                         } else if left.is_const() && right.is_const() {
                             Expr::new_num_from_op(Bop::Mul, left, right, e)
                         } else if Rc::ptr_eq(&left, &right) {
-                            Expr::new_binop(Bop::Pow, Expr::new_num(2, e), left, e)
+                            Expr::reduce_pow(left, Expr::new_num(2, e), e)
                         } else {
                             Expr::new_binop(Bop::Mul, left, right, e)
                         }
                     }
                     Bop::Div => {
-                        if left.is_zero() || right.is_zero() {
+                        if right.is_zero() {
                             panic!("zero div")
+                        } else if left.is_zero() {
+                            Expr::new_num(0, e)
                         } else if right.is_one() {
                             left
                         } else if right.is_minus_one() {
@@ -480,28 +612,119 @@ impl Expr {
                         }
                     }
                     Bop::Pow => {
-                        // TODO: e log x = x
-                        if left.is_zero() {
-                            Expr::new_num(0, e)
-                        } else if right.is_zero() {
-                            Expr::new_num(1, e)
-                        } else if left.is_one() {
+                        if right.is_zero() || left.is_one() {
                             Expr::new_num(1, e)
+                        } else if left.is_zero() {
+                            Expr::new_num(0, e)
                         } else if right.is_one() {
                             left
                         } else {
-                            Expr::new_binop(Bop::Pow, left, right, e)
+                            Expr::reduce_pow(left, right, e)
                         }
                     }
                 }
             }
-            _ => Rc::new(self.clone()),
+            _ => e.borrow_mut().extend_expr(self.clone()),
+        }
+    }
+
+    // 仮定を使ったlogの簡約. inexpはreduce済み
+    fn reduce_log(inexp: Rc<Expr>, e: &Env) -> Rc<Expr> {
+        match &*inexp {
+            // log(exp x) = x  (xが実数)
+            Expr::UnOp { op: Uop::Exp, exp } if exp.is_real(e) => exp.clone(),
+            // log(x^n) = n log x  (x > 0, nが実数)
+            Expr::BinOp {
+                op: Bop::Pow,
+                exp1,
+                exp2,
+            } if exp1.is_positive(e) && exp2.is_real(e) => {
+                let l = Expr::new_unop(Uop::Log, exp1.clone(), e);
+                Expr::new_binop(Bop::Mul, exp2.clone(), l, e).reduce(e)
+            }
+            // log(a b) = log a + log b, log(a / b) = log a - log b  (a, b > 0)
+            Expr::BinOp {
+                op: op @ (Bop::Mul | Bop::Div),
+                exp1,
+                exp2,
+            } if exp1.is_positive(e) && exp2.is_positive(e) => {
+                let l1 = Expr::new_unop(Uop::Log, exp1.clone(), e);
+                let l2 = Expr::new_unop(Uop::Log, exp2.clone(), e);
+                let op = if *op == Bop::Mul { Bop::Add } else { Bop::Sub };
+                Expr::new_binop(op, l1, l2, e).reduce(e)
+            }
+            _ => Expr::new_unop(Uop::Log, inexp, e),
+        }
+    }
+
+    // 仮定を使ったexpの簡約. inexpはreduce済み
+    fn reduce_exp(inexp: Rc<Expr>, e: &Env) -> Rc<Expr> {
+        match &*inexp {
+            // exp(log x) = x  (x > 0)
+            Expr::UnOp { op: Uop::Log, exp } if exp.is_positive(e) => exp.clone(),
+            // exp(a log x) = x^a  (x > 0, aが実数)
+            Expr::BinOp {
+                op: Bop::Mul,
+                exp1,
+                exp2,
+            } => {
+                let (coef, log) = match (&**exp1, &**exp2) {
+                    (_, Expr::UnOp { op: Uop::Log, exp }) => (exp1, exp),
+                    (Expr::UnOp { op: Uop::Log, exp }, _) => (exp2, exp),
+                    _ => return Expr::new_unop(Uop::Exp, inexp.clone(), e),
+                };
+                if log.is_positive(e) && coef.is_real(e) {
+                    Expr::new_binop(Bop::Pow, log.clone(), coef.clone(), e).reduce(e)
+                } else {
+                    Expr::new_unop(Uop::Exp, inexp.clone(), e)
+                }
+            }
+            _ => Expr::new_unop(Uop::Exp, inexp, e),
+        }
+    }
+
+    // 仮定を使ったPowの簡約. left, rightはreduce済み
+    fn reduce_pow(left: Rc<Expr>, right: Rc<Expr>, e: &Env) -> Rc<Expr> {
+        match &*left {
+            // i^n は n mod 4 で決まる
+            Expr::Var(_) if left.is_imag(e) => match *right {
+                Expr::Num(n) if n.is_integer() => match n.numer().rem_euclid(4) {
+                    0 => Expr::new_num(1, e),
+                    1 => left,
+                    2 => Expr::new_num(-1, e),
+                    _ => Expr::new_unop(Uop::Neg, left, e),
+                },
+                _ => Expr::new_binop(Bop::Pow, left, right, e),
+            },
+            // (x^a)^b = x^(a b)  (x > 0 で a, b が実数, または bが整数)
+            Expr::BinOp {
+                op: Bop::Pow,
+                exp1,
+                exp2,
+            } if (exp1.is_positive(e) && exp2.is_real(e) && right.is_real(e))
+                || right.is_integer(e) =>
+            {
+                let ex = Expr::new_binop(Bop::Mul, exp2.clone(), right, e);
+                Expr::new_binop(Bop::Pow, exp1.clone(), ex, e).reduce(e)
+            }
+            // 有理数の整数乗は計算できる
+            Expr::Num(a) => match *right {
+                Expr::Num(n) if n.is_integer() && n.numer().abs() <= 64 => {
+                    match checked_powi(*a, *n.numer()) {
+                        Some(p) => Expr::new_num_from_rat(p, e),
+                        // あふれるときは冪のまま残す
+                        None => Expr::new_binop(Bop::Pow, left, right, e),
+                    }
+                }
+                _ => Expr::new_binop(Bop::Pow, left, right, e),
+            },
+            _ => Expr::new_binop(Bop::Pow, left, right, e),
         }
     }
 
     pub fn print(&self, e: &Env) {
         self.print_internal(e);
-        println!("");
+        println!();
     }
 
     fn print_func(&self, name: &str, e: &Env) {
@@ -534,24 +757,13 @@ impl Expr {
                 }
             },
             Expr::BinOp { op, exp1, exp2 } => {
-                let ops;
-                match op {
-                    Bop::Add => {
-                        ops = String::from("+");
-                    }
-                    Bop::Sub => {
-                        ops = String::from("-");
-                    }
-                    Bop::Mul => {
-                        ops = String::from("*");
-                    }
-                    Bop::Div => {
-                        ops = String::from("/");
-                    }
-                    Bop::Pow => {
-                        ops = String::from("^");
-                    }
-                }
+                let ops = match op {
+                    Bop::Add => "+",
+                    Bop::Sub => "-",
+                    Bop::Mul => "*",
+                    Bop::Div => "/",
+                    Bop::Pow => "^",
+                };
                 print!("(");
                 exp1.print_internal(e);
                 print!("{}", ops);
@@ -568,46 +780,110 @@ impl Expr {
     }
 
     pub fn eval(&self, vars: &str, vals: &Vec<f64>, e: &Env) -> f64 {
-        let mut varvec: Vec<Var>;
-        match variables().parse(vars, e) {
-            Ok((_, _, vars)) => {
-                varvec = vars
-                    .iter()
-                    .map(|v| match **v {
-                        Expr::Var(vv) => vv,
-                        _ => unreachable!(),
-                    })
-                    .collect();
-            }
-            Err(_) => panic!("failed to parse variables"),
-        }
-        varvec.sort();
-        self.eval_internal(&varvec, vals)
+        self.eval_internal(&parse_vars(vars, e), vals)
     }
+    // 公開している引数の型は変えない
+    #[allow(clippy::ptr_arg)]
     pub fn eval_internal(&self, vars: &Vec<Var>, vals: &Vec<f64>) -> f64 {
-        match self {
-            Expr::UnOp { op, exp } => match op {
-                Uop::Sin => exp.eval_internal(vars, vals).sin(),
-                Uop::Cos => exp.eval_internal(vars, vals).cos(),
-                Uop::Tan => exp.eval_internal(vars, vals).tan(),
-                Uop::Log => exp.eval_internal(vars, vals).log(std::f64::consts::E),
-                Uop::Exp => std::f64::consts::E.powf(exp.eval_internal(vars, vals)),
-                Uop::Neg => -exp.eval_internal(vars, vals),
-            },
-            Expr::BinOp { op, exp1, exp2 } => match op {
-                Bop::Add => exp1.eval_internal(vars, vals) + exp2.eval_internal(vars, vals),
-                Bop::Sub => exp1.eval_internal(vars, vals) - exp2.eval_internal(vars, vals),
-                Bop::Mul => exp1.eval_internal(vars, vals) * exp2.eval_internal(vars, vals),
-                Bop::Div => exp1.eval_internal(vars, vals) / exp2.eval_internal(vars, vals),
-                Bop::Pow => exp1
-                    .eval_internal(vars, vals)
-                    .powf(exp2.eval_internal(vars, vals)),
-            },
-            Expr::Var(vt) => match vars.binary_search(&vt) {
-                Ok(i) => vals[i],
-                Err(_) => panic!("var {} is not specified", vt.id),
-            },
-            Expr::Num(n) => *n.numer() as f64 / *n.denom() as f64,
+        self.eval_generic_internal(vars, vals)
+    }
+}
+
+// "x y z" のような変数列をidの昇順に並べる. vals[i] は i 番目の変数の値
+pub fn parse_vars(vars: &str, e: &Env) -> Vec<Var> {
+    let mut varvec: Vec<Var> = match variables().parse(vars, e) {
+        Ok((_, _, vars)) => vars
+            .iter()
+            .map(|v| match **v {
+                Expr::Var(vv) => vv,
+                _ => unreachable!(),
+            })
+            .collect(),
+        Err(_) => panic!("failed to parse variables"),
+    };
+    varvec.sort();
+    varvec
+}
+
+// parse_vars の並びと, 書いた順の各変数がその中で何番目か
+pub fn parse_vars_slots(vars: &str, e: &Env) -> (Vec<Var>, Vec<usize>) {
+    let sorted = parse_vars(vars, e);
+    let slots = vars
+        .split_whitespace()
+        .map(|s| {
+            let v = e.borrow().search_var(&String::from(s)).unwrap();
+            sorted.binary_search(&v).unwrap()
+        })
+        .collect();
+    (sorted, slots)
+}
+
+// 書いた順の値を parse_vars の並びに置き直す
+pub fn sort_by_slots<T: Clone>(slots: &[usize], vals: &[T]) -> Vec<T> {
+    let mut res = vals.to_vec();
+    for (s, v) in slots.iter().zip(vals) {
+        res[*s] = v.clone();
+    }
+    res
+}
+
+// 有理数の整数乗. i64からあふれるか0の負冪ならNone
+pub(crate) fn checked_powi(a: C, n: i64) -> Option<C> {
+    let k = u32::try_from(n.unsigned_abs()).ok()?;
+    let p = a.numer().checked_pow(k)?;
+    let q = a.denom().checked_pow(k)?;
+    if 0 <= n {
+        Some(C::new(p, q))
+    } else if p == 0 || p == i64::MIN {
+        None
+    } else {
+        Some(C::new(q, p))
+    }
+}
+
+// 変数に付ける仮定. positiveならrealかつnonzero, integerならreal
+#[derive(Debug, Copy, Clone, Default, Hash, PartialEq, Eq)]
+pub struct Assumption {
+    pub real: bool,
+    pub positive: bool,
+    pub nonzero: bool,
+    pub integer: bool,
+}
+
+impl Assumption {
+    pub fn new() -> Self {
+        Assumption::default()
+    }
+
+    pub fn real(mut self) -> Self {
+        self.real = true;
+        self
+    }
+
+    pub fn positive(mut self) -> Self {
+        self.positive = true;
+        self.real = true;
+        self.nonzero = true;
+        self
+    }
+
+    pub fn nonzero(mut self) -> Self {
+        self.nonzero = true;
+        self
+    }
+
+    pub fn integer(mut self) -> Self {
+        self.integer = true;
+        self.real = true;
+        self
+    }
+
+    fn merge(self, other: Assumption) -> Self {
+        Assumption {
+            real: self.real || other.real,
+            positive: self.positive || other.positive,
+            nonzero: self.nonzero || other.nonzero,
+            integer: self.integer || other.integer,
         }
     }
 }
@@ -619,6 +895,9 @@ pub struct Environment {
     pub vars: HashMap<Var, String>,
     pub rev_vars: HashMap<String, Var>,
     pub exprs: HashMap<Expr, Rc<Expr>>,
+    pub assumptions: HashMap<Var, Assumption>,
+    // 虚数単位として扱う変数. use_imag を呼ぶまでは i もただの変数
+    pub imag: Option<Var>,
 }
 
 pub type Env = RefCell<Environment>;
@@ -629,6 +908,8 @@ impl Environment {
             vars: HashMap::new(),
             rev_vars: HashMap::new(),
             exprs: HashMap::new(),
+            assumptions: HashMap::new(),
+            imag: None,
         })
     }
 
@@ -644,11 +925,50 @@ impl Environment {
         }
     }
 
-    pub fn search_var(&self, var_str: &String) -> Option<Var> {
-        match self.rev_vars.get(var_str) {
-            Some(v) => Some(*v),
-            None => None,
+    // 変数に仮定を追加する. 変数がなければ作る
+    pub fn assume(&mut self, var_str: String, a: Assumption) -> Var {
+        let v = self.extend_var(var_str);
+        let cur = self.assumption(v);
+        self.assumptions.insert(v, cur.merge(a));
+        v
+    }
+
+    // 以後 i を虚数単位として扱う
+    pub fn use_imag(&mut self) -> Var {
+        let v = self.assume(String::from("i"), Assumption::new().nonzero());
+        self.imag = Some(v);
+        v
+    }
+
+    pub fn assumption(&self, v: Var) -> Assumption {
+        self.assumptions.get(&v).copied().unwrap_or_default()
+    }
+
+    // まだない名前 prefix0, prefix1, ... で変数を作る
+    pub fn fresh_var(&mut self, prefix: &str) -> Var {
+        let mut k = 0;
+        while self.search_var(&format!("{}{}", prefix, k)).is_some() {
+            k += 1;
         }
+        self.extend_var(format!("{}{}", prefix, k))
+    }
+
+    // id が n 以上の変数を消す. 一時的に作った変数の後始末に使う
+    pub fn truncate_vars(&mut self, n: usize) {
+        let removed: Vec<Var> = self.vars.keys().filter(|v| n <= v.id).copied().collect();
+        for v in removed {
+            let name = self.vars.remove(&v).unwrap();
+            self.rev_vars.remove(&name);
+            self.assumptions.remove(&v);
+            self.exprs.remove(&Expr::Var(v));
+            if self.imag == Some(v) {
+                self.imag = None;
+            }
+        }
+    }
+
+    pub fn search_var(&self, var_str: &String) -> Option<Var> {
+        self.rev_vars.get(var_str).copied()
     }
 
     pub fn extend_expr(&mut self, e: Expr) -> Rc<Expr> {
@@ -663,10 +983,7 @@ impl Environment {
     }
 
     fn search_expr(&self, e: &Expr) -> Option<Rc<Expr>> {
-        match self.exprs.get(&e) {
-            Some(ptr) => Some(ptr.clone()),
-            None => None,
-        }
+        self.exprs.get(e).cloned()
     }
 
     pub fn remove_expr(&mut self, e: &Expr) {
@@ -683,7 +1000,7 @@ impl Environment {
             change = false;
             {
                 for (exp, expp) in &self.exprs {
-                    if Rc::strong_count(&expp) == 1 {
+                    if Rc::strong_count(expp) == 1 {
                         remove_list.insert(exp.clone());
                     }
                 }
@@ -696,3 +1013,156 @@ impl Environment {
         }
     }
 }
+
+#[cfg(test)]
+fn parse_reduce(s: &str, e: &Env) -> Rc<Expr> {
+    match expr().parse(s, e) {
+        Ok((_, _, (expr, env))) => expr.reduce(env),
+        Err(_) => panic!("failed to parse {}", s),
+    }
+}
+
+#[test]
+fn reduce_interns_leaves() {
+    let e = &Environment::new();
+    // 以前は葉と符号を反転した定数を作り直していたので x - x が残っていた
+    assert_eq!(parse_reduce("x - x", e), Expr::new_num(0, e));
+    assert_eq!(parse_reduce("x / x", e), Expr::new_num(1, e));
+    assert!(Rc::ptr_eq(&parse_reduce("-3", e), &Expr::new_num(-3, e)));
+}
+
+#[test]
+fn non_commutative_order() {
+    let e = &Environment::new();
+    let x = String::from("x");
+    // 以前は被演算子を常に並べ替えていたので 1 - x が x - 1 になっていた
+    assert_eq!(-2., parse_reduce("1 - x", e).eval(&x, &vec![3.], e));
+    assert_eq!(0.5, parse_reduce("x / 6", e).eval(&x, &vec![3.], e));
+    assert_eq!(8., parse_reduce("2 ^ x", e).eval(&x, &vec![3.], e));
+    // 可換な演算は並べ替えて同じ式にする
+    assert_eq!(parse_reduce("x + y", e), parse_reduce("y + x", e));
+}
+
+#[test]
+fn reduce_zero_minus() {
+    let e = &Environment::new();
+    let x = String::from("x");
+    // 以前は 0 - x が x になっていた
+    assert_eq!(-3., parse_reduce("0 - x", e).eval(&x, &vec![3.], e));
+    assert_eq!(parse_reduce("0 - x", e), parse_reduce("-x", e));
+    assert_eq!(parse_reduce("0 - 2", e), Expr::new_num(-2, e));
+}
+
+#[test]
+fn reduce_square() {
+    let e = &Environment::new();
+    let x = String::from("x");
+    // 以前は x * x が 2 ^ x になっていた
+    assert_eq!(9., parse_reduce("x * x", e).eval(&x, &vec![3.], e));
+    assert_eq!(parse_reduce("x * x", e), parse_reduce("x ^ 2", e));
+}
+
+#[test]
+fn reduce_zero_div() {
+    let e = &Environment::new();
+    // 以前は 0 / y でも "zero div" でpanicしていた
+    assert_eq!(parse_reduce("0 / y", e), Expr::new_num(0, e));
+    assert_eq!(parse_reduce("(x - x) / (y + 1)", e), Expr::new_num(0, e));
+}
+
+#[test]
+#[should_panic(expected = "zero div")]
+fn reduce_div_by_zero() {
+    let e = &Environment::new();
+    parse_reduce("y / 0", e);
+}
+
+#[test]
+fn reduce_zero_pow() {
+    let e = &Environment::new();
+    let x = String::from("x");
+    // 以前は 0 ^ 0 が 0 になっていた. powf と同じく 1 にする
+    assert_eq!(parse_reduce("0 ^ 0", e), Expr::new_num(1, e));
+    assert_eq!(parse_reduce("0 ^ (x - x)", e), Expr::new_num(1, e));
+    assert_eq!(parse_reduce("0 ^ x", e), Expr::new_num(0, e));
+    assert_eq!(
+        0f64.powf(0.),
+        parse_reduce("0 ^ 0", e).eval(&x, &vec![0.], e)
+    );
+}
+
+#[test]
+fn reduce_with_assumptions() {
+    let e = &Environment::new();
+    let pos = Assumption::new().positive();
+    e.borrow_mut().assume(String::from("x"), pos);
+    e.borrow_mut().assume(String::from("y"), pos);
+    e.borrow_mut()
+        .assume(String::from("n"), Assumption::new().integer());
+    let same = |s: &str, t: &str| assert_eq!(parse_reduce(s, e), parse_reduce(t, e), "{}", s);
+    same("exp(log(x))", "x");
+    same("log(exp(x))", "x");
+    same("log(x ^ 3)", "3 * log(x)");
+    same("log(x * y)", "log(x) + log(y)");
+    same("log(x / y)", "log(x) - log(y)");
+    same("log(2 * x * y)", "log(2) + log(x) + log(y)");
+    same("exp(y * log(x))", "x ^ y");
+    same("(x ^ 2) ^ (1 / 2)", "x");
+    same("(x ^ y) ^ 3", "x ^ (3 * y)");
+
+    // 仮定のない変数はそのまま
+    let z = parse_reduce("exp(log(z))", e);
+    assert!(matches!(*z, Expr::UnOp { op: Uop::Exp, .. }));
+    let z = parse_reduce("log(z * w)", e);
+    assert!(matches!(*z, Expr::UnOp { op: Uop::Log, .. }));
+    let z = parse_reduce("(z ^ 2) ^ (1 / 2)", e);
+    assert!(matches!(*z, Expr::BinOp { op: Bop::Pow, .. }));
+    same("(z ^ 2) ^ n", "z ^ (2 * n)");
+    same("(z ^ 2) ^ 3", "z ^ 6");
+}
+
+#[test]
+fn diff_large_power() {
+    let e = &Environment::new();
+    let d = parse_expr("(x + 1) ^ 100", e).diff("x", e);
+    let x = String::from("x");
+    let v = d.eval(&x, &vec![0.5], e);
+    assert!((v / (100. * 1.5f64.powi(99)) - 1.).abs() < 1e-12, "{}", v);
+    // 展開せず定数冪の規則で微分するのでlogは出てこない
+    let reduced = d.reduce(e);
+    assert_eq!(reduced, parse_reduce("100 * (x + 1) ^ 99", e));
+    // 小さい多項式は今まで通りPolyで微分する
+    let p = parse_expr("(x + 1) ^ 3", e).diff("x", e);
+    assert_eq!(p, parse_expr("3 * x ^ 2 + 6 * x + 3", e));
+}
+
+#[test]
+fn reduce_num_pow() {
+    let e = &Environment::new();
+    assert_eq!(parse_reduce("2 ^ 62", e), Expr::new_num(1 << 62, e));
+    assert_eq!(parse_reduce("(2/3) ^ (0 - 2)", e), parse_reduce("9 / 4", e));
+    // i64に収まらなければ冪のまま
+    for s in ["2 ^ 64", "3 ^ 50", "(1/2) ^ 63", "(0 - 3) ^ (0 - 40)"].iter() {
+        let r = parse_reduce(s, e);
+        assert!(matches!(*r, Expr::BinOp { op: Bop::Pow, .. }), "{}", s);
+    }
+    // 四則演算もあふれるときは畳み込まない
+    let big = "4611686018427387904";
+    let r = parse_reduce(&format!("{} * 4", big), e);
+    assert!(matches!(*r, Expr::BinOp { op: Bop::Mul, .. }));
+    let r = parse_reduce(&format!("{} + {}", big, big), e);
+    assert!(matches!(*r, Expr::BinOp { op: Bop::Add, .. }));
+}
+
+#[test]
+fn plain_variable_i() {
+    let e = &Environment::new();
+    // use_imag しなければ i はただの実数の変数
+    let f = parse_expr("i*i + x", e);
+    let vals = vec![3., 1.];
+    assert_eq!(f.eval("i x", &vals, e), 10.);
+    assert_eq!(f.reduce(e).eval("i x", &vals, e), 10.);
+    let p = parse_reduce("i ^ 6", e);
+    assert!(!p.is_imag(e) && matches!(*p, Expr::BinOp { op: Bop::Pow, .. }));
+    assert_eq!(p.eval("i", &vec![2.], e), 64.);
+}
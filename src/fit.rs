@@ -0,0 +1,350 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Env, Expr, Rc, Var};
+#[cfg(test)]
+use super::parse::*;
+use super::solve::lin_solve;
+
+// 反復を終えた理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStatus {
+    Converged,
+    MaxIterations,
+    // 減衰係数を大きくしてもrssが減らない
+    Stalled,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitResult {
+    // paramsで与えた順
+    pub params: Vec<f64>,
+    pub covariance: Vec<Vec<f64>>,
+    pub std_errors: Vec<f64>,
+    // y - model
+    pub residuals: Vec<f64>,
+    // 残差平方和
+    pub rss: f64,
+    // rss / (データ数 - パラメータ数)
+    pub reduced_chi2: f64,
+    pub r_squared: f64,
+    pub iterations: usize,
+    pub status: FitStatus,
+}
+
+impl FitResult {
+    pub fn converged(&self) -> bool {
+        self.status == FitStatus::Converged
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FitOptions {
+    // rssの相対変化, または歩幅の相対値がこれ以下なら収束
+    pub tol: f64,
+    pub max_iter: usize,
+    // 減衰係数の初期値
+    pub lambda: f64,
+}
+
+impl Default for FitOptions {
+    fn default() -> Self {
+        FitOptions {
+            tol: 1e-12,
+            max_iter: 200,
+            lambda: 1e-3,
+        }
+    }
+}
+
+impl FitOptions {
+    pub fn new() -> Self {
+        FitOptions::default()
+    }
+
+    pub fn tol(mut self, tol: f64) -> Self {
+        self.tol = tol;
+        self
+    }
+
+    pub fn max_iter(mut self, n: usize) -> Self {
+        self.max_iter = n;
+        self
+    }
+}
+
+// モデルとパラメータについての偏導関数. 変数の並びは一度だけ作る
+#[derive(Debug, Clone)]
+pub struct CurveFit {
+    model: Rc<Expr>,
+    jac: Vec<Rc<Expr>>,
+    vars: Vec<Var>,
+    pslots: Vec<usize>,
+    xslots: Vec<usize>,
+}
+
+impl CurveFit {
+    // params, indep はそれぞれ与えた順に値を並べる
+    pub fn new(model: Rc<Expr>, params: &str, indep: &str, env: &Env) -> Self {
+        let ext = |s: &str| -> Vec<Var> {
+            s.split_whitespace()
+                .map(|v| env.borrow_mut().extend_var(String::from(v)))
+                .collect()
+        };
+        let (ps, xs) = (ext(params), ext(indep));
+        let mut vars: Vec<Var> = ps.iter().chain(&xs).cloned().collect();
+        vars.sort();
+        let slots = |vs: &[Var]| -> Vec<usize> {
+            vs.iter().map(|v| vars.binary_search(v).unwrap()).collect()
+        };
+        let (pslots, xslots) = (slots(&ps), slots(&xs));
+        let jac = params
+            .split_whitespace()
+            .map(|p| model.diff(p, env).reduce(env))
+            .collect();
+        CurveFit {
+            model,
+            jac,
+            vars,
+            pslots,
+            xslots,
+        }
+    }
+
+    fn bind(&self, p: &[f64], xs: &[Vec<f64>], i: usize) -> Vec<f64> {
+        let mut vals = vec![0.; self.vars.len()];
+        for (s, v) in self.pslots.iter().zip(p) {
+            vals[*s] = *v;
+        }
+        for (s, x) in self.xslots.iter().zip(xs) {
+            vals[*s] = x[i];
+        }
+        vals
+    }
+
+    fn residuals(&self, p: &[f64], xs: &[Vec<f64>], ys: &[f64]) -> Vec<f64> {
+        ys.iter()
+            .enumerate()
+            .map(|(i, y)| y - self.model.eval_internal(&self.vars, &self.bind(p, xs, i)))
+            .collect()
+    }
+
+    // i行k列は i 番目のデータ点でのモデルの p_k による偏微分
+    pub fn jacobian(&self, p: &[f64], xs: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        let n = xs.first().map(|x| x.len()).unwrap_or(0);
+        (0..n)
+            .map(|i| {
+                let vals = self.bind(p, xs, i);
+                self.jac
+                    .iter()
+                    .map(|d| d.eval_internal(&self.vars, &vals))
+                    .collect()
+            })
+            .collect()
+    }
+
+    // xs[k] は k 番目の独立変数のデータ
+    pub fn fit(&self, xs: &[Vec<f64>], ys: &[f64], p0: &[f64], opts: &FitOptions) -> FitResult {
+        assert!(
+            xs.len() == self.xslots.len() && xs.iter().all(|x| x.len() == ys.len()),
+            "data size mismatch"
+        );
+        assert!(
+            p0.len() == self.pslots.len(),
+            "number of parameters and p0 differ"
+        );
+        let np = p0.len();
+        let mut p = p0.to_vec();
+        let mut r = self.residuals(&p, xs, ys);
+        let mut rss: f64 = r.iter().map(|v| v * v).sum();
+        let mut lambda = opts.lambda;
+        let (mut iterations, mut status) = (0, FitStatus::MaxIterations);
+        let normal = |j: &[Vec<f64>], r: &[f64]| {
+            let a: Vec<Vec<f64>> = (0..np)
+                .map(|k| {
+                    (0..np)
+                        .map(|l| j.iter().map(|row| row[k] * row[l]).sum())
+                        .collect()
+                })
+                .collect();
+            let g: Vec<f64> = (0..np)
+                .map(|k| j.iter().zip(r).map(|(row, v)| row[k] * v).sum())
+                .collect();
+            (a, g)
+        };
+        while iterations < opts.max_iter && status == FitStatus::MaxIterations {
+            iterations += 1;
+            let (a, g) = normal(&self.jacobian(&p, xs), &r);
+            if g.iter().all(|v| *v == 0.) {
+                status = FitStatus::Converged;
+                break;
+            }
+            // (J^T J + λ diag(J^T J)) δ = J^T r
+            loop {
+                let mut damped = a.clone();
+                for (k, row) in damped.iter_mut().enumerate() {
+                    row[k] += lambda * a[k][k].max(1e-12);
+                }
+                let step = lin_solve(damped, g.clone());
+                let trial = step.as_ref().map(|d| {
+                    let pt: Vec<f64> = p.iter().zip(d).map(|(a, b)| a + b).collect();
+                    let rt = self.residuals(&pt, xs, ys);
+                    let rss_t: f64 = rt.iter().map(|v| v * v).sum();
+                    (pt, rt, rss_t)
+                });
+                match trial {
+                    Some((pt, rt, rss_t)) if rss_t.is_finite() && rss_t <= rss => {
+                        let dp = pt.iter().zip(&p).map(|(a, b)| (a - b).abs());
+                        let pn = p.iter().fold(0f64, |m, v| m.max(v.abs()));
+                        if rss - rss_t <= opts.tol * rss
+                            || dp.fold(0., f64::max) <= opts.tol * (pn + opts.tol)
+                        {
+                            status = FitStatus::Converged;
+                        }
+                        p = pt;
+                        r = rt;
+                        rss = rss_t;
+                        lambda /= 10.;
+                        break;
+                    }
+                    _ => {
+                        lambda *= 10.;
+                        // これ以上減らせない
+                        if 1e16 < lambda {
+                            status = FitStatus::Stalled;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let m = ys.len();
+        let dof = m.saturating_sub(np);
+        let reduced_chi2 = if dof == 0 { f64::NAN } else { rss / dof as f64 };
+        // cov = s^2 (J^T J)^{-1}
+        let (a, _) = normal(&self.jacobian(&p, xs), &r);
+        let covariance: Vec<Vec<f64>> = (0..np)
+            .map(|k| {
+                let mut e = vec![0.; np];
+                e[k] = 1.;
+                match lin_solve(a.clone(), e) {
+                    Some(col) => col.iter().map(|v| v * reduced_chi2).collect(),
+                    None => vec![f64::NAN; np],
+                }
+            })
+            .collect();
+        let mean = ys.iter().sum::<f64>() / m as f64;
+        let tss: f64 = ys.iter().map(|y| (y - mean).powi(2)).sum();
+        FitResult {
+            std_errors: (0..np).map(|k| covariance[k][k].sqrt()).collect(),
+            params: p,
+            covariance,
+            residuals: r,
+            rss,
+            reduced_chi2,
+            r_squared: 1. - rss / tss,
+            iterations,
+            status,
+        }
+    }
+}
+
+impl Expr {
+    // Levenberg-Marquardt法で model を (xs, ys) に合わせる
+    #[allow(clippy::too_many_arguments)]
+    pub fn curve_fit(
+        self: &Rc<Expr>,
+        params: &str,
+        indep: &str,
+        xs: &[Vec<f64>],
+        ys: &[f64],
+        p0: &[f64],
+        opts: &FitOptions,
+        env: &Env,
+    ) -> FitResult {
+        CurveFit::new(self.clone(), params, indep, env).fit(xs, ys, p0, opts)
+    }
+}
+
+#[test]
+fn fit_exponential_decay() {
+    let e = &Environment::new();
+    let model = parse_expr("a * exp(0 - b * t) + c", e);
+    let ts: Vec<f64> = (0..21).map(|i| 0.2 * i as f64).collect();
+    let truth = |t: f64| 2.5 * (-1.3 * t).exp() + 0.5;
+    let ys: Vec<f64> = ts.iter().map(|t| truth(*t)).collect();
+    let xs = vec![ts.clone()];
+    let opts = FitOptions::new();
+    let r = model.curve_fit("a b c", "t", &xs, &ys, &[1., 1., 0.], &opts, e);
+    assert!(r.converged(), "{:?}", r);
+    for (p, q) in r.params.iter().zip(&[2.5, 1.3, 0.5]) {
+        assert!((p - q).abs() < 1e-8, "{:?}", r.params);
+    }
+    assert!(r.rss < 1e-20 && (r.r_squared - 1.).abs() < 1e-12);
+
+    // 乱れを加えると誤差が付く
+    let noisy: Vec<f64> = ts
+        .iter()
+        .map(|t| truth(*t) + 0.01 * (7. * t).sin())
+        .collect();
+    let r = model.curve_fit("a b c", "t", &xs, &noisy, &[1., 1., 0.], &opts, e);
+    assert!(r.converged() && 0. < r.rss && r.r_squared < 1.);
+    for k in 0..3 {
+        assert!(0. < r.std_errors[k] && r.std_errors[k] < 0.05, "{:?}", r);
+        for l in 0..3 {
+            assert!((r.covariance[k][l] - r.covariance[l][k]).abs() < 1e-15);
+        }
+    }
+}
+
+#[test]
+fn fit_matches_linear_regression() {
+    let e = &Environment::new();
+    // 線形モデルなら正規方程式の解と一致する
+    let xs: Vec<f64> = (0..10).map(|i| i as f64).collect();
+    let ys: Vec<f64> = xs.iter().map(|x| 3. * x - 2. + (x * x).cos()).collect();
+    let n = xs.len() as f64;
+    let (sx, sy) = (xs.iter().sum::<f64>(), ys.iter().sum::<f64>());
+    let sxx: f64 = xs.iter().map(|x| x * x).sum();
+    let sxy: f64 = xs.iter().zip(&ys).map(|(x, y)| x * y).sum();
+    let slope = (n * sxy - sx * sy) / (n * sxx - sx * sx);
+    let intercept = (sy - slope * sx) / n;
+
+    let model = parse_expr("m * x + k", e);
+    let r = model.curve_fit("m k", "x", &[xs], &ys, &[0., 0.], &FitOptions::new(), e);
+    assert!((r.params[0] - slope).abs() < 1e-10 && (r.params[1] - intercept).abs() < 1e-10);
+    let s2 = r.rss / (n - 2.);
+    let var_slope = s2 * n / (n * sxx - sx * sx);
+    assert!((r.covariance[0][0] - var_slope).abs() < 1e-12);
+
+    // 独立変数が二つ
+    let us: Vec<f64> = (0..12).map(|i| 0.1 * i as f64).collect();
+    let vs: Vec<f64> = (0..12).map(|i| 1. + (i % 4) as f64).collect();
+    let zs: Vec<f64> = us
+        .iter()
+        .zip(&vs)
+        .map(|(u, v)| 1.5 * (0.8 * u).exp() * v)
+        .collect();
+    let model = parse_expr("p * exp(q * u) * v", e);
+    let r = model.curve_fit(
+        "p q",
+        "u v",
+        &[us, vs],
+        &zs,
+        &[1., 0.],
+        &FitOptions::new(),
+        e,
+    );
+    assert!((r.params[0] - 1.5).abs() < 1e-8 && (r.params[1] - 0.8).abs() < 1e-8);
+}
+
+#[test]
+fn fit_stalled() {
+    let e = &Environment::new();
+    // 初期値でモデルがNaNになるとrssが減らない
+    let model = parse_expr("a^(1/2) * x", e);
+    let xs = vec![vec![1., 2., 3.]];
+    let r = model.curve_fit("a", "x", &xs, &[1., 2., 3.], &[-1.], &FitOptions::new(), e);
+    assert_eq!(r.status, FitStatus::Stalled);
+    assert!(!r.converged() && r.rss.is_nan());
+}
@@ -0,0 +1,334 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Env, Expr, Rc};
+#[cfg(test)]
+use super::expr::{Bop, Uop};
+#[cfg(test)]
+use super::parse::*;
+
+// 検査する微分の実装
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    // Expr::diff してからreduceした式
+    Symbolic,
+    // Deriv::backward_grad
+    Graph,
+    // Deriv::reduce した後の backward_grad
+    ReducedGraph,
+}
+
+// 一つの成分での比較. ratio が 1 を超えると許容誤差外
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub source: Source,
+    pub var: String,
+    pub point: Vec<f64>,
+    pub analytic: f64,
+    pub numeric: f64,
+    pub ratio: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradCheck {
+    pub passed: bool,
+    // 比較した点の数. 値が有限でない点は数えない
+    pub checked: usize,
+    pub skipped: usize,
+    // 最も許容誤差に近い(または超えた)成分
+    pub worst: Option<Mismatch>,
+}
+
+// |analytic - numeric| <= atol + rtol max(|analytic|, |numeric|) なら合格
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheckOptions {
+    pub rtol: f64,
+    pub atol: f64,
+    // 中心差分の刻み. max(1, |x|) 倍して使う
+    pub step: f64,
+    // 乱数で選ぶ点の数と範囲
+    pub samples: usize,
+    pub range: (f64, f64),
+    pub seed: u64,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            rtol: 1e-5,
+            atol: 1e-7,
+            step: 1e-5,
+            samples: 20,
+            range: (-2., 2.),
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl CheckOptions {
+    pub fn new() -> Self {
+        CheckOptions::default()
+    }
+
+    pub fn rtol(mut self, tol: f64) -> Self {
+        self.rtol = tol;
+        self
+    }
+
+    pub fn atol(mut self, tol: f64) -> Self {
+        self.atol = tol;
+        self
+    }
+
+    pub fn step(mut self, h: f64) -> Self {
+        self.step = h;
+        self
+    }
+
+    pub fn samples(mut self, n: usize) -> Self {
+        self.samples = n;
+        self
+    }
+
+    pub fn range(mut self, lo: f64, hi: f64) -> Self {
+        self.range = (lo, hi);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+// xorshift64*. 再現できれば十分なので外部crateは使わない
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn range(&mut self, lo: f64, hi: f64) -> f64 {
+        lo + (hi - lo) * self.next_f64()
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+impl Expr {
+    // 与えた点で各実装の勾配を中心差分と比べる. 点の座標はvarsの順
+    pub fn check_gradient(
+        self: &Rc<Expr>,
+        vars: &str,
+        points: &[Vec<f64>],
+        opts: &CheckOptions,
+        env: &Env,
+    ) -> GradCheck {
+        let parsed = parse_vars(vars, env);
+        assert!(!parsed.is_empty(), "no variables to check");
+        assert!(
+            points.iter().all(|x| x.len() == parsed.len()),
+            "number of variables and coordinates of a point differ"
+        );
+        let names: Vec<String> = parsed
+            .iter()
+            .map(|v| env.borrow().vars[v].clone())
+            .collect();
+        let symbolic: Vec<Rc<Expr>> = names
+            .iter()
+            .map(|n| self.diff(n, env).reduce(env))
+            .collect();
+        let graph = Deriv::new(self.clone(), env, &names[0]);
+        let mut reduced = graph.clone();
+        reduced.reduce(env);
+
+        let mut res = GradCheck {
+            passed: true,
+            checked: 0,
+            skipped: 0,
+            worst: None,
+        };
+        for x in points {
+            let x = x.clone();
+            let f = |x: &Vec<f64>| self.eval_internal(&parsed, x);
+            let numeric: Vec<f64> = (0..x.len())
+                .map(|i| {
+                    let central = |h: f64| {
+                        let (mut a, mut b) = (x.clone(), x.clone());
+                        a[i] += h;
+                        b[i] -= h;
+                        (f(&a) - f(&b)) / (2. * h)
+                    };
+                    // Richardson補外で打ち切り誤差を h^4 にする
+                    let h = opts.step * x[i].abs().max(1.);
+                    (4. * central(h / 2.) - central(h)) / 3.
+                })
+                .collect();
+            if !f(&x).is_finite() || numeric.iter().any(|v| !v.is_finite()) {
+                res.skipped += 1;
+                continue;
+            }
+            res.checked += 1;
+            let grads = [
+                (
+                    Source::Symbolic,
+                    symbolic
+                        .iter()
+                        .map(|d| d.eval_internal(&parsed, &x))
+                        .collect(),
+                ),
+                (Source::Graph, graph.backward_grad(vars, &x, env)),
+                (Source::ReducedGraph, reduced.backward_grad(vars, &x, env)),
+            ];
+            for (source, g) in grads.iter() {
+                for (i, (a, n)) in g.iter().zip(&numeric).enumerate() {
+                    let ratio = (a - n).abs() / (opts.atol + opts.rtol * a.abs().max(n.abs()));
+                    // NaN も不合格として扱う
+                    let ratio = if ratio.is_nan() { f64::INFINITY } else { ratio };
+                    if res.worst.as_ref().is_none_or(|w| w.ratio < ratio) {
+                        res.worst = Some(Mismatch {
+                            source: *source,
+                            var: names[i].clone(),
+                            point: x.clone(),
+                            analytic: *a,
+                            numeric: *n,
+                            ratio,
+                        });
+                    }
+                    if 1. < ratio {
+                        res.passed = false;
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    // opts.range から一様に選んだ opts.samples 個の点で検査する
+    pub fn check_gradient_random(
+        self: &Rc<Expr>,
+        vars: &str,
+        opts: &CheckOptions,
+        env: &Env,
+    ) -> GradCheck {
+        let n = parse_vars(vars, env).len();
+        let mut rng = Rng::new(opts.seed);
+        let points: Vec<Vec<f64>> = (0..opts.samples)
+            .map(|_| {
+                (0..n)
+                    .map(|_| rng.range(opts.range.0, opts.range.1))
+                    .collect()
+            })
+            .collect();
+        self.check_gradient(vars, &points, opts, env)
+    }
+}
+
+// 定義域の端を避けた式を作る. logとDivの引数は正にしておく
+#[cfg(test)]
+fn random_expr(rng: &mut Rng, vars: &[Rc<Expr>], depth: usize, e: &Env) -> Rc<Expr> {
+    if depth == 0 || rng.below(4) == 0 {
+        return if rng.below(3) == 0 {
+            Expr::new_num(1 + rng.below(3) as i64, e)
+        } else {
+            vars[rng.below(vars.len())].clone()
+        };
+    }
+    let sub = random_expr(rng, vars, depth - 1, e);
+    let positive = |x: Rc<Expr>| {
+        let sq = Expr::new_binop(Bop::Mul, x.clone(), x, e);
+        Expr::new_binop(Bop::Add, sq, Expr::new_num(1, e), e)
+    };
+    match rng.below(10) {
+        0 => Expr::new_unop(Uop::Sin, sub, e),
+        1 => Expr::new_unop(Uop::Cos, sub, e),
+        2 => Expr::new_unop(Uop::Exp, Expr::new_unop(Uop::Sin, sub, e), e),
+        3 => Expr::new_unop(Uop::Log, positive(sub), e),
+        4 => Expr::new_unop(Uop::Neg, sub, e),
+        // 定数の冪や冪の冪は激しく振動させ, 差分の誤差が許容を超える
+        5 if !matches!(*sub, Expr::Num(_) | Expr::BinOp { op: Bop::Pow, .. }) => {
+            let k = Expr::new_num(2 + rng.below(2) as i64, e);
+            Expr::new_binop(Bop::Pow, sub, k, e)
+        }
+        5 => sub,
+        n => {
+            let other = random_expr(rng, vars, depth - 1, e);
+            match n {
+                6 => Expr::new_binop(Bop::Add, sub, other, e),
+                7 => Expr::new_binop(Bop::Sub, sub, other, e),
+                8 => Expr::new_binop(Bop::Mul, sub, other, e),
+                _ => Expr::new_binop(Bop::Div, sub, positive(other), e),
+            }
+        }
+    }
+}
+
+#[test]
+fn gradient_check_detects_errors() {
+    let e = &Environment::new();
+    let f = parse_expr("sin(x * y) + exp(x) / (y^2 + 1)", e);
+    let opts = CheckOptions::new();
+    let r = f.check_gradient("x y", &[vec![0.3, -1.2], vec![1.5, 0.7]], &opts, e);
+    assert!(r.passed && r.checked == 2 && r.skipped == 0, "{:?}", r);
+    let r = f.check_gradient_random("x y", &opts, e);
+    assert!(r.passed && r.checked == opts.samples, "{:?}", r);
+
+    // 定義域外の点は飛ばす
+    let r = parse_expr("log(x)", e).check_gradient("x", &[vec![-1.], vec![2.]], &opts, e);
+    assert!(r.passed && r.checked == 1 && r.skipped == 1, "{:?}", r);
+
+    // 刻みが粗いと非線形な変数だけ外れ, それが最悪として報告される
+    let coarse = opts.step(0.5);
+    let r = parse_expr("x + exp(3 * y)", e).check_gradient("x y", &[vec![0.1, 1.]], &coarse, e);
+    let w = r.worst.unwrap();
+    assert!(!r.passed && w.var == "y" && 1. < w.ratio, "{:?}", w);
+    assert!(
+        (w.analytic / (3. * 3f64.exp()) - 1.).abs() < 1e-12,
+        "{:?}",
+        w
+    );
+}
+
+#[test]
+fn gradient_check_random_expressions() {
+    let e = &Environment::new();
+    let vars = vec![
+        Expr::new_var(String::from("x"), e),
+        Expr::new_var(String::from("y"), e),
+        Expr::new_var(String::from("z"), e),
+    ];
+    let mut rng = Rng::new(42);
+    let opts = CheckOptions::new().samples(5).range(-1., 1.);
+    for i in 0..60 {
+        let f = random_expr(&mut rng, &vars, 4, e);
+        let r = f.check_gradient_random("x y z", &opts.seed(i + 1), e);
+        if !r.passed {
+            f.print(e);
+        }
+        assert!(r.passed && 0 < r.checked, "{:?}", r);
+    }
+}
+
+#[test]
+#[should_panic(expected = "number of variables and coordinates of a point differ")]
+fn gradient_check_point_size() {
+    let e = &Environment::new();
+    let f = parse_expr("x * y", e);
+    f.check_gradient("x y", &[vec![1.]], &CheckOptions::new(), e);
+}
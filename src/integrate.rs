@@ -0,0 +1,883 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Bop, Env, Expr, HashMap, One, Rc, Uop, Var, Zero, C};
+use super::interval::Interval;
+#[cfg(test)]
+use super::parse::*;
+use super::poly::{igcd, Poly};
+use super::ratfunc::RatFunc;
+use super::scalar::Scalar;
+
+// 積分できなかった式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotIntegrable(pub Expr);
+
+// 置換積分や展開の入れ子の深さの上限
+const MAX_DEPTH: usize = 4;
+
+// 因子の積 Π base^exp
+type Factors = Vec<(Rc<Expr>, C)>;
+
+fn depends(expr: &Expr, v: Var) -> bool {
+    expr.free_vars().contains(&v)
+}
+
+fn binop(op: Bop, a: Rc<Expr>, b: Rc<Expr>, env: &Env) -> Rc<Expr> {
+    Expr::new_binop(op, a, b, env)
+}
+
+fn num(c: C, env: &Env) -> Rc<Expr> {
+    Expr::new_num_from_rat(c, env)
+}
+
+// 係数と因子に分ける. 同じ底の指数はまとめる
+fn flatten(expr: &Rc<Expr>) -> (C, Factors) {
+    fn walk(expr: &Rc<Expr>, k: C, coef: &mut C, res: &mut Factors) {
+        let unit = k == C::one() || k == -C::one();
+        match &**expr {
+            Expr::Num(n) if k == C::one() => *coef *= *n,
+            Expr::Num(n) if k == -C::one() && !n.is_zero() => *coef /= *n,
+            Expr::UnOp { op: Uop::Neg, exp } if unit => {
+                *coef = -*coef;
+                walk(exp, k, coef, res);
+            }
+            Expr::BinOp {
+                op: Bop::Mul,
+                exp1,
+                exp2,
+            } => {
+                walk(exp1, k, coef, res);
+                walk(exp2, k, coef, res);
+            }
+            Expr::BinOp {
+                op: Bop::Div,
+                exp1,
+                exp2,
+            } => {
+                walk(exp1, k, coef, res);
+                walk(exp2, -k, coef, res);
+            }
+            Expr::BinOp {
+                op: Bop::Pow,
+                exp1,
+                exp2,
+            } if unit => match **exp2 {
+                Expr::Num(r) => res.push((exp1.clone(), r * k)),
+                _ => res.push((expr.clone(), k)),
+            },
+            _ => res.push((expr.clone(), k)),
+        }
+    }
+    let mut coef = C::one();
+    let mut fs: Factors = vec![];
+    walk(expr, C::one(), &mut coef, &mut fs);
+    let mut res: Factors = vec![];
+    for (b, k) in fs {
+        match res.iter_mut().find(|(c, _)| *c == b) {
+            Some((_, l)) => *l += k,
+            None => res.push((b, k)),
+        }
+    }
+    res.retain(|(_, k)| !k.is_zero());
+    (coef, res)
+}
+
+fn rebuild(coef: C, fs: &[(Rc<Expr>, C)], env: &Env) -> Rc<Expr> {
+    let mut num_part = num(coef, env);
+    let mut den_part = Expr::new_num(1, env);
+    for (b, k) in fs {
+        let p = |k: C| match k.is_one() {
+            true => b.clone(),
+            false => binop(Bop::Pow, b.clone(), num(k, env), env),
+        };
+        if *k < C::zero() {
+            den_part = binop(Bop::Mul, den_part, p(-*k), env);
+        } else {
+            num_part = binop(Bop::Mul, num_part, p(*k), env);
+        }
+    }
+    binop(Bop::Div, num_part, den_part, env).reduce(env)
+}
+
+// uがvについて1次なら傾き
+fn slope(u: &Rc<Expr>, v: Var, env: &Env) -> Option<Rc<Expr>> {
+    if !depends(u, v) {
+        return None;
+    }
+    let name = env.borrow().vars[&v].clone();
+    let a = u.diff(&name, env).reduce(env);
+    if depends(&a, v) || a.is_zero() {
+        None
+    } else {
+        Some(a)
+    }
+}
+
+// base^k (底はvについて1次) と, 1次式を引数に取る初等関数の表
+fn table(base: &Rc<Expr>, k: C, v: Var, env: &Env) -> Option<Rc<Expr>> {
+    if let Some(a) = slope(base, v, env) {
+        return Some(if k == -C::one() {
+            let l = Expr::new_unop(Uop::Log, base.clone(), env);
+            binop(Bop::Div, l, a, env)
+        } else {
+            let k1 = k + C::one();
+            let p = binop(Bop::Pow, base.clone(), num(k1, env), env);
+            let d = binop(Bop::Mul, num(k1, env), a, env);
+            binop(Bop::Div, p, d, env)
+        });
+    }
+    if !k.is_one() {
+        return None;
+    }
+    match &**base {
+        Expr::UnOp { op, exp: u } => {
+            let a = slope(u, v, env)?;
+            let f = |op: Uop| Expr::new_unop(op, u.clone(), env);
+            let res = match op {
+                Uop::Sin => Expr::new_unop(Uop::Neg, f(Uop::Cos), env),
+                Uop::Cos => f(Uop::Sin),
+                Uop::Tan => {
+                    Expr::new_unop(Uop::Neg, Expr::new_unop(Uop::Log, f(Uop::Cos), env), env)
+                }
+                Uop::Exp => base.clone(),
+                Uop::Log => {
+                    let ul = binop(Bop::Mul, u.clone(), base.clone(), env);
+                    binop(Bop::Sub, ul, u.clone(), env)
+                }
+                Uop::Neg => return None,
+            };
+            Some(binop(Bop::Div, res, a, env))
+        }
+        // c^u = exp(u log c)
+        Expr::BinOp {
+            op: Bop::Pow,
+            exp1: c,
+            exp2: u,
+        } if !depends(c, v) => {
+            let a = slope(u, v, env)?;
+            let l = Expr::new_unop(Uop::Log, c.clone(), env);
+            Some(binop(
+                Bop::Div,
+                base.clone(),
+                binop(Bop::Mul, a, l, env),
+                env,
+            ))
+        }
+        _ => None,
+    }
+}
+
+// f = exp, sin, cos (引数は傾きaの1次式) のn回積分
+fn nth_antiderivative(op: Uop, u: &Rc<Expr>, a: &Rc<Expr>, n: u32, env: &Env) -> Rc<Expr> {
+    let f = |op: Uop| Expr::new_unop(op, u.clone(), env);
+    // sinとcosは4回で一周する
+    let (g, neg) = match (op, n % 4) {
+        (Uop::Exp, _) => (f(Uop::Exp), false),
+        (Uop::Sin, 0) | (Uop::Cos, 1) => (f(Uop::Sin), false),
+        (Uop::Sin, 2) | (Uop::Cos, 3) => (f(Uop::Sin), true),
+        (Uop::Cos, 0) | (Uop::Sin, 3) => (f(Uop::Cos), false),
+        _ => (f(Uop::Cos), true),
+    };
+    let g = if neg {
+        Expr::new_unop(Uop::Neg, g, env)
+    } else {
+        g
+    };
+    let an = binop(Bop::Pow, a.clone(), Expr::new_num(i64::from(n), env), env);
+    binop(Bop::Div, g, an, env)
+}
+
+// 多項式 × exp, sin, cos (1次式) を部分積分の繰り返しで求める
+// ∫ p f = Σ (-1)^j p^(j) F_(j+1)
+fn by_parts(fs: &[(Rc<Expr>, C)], v: Var, env: &Env) -> Option<Rc<Expr>> {
+    let i = fs.iter().position(|(b, k)| {
+        k.is_one()
+            && matches!(&**b, Expr::UnOp { op, exp } if matches!(op, Uop::Exp | Uop::Sin | Uop::Cos)
+                && slope(exp, v, env).is_some())
+    })?;
+    let (op, u) = match &*fs[i].0 {
+        Expr::UnOp { op, exp } => (*op, exp.clone()),
+        _ => unreachable!(),
+    };
+    let a = slope(&u, v, env)?;
+    let rest: Factors = fs
+        .iter()
+        .enumerate()
+        .filter(|(j, _)| *j != i)
+        .map(|(_, f)| f.clone())
+        .collect();
+    let mut p = Poly::from_expr(&rebuild(C::one(), &rest, env)).ok()?;
+    let mut res = Expr::new_num(0, env);
+    let mut j = 0;
+    while !p.is_zero() {
+        let t = binop(
+            Bop::Mul,
+            p.to_expr(env),
+            nth_antiderivative(op, &u, &a, j + 1, env),
+            env,
+        );
+        let op = if j % 2 == 0 { Bop::Add } else { Bop::Sub };
+        res = binop(op, res, t, env);
+        p = p.derivative(v);
+        j += 1;
+    }
+    Some(res)
+}
+
+// 置換 u = g(x). 被積分関数が f(g) g' の形なら ∫ f(u) du に g を代入する
+fn substitution(fs: &[(Rc<Expr>, C)], v: Var, depth: usize, env: &Env) -> Option<Rc<Expr>> {
+    let mut candidates: Vec<Rc<Expr>> = vec![];
+    fn collect(e: &Rc<Expr>, v: Var, res: &mut Vec<Rc<Expr>>) {
+        if !depends(e, v) || matches!(**e, Expr::Var(_)) || res.contains(e) {
+            return;
+        }
+        res.push(e.clone());
+        match &**e {
+            Expr::UnOp { exp, .. } => collect(exp, v, res),
+            Expr::BinOp { exp1, exp2, .. } => {
+                collect(exp1, v, res);
+                collect(exp2, v, res);
+            }
+            _ => (),
+        }
+    }
+    for (b, _) in fs {
+        collect(b, v, &mut candidates);
+    }
+    let name = env.borrow().vars[&v].clone();
+    // 積分の最後に消す
+    let u = env.borrow_mut().fresh_var("_u");
+    let ux = env.borrow_mut().extend_expr(Expr::Var(u));
+    for g in candidates {
+        let dg = g.diff(&name, env).reduce(env);
+        if dg.is_zero() {
+            continue;
+        }
+        // 被積分関数を g' で割る
+        let (dc, dfs) = flatten(&dg);
+        let (mut coef, mut rest) = (C::one(), fs.to_vec());
+        let mut ok = !dc.is_zero();
+        for (b, k) in dfs {
+            if !depends(&b, v) {
+                let c = rebuild(C::one(), &[(b, k)], env);
+                rest.push((c, -C::one()));
+                continue;
+            }
+            match rest.iter_mut().find(|(c, _)| *c == b) {
+                Some((_, l)) => *l -= k,
+                None => {
+                    ok = false;
+                    break;
+                }
+            }
+        }
+        if !ok {
+            continue;
+        }
+        coef /= dc;
+        rest.retain(|(_, k)| !k.is_zero());
+        let mut map = HashMap::new();
+        map.insert(g.clone(), ux.clone());
+        let h = rebuild(coef, &rest, env).subs(&map, true, env);
+        if depends(&h, v) || !depends(&h, u) {
+            continue;
+        }
+        if let Some(r) = integrate_internal(&h, u, depth + 1, env) {
+            let mut back = HashMap::new();
+            back.insert(u, g.clone());
+            return Some(r.subs_vars(&back, false, env));
+        }
+    }
+    None
+}
+
+fn integrate_internal(expr: &Rc<Expr>, v: Var, depth: usize, env: &Env) -> Option<Rc<Expr>> {
+    let x = env.borrow_mut().extend_expr(Expr::Var(v));
+    if !depends(expr, v) {
+        return Some(binop(Bop::Mul, expr.clone(), x, env));
+    }
+    match &**expr {
+        Expr::BinOp {
+            op: op @ (Bop::Add | Bop::Sub),
+            exp1,
+            exp2,
+        } => {
+            let a = integrate_internal(exp1, v, depth, env)?;
+            let b = integrate_internal(exp2, v, depth, env)?;
+            return Some(binop(*op, a, b, env));
+        }
+        Expr::UnOp { op: Uop::Neg, exp } => {
+            let a = integrate_internal(exp, v, depth, env)?;
+            return Some(Expr::new_unop(Uop::Neg, a, env));
+        }
+        _ => (),
+    }
+    if let Ok(p) = Poly::from_expr(expr) {
+        return Some(p.antiderivative(v).to_expr(env));
+    }
+    // vによらない因子をくくり出す
+    let (coef, fs) = flatten(expr);
+    let (consts, fs): (Factors, Factors) = fs.into_iter().partition(|(b, _)| !depends(b, v));
+    let c = rebuild(coef, &consts, env);
+    let f = rebuild(C::one(), &fs, env);
+    let res = integrate_factors(&f, &fs, v, depth, env)?;
+    Some(binop(Bop::Mul, c, res, env))
+}
+
+fn integrate_factors(
+    f: &Rc<Expr>,
+    fs: &[(Rc<Expr>, C)],
+    v: Var,
+    depth: usize,
+    env: &Env,
+) -> Option<Rc<Expr>> {
+    if let Ok(r) = RatFunc::from_expr(f) {
+        if let Some(res) = integrate_rational(&r, v, env) {
+            return Some(res);
+        }
+    }
+    if fs.len() == 1 {
+        if let Some(res) = table(&fs[0].0, fs[0].1, v, env) {
+            return Some(res);
+        }
+    }
+    if let Some(res) = by_parts(fs, v, env) {
+        return Some(res);
+    }
+    if MAX_DEPTH <= depth {
+        return None;
+    }
+    if let Some(res) = substitution(fs, v, depth, env) {
+        return Some(res);
+    }
+    // 展開して和になるなら項ごとに積分する
+    let ex = f.expand(env);
+    match &*ex {
+        Expr::BinOp {
+            op: Bop::Add | Bop::Sub,
+            ..
+        } if ex != *f => integrate_internal(&ex, v, depth + 1, env),
+        _ => None,
+    }
+}
+
+// vだけの1変数多項式を係数列 (低次から) にする
+fn to_dense(p: &Poly, v: Var) -> Option<Vec<C>> {
+    if p.vars().iter().any(|w| *w != v) {
+        return None;
+    }
+    let mut res = vec![C::zero(); p.degree(v) as usize + 1];
+    for (k, c) in p.coeffs(v) {
+        res[k as usize] = c.constant_value()?;
+    }
+    Some(res)
+}
+
+fn from_dense(c: &[C], v: Var) -> Poly {
+    let mut p = Poly::zero();
+    for (k, a) in c.iter().enumerate() {
+        if 0 < k {
+            p.add_term(vec![(v, k as u32)], *a);
+        } else {
+            p.add_term(vec![], *a);
+        }
+    }
+    p
+}
+
+fn trim(mut c: Vec<C>) -> Vec<C> {
+    while 1 < c.len() && c.last().unwrap().is_zero() {
+        c.pop();
+    }
+    c
+}
+
+// a = q b + r
+fn divmod(a: &[C], b: &[C]) -> (Vec<C>, Vec<C>) {
+    let mut r = a.to_vec();
+    let n = b.len() - 1;
+    if r.len() <= n {
+        return (vec![C::zero()], r);
+    }
+    let mut q = vec![C::zero(); r.len() - n];
+    for i in (0..q.len()).rev() {
+        let t = r[i + n] / b[n];
+        q[i] = t;
+        for (j, bj) in b.iter().enumerate() {
+            r[i + j] -= t * *bj;
+        }
+    }
+    r.truncate(n.max(1));
+    (q, trim(r))
+}
+
+fn horner(c: &[C], x: C) -> C {
+    c.iter().rev().fold(C::zero(), |acc, a| acc * x + *a)
+}
+
+// (v - a) で割った商. aは根とする
+fn deflate(c: &[C], a: C) -> Vec<C> {
+    let n = c.len() - 1;
+    let mut q = vec![C::zero(); n];
+    let mut carry = C::zero();
+    for k in (0..n).rev() {
+        carry = c[k + 1] + carry * a;
+        q[k] = carry;
+    }
+    q
+}
+
+fn divisors(n: i64) -> Vec<i64> {
+    let n = n.abs();
+    let mut res = vec![];
+    let mut d = 1;
+    while d * d <= n {
+        if n % d == 0 {
+            res.push(d);
+            res.push(n / d);
+        }
+        d += 1;
+    }
+    res
+}
+
+fn push_root(roots: &mut Vec<(C, u32)>, a: C) {
+    match roots.iter_mut().find(|(b, _)| *b == a) {
+        Some((_, m)) => *m += 1,
+        None => roots.push((a, 1)),
+    }
+}
+
+// 有理根とその重複度. 残りの因子も返す
+fn rational_roots(c: &[C]) -> (Vec<(C, u32)>, Vec<C>) {
+    let mut c = trim(c.to_vec());
+    let mut roots: Vec<(C, u32)> = vec![];
+    while 1 < c.len() && c[0].is_zero() {
+        c.remove(0);
+        push_root(&mut roots, C::zero());
+    }
+    while 1 < c.len() {
+        // 整数係数にして p | a_0, q | a_n を試す
+        let l = c
+            .iter()
+            .fold(1i64, |l, a| l / igcd(l, *a.denom()) * *a.denom());
+        let a0 = (c[0] * C::from(l)).to_integer();
+        let an = (c[c.len() - 1] * C::from(l)).to_integer();
+        let mut found = None;
+        'search: for p in divisors(a0) {
+            for q in divisors(an) {
+                for r in [C::new(p, q), C::new(-p, q)].iter() {
+                    if horner(&c, *r).is_zero() {
+                        found = Some(*r);
+                        break 'search;
+                    }
+                }
+            }
+        }
+        match found {
+            Some(r) => {
+                c = deflate(&c, r);
+                push_root(&mut roots, r);
+            }
+            None => break,
+        }
+    }
+    (roots, c)
+}
+
+// c(v) を (v - a) のべきで表した係数
+fn shift(c: &[C], a: C) -> Vec<C> {
+    let mut c = c.to_vec();
+    let mut res = vec![];
+    loop {
+        let r = horner(&c, a);
+        res.push(r);
+        if c.len() == 1 {
+            return res;
+        }
+        c[0] -= r;
+        c = deflate(&c, a);
+    }
+}
+
+// 部分分数分解して積分する. 分母は有理数の1次式と高々1つの既約2次式に分かれること
+fn integrate_rational(r: &RatFunc, v: Var, env: &Env) -> Option<Rc<Expr>> {
+    let num_c = to_dense(&r.num, v)?;
+    let den_c = trim(to_dense(&r.den, v)?);
+    let (q, rem) = divmod(&num_c, &den_c);
+    let x = env.borrow_mut().extend_expr(Expr::Var(v));
+    let mut res = from_dense(&q, v).antiderivative(v).to_expr(env);
+    if rem.iter().all(|c| c.is_zero()) {
+        return Some(res);
+    }
+    let (roots, quad) = rational_roots(&den_c);
+    if quad.len() != 1 && quad.len() != 3 {
+        return None;
+    }
+    let rem_r = RatFunc::new(from_dense(&rem, v), from_dense(&den_c, v));
+    let mut linear = RatFunc::from_poly(Poly::zero());
+    for (a, m) in &roots {
+        // g = rem / (den / (v - a)^m) の a での Taylor 係数
+        let mut other = den_c.clone();
+        for _ in 0..*m {
+            other = deflate(&other, *a);
+        }
+        let (ns, ds) = (shift(&rem, *a), shift(&other, *a));
+        let mut g: Vec<C> = vec![];
+        for k in 0..*m as usize {
+            let s = (1..=k).fold(*ns.get(k).unwrap_or(&C::zero()), |acc, j| {
+                acc - *ds.get(j).unwrap_or(&C::zero()) * g[k - j]
+            });
+            g.push(s / ds[0]);
+        }
+        let xa = binop(Bop::Sub, x.clone(), num(*a, env), env);
+        let base = from_dense(&[-*a, C::one()], v);
+        for k in 1..=*m {
+            // A / (v - a)^k
+            let c = g[(*m - k) as usize];
+            if c.is_zero() {
+                continue;
+            }
+            linear = linear.add(&RatFunc::new(Poly::constant(c), base.pow(k)));
+            let t = if k == 1 {
+                binop(
+                    Bop::Mul,
+                    num(c, env),
+                    Expr::new_unop(Uop::Log, xa.clone(), env),
+                    env,
+                )
+            } else {
+                let k1 = C::from(i64::from(k) - 1);
+                let p = binop(Bop::Pow, xa.clone(), num(-k1, env), env);
+                binop(Bop::Mul, num(-c / k1, env), p, env)
+            };
+            res = binop(Bop::Add, res, t, env);
+        }
+    }
+    if quad.len() == 1 {
+        return Some(res);
+    }
+    // 残りは (b v + c) / (v^2 + p v + q)
+    let rest = rem_r.sub(&linear);
+    let rn = to_dense(&rest.num, v)?;
+    let rd = trim(to_dense(&rest.den, v)?);
+    if rest.num.is_zero() {
+        return Some(res);
+    }
+    if rd.len() != 3 {
+        return None;
+    }
+    let lead = rd[2];
+    let (p, qq) = (rd[1] / lead, rd[0] / lead);
+    let b = *rn.get(1).unwrap_or(&C::zero()) / lead;
+    let c = rn[0] / lead;
+    let two = C::from(2);
+    let monic = from_dense(&[qq, p, C::one()], v).to_expr(env);
+    let l = Expr::new_unop(Uop::Log, monic, env);
+    res = binop(
+        Bop::Add,
+        res,
+        binop(Bop::Mul, num(b / two, env), l, env),
+        env,
+    );
+    let k = c - b * p / two;
+    if k.is_zero() {
+        return Some(res);
+    }
+    // 1 / ((v + h)^2 - s^2) = 1/(2s) log((v + h - s) / (v + h + s))
+    // s^2 < 0 なら arctan が要るので積分できない
+    let h = p / two;
+    let s2 = h * h - qq;
+    if s2 <= C::zero() {
+        return None;
+    }
+    let s = binop(Bop::Pow, num(s2, env), num(C::new(1, 2), env), env);
+    let xh = binop(Bop::Add, x, num(h, env), env);
+    let ratio = binop(
+        Bop::Div,
+        binop(Bop::Sub, xh.clone(), s.clone(), env),
+        binop(Bop::Add, xh, s.clone(), env),
+        env,
+    );
+    let coef = binop(Bop::Div, num(k / two, env), s, env);
+    let t = binop(Bop::Mul, coef, Expr::new_unop(Uop::Log, ratio, env), env);
+    Some(binop(Bop::Add, res, t, env))
+}
+
+// F' - f が0に簡約されるか, いくつかの点で数値的に一致するか
+fn differentiates_back(res: &Rc<Expr>, f: &Rc<Expr>, v: &str, env: &Env) -> bool {
+    let d = binop(Bop::Sub, res.diff(v, env), f.clone(), env).reduce(env);
+    if d.is_zero() {
+        return true;
+    }
+    let vars: Vec<Var> = d.free_vars().union(&f.free_vars()).copied().collect();
+    let mut checked = 0;
+    for t in [1.3, 2.1, 2.9] {
+        let vals: Vec<f64> = (0..vars.len()).map(|k| t + 0.37 * k as f64).collect();
+        let (lhs, rhs) = (d.eval_internal(&vars, &vals), f.eval_internal(&vars, &vals));
+        // 定義域の外の点は使わない
+        if !lhs.is_finite() || !rhs.is_finite() {
+            continue;
+        }
+        if 1e-8 * (1. + rhs.abs()) < lhs.abs() {
+            return false;
+        }
+        checked += 1;
+    }
+    0 < checked
+}
+
+// v が iv を動くとき式が有限で連続か. vだけによる部分式は区間で評価して確かめ,
+// 他の変数と混ざった分母, logの引数, 冪の底は確かめられないのでfalseにする
+fn regular_on(expr: &Rc<Expr>, v: Var, iv: Interval) -> bool {
+    if !depends(expr, v) {
+        return true;
+    }
+    // v 以外の変数がないときだけ区間で評価できる
+    let pure = |e: &Rc<Expr>| e.free_vars().iter().all(|w| *w == v);
+    let at = |e: &Rc<Expr>| e.eval_interval_internal(&[v], &[iv]);
+    let ok = match &**expr {
+        Expr::Var(_) | Expr::Num(_) => true,
+        Expr::UnOp { op, exp } => match op {
+            Uop::Log | Uop::Tan if !pure(exp) => false,
+            Uop::Log => 0. < at(exp).lo && regular_on(exp, v, iv),
+            _ => regular_on(exp, v, iv),
+        },
+        Expr::BinOp { op, exp1, exp2 } => {
+            let n = match **exp2 {
+                Expr::Num(n) if n.is_integer() => Some(*n.numer()),
+                _ => None,
+            };
+            let base_ok = match op {
+                Bop::Div => !depends(exp2, v) || (pure(exp2) && !at(exp2).contains_zero()),
+                Bop::Pow => match n {
+                    Some(n) if 0 <= n => true,
+                    _ if !pure(exp1) || !pure(exp2) => false,
+                    Some(_) => !at(exp1).contains_zero(),
+                    None if 0. < at(exp2).lo => 0. <= at(exp1).lo,
+                    None => 0. < at(exp1).lo,
+                },
+                _ => true,
+            };
+            base_ok && regular_on(exp1, v, iv) && regular_on(exp2, v, iv)
+        }
+    };
+    let finite = |i: Interval| !i.is_empty() && i.lo.is_finite() && i.hi.is_finite();
+    ok && (!pure(expr) || finite(at(expr)))
+}
+
+// F の log(u) を, iv の上で u < 0 なら log(-u) にする. 符号が決まらなければNone
+fn log_branch(f: &Rc<Expr>, v: Var, iv: Interval, env: &Env) -> Option<Rc<Expr>> {
+    if !depends(f, v) {
+        return Some(f.clone());
+    }
+    match &**f {
+        Expr::UnOp { op: Uop::Log, exp } => {
+            if exp.free_vars().len() != 1 {
+                return None;
+            }
+            let u = exp.eval_interval_internal(&[v], &[iv]);
+            let exp = log_branch(exp, v, iv, env)?;
+            if 0. < u.lo {
+                Some(Expr::new_unop(Uop::Log, exp, env))
+            } else if u.hi < 0. {
+                let neg = Expr::new_unop(Uop::Neg, exp, env);
+                Some(Expr::new_unop(Uop::Log, neg, env))
+            } else {
+                None
+            }
+        }
+        Expr::UnOp { op, exp } => Some(Expr::new_unop(*op, log_branch(exp, v, iv, env)?, env)),
+        Expr::BinOp { op, exp1, exp2 } => Some(binop(
+            *op,
+            log_branch(exp1, v, iv, env)?,
+            log_branch(exp2, v, iv, env)?,
+            env,
+        )),
+        _ => Some(f.clone()),
+    }
+}
+
+impl Expr {
+    // vについての不定積分. 積分定数は付けない
+    // 結果は微分して元に戻ることを確かめてから返す
+    pub fn integrate(&self, v: &str, env: &Env) -> Result<Rc<Expr>, NotIntegrable> {
+        let var = env.borrow_mut().extend_var(String::from(v));
+        let n = env.borrow().vars.len();
+        let me = self.reduce(env);
+        let res = integrate_internal(&me, var, 0, env).map(|r| r.reduce(env));
+        let res = res.filter(|r| r.free_vars().iter().all(|u| u.id < n));
+        env.borrow_mut().truncate_vars(n);
+        match res {
+            Some(r) if differentiates_back(&r, &me, v, env) => Ok(r),
+            _ => Err(NotIntegrable(self.clone())),
+        }
+    }
+
+    // a から b までの定積分. 区間に極や定義域の端があれば積分できないと返す
+    pub fn integrate_definite(
+        &self,
+        v: &str,
+        a: C,
+        b: C,
+        env: &Env,
+    ) -> Result<Rc<Expr>, NotIntegrable> {
+        let f = self.integrate(v, env)?;
+        let var = env.borrow_mut().extend_var(String::from(v));
+        let (lo, hi) = (Interval::from_rat(a.min(b)), Interval::from_rat(a.max(b)));
+        let iv = lo.hull(&hi);
+        // log(u) は u < 0 の区間では log(-u) を原始関数に使う
+        let f = match log_branch(&f, var, iv, env) {
+            Some(g) if regular_on(&self.reduce(env), var, iv) && regular_on(&g, var, iv) => g,
+            _ => return Err(NotIntegrable(self.clone())),
+        };
+        let at = |c: C| {
+            let mut map = HashMap::new();
+            map.insert(var, num(c, env));
+            f.subs_vars(&map, true, env)
+        };
+        Ok(binop(Bop::Sub, at(b), at(a), env).reduce(env))
+    }
+}
+
+#[test]
+fn integrate_and_differentiate_back() {
+    let e = &Environment::new();
+    let check = |s: &str| {
+        let f = parse_expr(s, e);
+        let g = match f.integrate("x", e) {
+            Ok(g) => g,
+            Err(_) => panic!("cannot integrate {}", s),
+        };
+        let d = g.diff("x", e).reduce(e);
+        for x in [2.5, 3.1, 4.2].iter() {
+            let vals = vec![*x, 0.7];
+            let (lhs, rhs) = (d.eval("x y", &vals, e), f.eval("x y", &vals, e));
+            assert!(
+                (lhs - rhs).abs() < 1e-9 * (1. + rhs.abs()),
+                "{}: {} {}",
+                s,
+                lhs,
+                rhs
+            );
+        }
+    };
+    e.borrow_mut().extend_var(String::from("x"));
+    e.borrow_mut().extend_var(String::from("y"));
+    for s in [
+        "3*x^2 + 2*x + 1",
+        "exp(2*x + 1)",
+        "sin(3*x) + cos(x) / 2",
+        "tan(x)",
+        "log(x)",
+        "y * sin(x) + y^2",
+        "x * exp(x)",
+        "x^2 * sin(x)",
+        "(x + 1) * cos(2*x)",
+        "x * cos(x^2)",
+        "exp(sin(x)) * cos(x)",
+        "sin(x)^3 * cos(x)",
+        "log(x) / x",
+        "1 / (2*x + 1)",
+        "(x + 1) ^ (1/2)",
+        "2 ^ x",
+        "1 / (x^2 - 1)",
+        "(x^3 + 1) / (x^2 - 3*x + 2)",
+        "1 / (x * (x + 1)^2)",
+        "(2*x + 3) / (x^2 - 2)",
+        "x / (x^2 + 1)",
+        "(x + 1)^2 * exp(x)",
+    ]
+    .iter()
+    {
+        check(s);
+    }
+}
+
+#[test]
+fn integrate_exact_and_failures() {
+    let e = &Environment::new();
+    let p = parse_expr("3*x^2 + 2*x + 1", e).integrate("x", e).unwrap();
+    assert_eq!(
+        Poly::from_expr(&p),
+        Poly::from_expr(&parse_expr("x^3 + x^2 + x", e))
+    );
+    // 定積分は有理数の端点で厳密に
+    let d = parse_expr("x^2 - 1/2", e).integrate_definite("x", C::new(0, 1), C::new(3, 2), e);
+    assert_eq!(d, Ok(Expr::new_num_from_rat(C::new(3, 8), e)));
+    let d = parse_expr("1 / x", e)
+        .integrate_definite("x", C::new(1, 1), C::new(2, 1), e)
+        .unwrap();
+    assert!((d.eval_internal(&vec![], &vec![]) - 2f64.ln()).abs() < 1e-12);
+
+    // 初等関数で書けないもの, arctanが要るものは積分できないと返す
+    for s in ["exp(x^2)", "sin(x) / x", "1 / (x^2 + 1)", "x^x"].iter() {
+        let f = parse_expr(s, e);
+        assert_eq!(
+            f.integrate("x", e),
+            Err(NotIntegrable((*f).clone())),
+            "{}",
+            s
+        );
+    }
+}
+
+#[test]
+fn integrate_leaves_env_clean() {
+    let e = &Environment::new();
+    let f = parse_expr("x * cos(x^2) + exp(sin(x)) * cos(x)", e);
+    let n = e.borrow().vars.len();
+    assert!(f.integrate("x", e).is_ok());
+    // 置換で使った変数は残らない
+    assert_eq!(e.borrow().vars.len(), n);
+    assert!(e.borrow().vars.values().all(|s| !s.starts_with("_u")));
+
+    // 利用者の変数 _u0 とぶつからない
+    let u = Expr::new_var(String::from("_u0"), e);
+    let f = Expr::new_binop(Bop::Mul, u, parse_expr("x * cos(x^2)", e), e);
+    let g = f.integrate("x", e).unwrap();
+    let (vars, vals) = (g.free_vars().into_iter().collect(), vec![0.3, 1.7]);
+    let d = g.diff("x", e).reduce(e);
+    assert!((d.eval_internal(&vars, &vals) - f.eval_internal(&vars, &vals)).abs() < 1e-12);
+
+    // 微分して戻らない結果は返さない
+    let f = parse_expr("x * cos(x^2)", e);
+    assert!(differentiates_back(
+        &parse_expr("sin(x^2) / 2", e),
+        &f,
+        "x",
+        e
+    ));
+    assert!(!differentiates_back(&parse_expr("sin(x^2)", e), &f, "x", e));
+}
+
+#[test]
+fn integrate_definite_poles_and_branches() {
+    let e = &Environment::new();
+    let definite = |s: &str, a: i64, b: i64| {
+        parse_expr(s, e).integrate_definite("x", C::from(a), C::from(b), e)
+    };
+    // 区間に極がある
+    for s in ["1 / x^2", "1 / x", "x / (x^2 - 1/4)", "tan(x + 1)"].iter() {
+        let f = parse_expr(s, e);
+        assert_eq!(
+            definite(s, -1, 1),
+            Err(NotIntegrable((*f).clone())),
+            "{}",
+            s
+        );
+    }
+    // 負の側だけなら log(-x) を使う
+    let d = definite("1 / x", -2, -1).unwrap();
+    let v = d.eval_internal(&vec![], &vec![]);
+    assert!((v + 2f64.ln()).abs() < 1e-12, "{:?}", d);
+    let d = definite("1 / (x^2 - 1)", 2, 3).unwrap();
+    let v = d.eval_internal(&vec![], &vec![]);
+    assert!((v - (1.5f64).ln() / 2.).abs() < 1e-12, "{:?}", d);
+    let d = definite("1 / (x^2 - 1)", -3, -2).unwrap();
+    let v = d.eval_internal(&vec![], &vec![]);
+    assert!((v - (1.5f64).ln() / 2.).abs() < 1e-12, "{:?}", d);
+    // 定義域の外
+    assert!(definite("(x + 1) ^ (1/2)", -3, 0).is_err());
+    assert!(definite("log(x)", -1, 1).is_err());
+}
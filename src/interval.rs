@@ -0,0 +1,713 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::parse_vars;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars_slots, sort_by_slots, Env, Expr, Rc, Var, C};
+#[cfg(test)]
+use super::gradcheck::Rng;
+#[cfg(test)]
+use super::parse::*;
+use super::scalar::Scalar;
+use std::f64::consts::{FRAC_PI_2, PI, TAU};
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+// 閉区間 [lo, hi]. 空集合は両端をNaNで表す
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+// 一つ上の浮動小数点数. 丸めの向きを指定できないので結果を1ulp外側に広げる
+fn up(x: f64) -> f64 {
+    if x.is_nan() || x == f64::INFINITY {
+        x
+    } else if x == 0. {
+        f64::from_bits(1)
+    } else if 0. < x {
+        f64::from_bits(x.to_bits() + 1)
+    } else {
+        f64::from_bits(x.to_bits() - 1)
+    }
+}
+
+fn down(x: f64) -> f64 {
+    -up(-x)
+}
+
+// 初等関数は正しく丸められるとは限らないので2ulp広げる
+fn lib_lo(x: f64) -> f64 {
+    down(down(x))
+}
+
+fn lib_hi(x: f64) -> f64 {
+    up(up(x))
+}
+
+// 近似値 r と誤差 err (真値 = r + err) から向きを決めて丸める.
+// 誤差なく計算できたときは広げないので整数の演算は点のまま残る
+fn round_dn(r: f64, err: f64) -> f64 {
+    if !r.is_finite() || err < 0. {
+        down(r)
+    } else {
+        r
+    }
+}
+
+fn round_up(r: f64, err: f64) -> f64 {
+    if !r.is_finite() || 0. < err {
+        up(r)
+    } else {
+        r
+    }
+}
+
+// これより小さい積や商は誤差項がアンダーフローするので常に広げる
+const TINY: f64 = 1e-290;
+
+// TwoSum の誤差項
+fn add_err(a: f64, b: f64, s: f64) -> f64 {
+    let bb = s - a;
+    (a - (s - bb)) + (b - bb)
+}
+
+fn add_dn(a: f64, b: f64) -> f64 {
+    let s = a + b;
+    round_dn(s, add_err(a, b, s))
+}
+
+fn add_up(a: f64, b: f64) -> f64 {
+    let s = a + b;
+    round_up(s, add_err(a, b, s))
+}
+
+// 0 * inf は 0 とする. 積の誤差は fma で正確に求まる
+fn mul_dn(a: f64, b: f64) -> f64 {
+    if a == 0. || b == 0. {
+        return 0.;
+    }
+    let p = a * b;
+    if p.abs() < TINY {
+        return down(p);
+    }
+    round_dn(p, a.mul_add(b, -p))
+}
+
+fn mul_up(a: f64, b: f64) -> f64 {
+    if a == 0. || b == 0. {
+        return 0.;
+    }
+    let p = a * b;
+    if p.abs() < TINY {
+        return up(p);
+    }
+    round_up(p, a.mul_add(b, -p))
+}
+
+// 剰余 a - q b の符号から商の誤差の向きが分かる
+fn div_err(a: f64, b: f64, q: f64) -> f64 {
+    let r = -q.mul_add(b, -a);
+    if b < 0. {
+        -r
+    } else {
+        r
+    }
+}
+
+fn div_dn(a: f64, b: f64) -> f64 {
+    let q = a / b;
+    if a == 0. {
+        return 0.;
+    }
+    if q.abs() < TINY {
+        return down(q);
+    }
+    round_dn(q, div_err(a, b, q))
+}
+
+fn div_up(a: f64, b: f64) -> f64 {
+    let q = a / b;
+    if a == 0. {
+        return 0.;
+    }
+    if q.abs() < TINY {
+        return up(q);
+    }
+    round_up(q, div_err(a, b, q))
+}
+
+// [lo, hi] が offset + k period を含むか. 誤差の分だけ含む側に倒す
+fn hits(lo: f64, hi: f64, offset: f64, period: f64) -> bool {
+    let k_lo = ((lo - offset) / period - 1e-9).ceil();
+    let k_hi = ((hi - offset) / period + 1e-9).floor();
+    k_lo <= k_hi
+}
+
+impl Interval {
+    pub const EMPTY: Interval = Interval {
+        lo: f64::NAN,
+        hi: f64::NAN,
+    };
+    pub const ENTIRE: Interval = Interval {
+        lo: f64::NEG_INFINITY,
+        hi: f64::INFINITY,
+    };
+
+    pub fn new(lo: f64, hi: f64) -> Self {
+        assert!(lo <= hi, "invalid interval [{}, {}]", lo, hi);
+        Interval { lo, hi }
+    }
+
+    pub fn point(x: f64) -> Self {
+        Interval { lo: x, hi: x }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lo.is_nan()
+    }
+
+    pub fn contains(&self, x: f64) -> bool {
+        self.lo <= x && x <= self.hi
+    }
+
+    pub fn contains_zero(&self) -> bool {
+        self.contains(0.)
+    }
+
+    pub fn width(&self) -> f64 {
+        self.hi - self.lo
+    }
+
+    pub fn mid(&self) -> f64 {
+        if self.lo.is_infinite() || self.hi.is_infinite() {
+            if self.lo.is_infinite() && self.hi.is_infinite() {
+                0.
+            } else if self.lo.is_infinite() {
+                self.hi.min(0.) - 1.
+            } else {
+                self.lo.max(0.) + 1.
+            }
+        } else {
+            self.lo + (self.hi - self.lo) / 2.
+        }
+    }
+
+    // |x| の最大値
+    pub fn mag(&self) -> f64 {
+        self.lo.abs().max(self.hi.abs())
+    }
+
+    pub fn hull(&self, other: &Interval) -> Interval {
+        if self.is_empty() {
+            *other
+        } else if other.is_empty() {
+            *self
+        } else {
+            Interval {
+                lo: self.lo.min(other.lo),
+                hi: self.hi.max(other.hi),
+            }
+        }
+    }
+
+    pub fn intersect(&self, other: &Interval) -> Interval {
+        let (lo, hi) = (self.lo.max(other.lo), self.hi.min(other.hi));
+        if self.is_empty() || other.is_empty() || hi < lo {
+            Interval::EMPTY
+        } else {
+            Interval { lo, hi }
+        }
+    }
+
+    pub fn split(&self) -> (Interval, Interval) {
+        let m = self.mid();
+        (
+            Interval { lo: self.lo, hi: m },
+            Interval { lo: m, hi: self.hi },
+        )
+    }
+
+    pub fn exp(self) -> Interval {
+        if self.is_empty() {
+            return self;
+        }
+        Interval {
+            lo: lib_lo(self.lo.exp()).max(0.),
+            hi: lib_hi(self.hi.exp()),
+        }
+    }
+
+    // 定義域外の部分は捨てる
+    pub fn log(self) -> Interval {
+        if self.is_empty() || self.hi < 0. {
+            return Interval::EMPTY;
+        }
+        Interval {
+            lo: if 0. < self.lo {
+                lib_lo(self.lo.ln())
+            } else {
+                f64::NEG_INFINITY
+            },
+            hi: lib_hi(self.hi.ln()),
+        }
+    }
+
+    // 極値をとる点 max_at + 2k pi, min_at + 2k pi を含むかで決める
+    fn periodic(self, f: fn(f64) -> f64, max_at: f64, min_at: f64) -> Interval {
+        if self.is_empty() {
+            return self;
+        }
+        if TAU <= self.width() || !self.width().is_finite() || 1e8 < self.mag() {
+            return Interval::new(-1., 1.);
+        }
+        let (a, b) = (f(self.lo), f(self.hi));
+        Interval {
+            lo: if hits(self.lo, self.hi, min_at, TAU) {
+                -1.
+            } else {
+                lib_lo(a.min(b)).max(-1.)
+            },
+            hi: if hits(self.lo, self.hi, max_at, TAU) {
+                1.
+            } else {
+                lib_hi(a.max(b)).min(1.)
+            },
+        }
+    }
+
+    pub fn sin(self) -> Interval {
+        self.periodic(f64::sin, FRAC_PI_2, -FRAC_PI_2)
+    }
+
+    pub fn cos(self) -> Interval {
+        self.periodic(f64::cos, 0., PI)
+    }
+
+    // 極 pi/2 + k pi を含めば全体
+    pub fn tan(self) -> Interval {
+        if self.is_empty() {
+            return self;
+        }
+        if PI <= self.width()
+            || !self.width().is_finite()
+            || 1e8 < self.mag()
+            || hits(self.lo, self.hi, FRAC_PI_2, PI)
+        {
+            return Interval::ENTIRE;
+        }
+        Interval {
+            lo: lib_lo(self.lo.tan()),
+            hi: lib_hi(self.hi.tan()),
+        }
+    }
+
+    pub fn powi(self, n: i32) -> Interval {
+        if self.is_empty() {
+            return self;
+        }
+        if n < 0 {
+            return Interval::point(1.) / self.powi(-n);
+        }
+        if n == 0 {
+            return Interval::point(1.);
+        }
+        // powiの誤差は次数に比例する
+        let k = n as f64 * f64::EPSILON;
+        let lo_of = |v: f64| down(v - v.abs() * k);
+        let hi_of = |v: f64| up(v + v.abs() * k);
+        let (a, b) = (self.lo.powi(n), self.hi.powi(n));
+        if n % 2 == 1 {
+            Interval {
+                lo: lo_of(a),
+                hi: hi_of(b),
+            }
+        } else if self.contains_zero() {
+            Interval {
+                lo: 0.,
+                hi: hi_of(a.max(b)),
+            }
+        } else {
+            Interval {
+                lo: lo_of(a.min(b)).max(0.),
+                hi: hi_of(a.max(b)),
+            }
+        }
+    }
+
+    // 指数が整数の点でなければ底は正の部分だけを見る
+    pub fn pow(self, other: Interval) -> Interval {
+        if other.lo == other.hi && other.lo.fract() == 0. && other.lo.abs() <= i32::MAX as f64 {
+            self.powi(other.lo as i32)
+        } else {
+            let base = self.intersect(&Interval::new(0., f64::INFINITY));
+            (other * base.log()).exp()
+        }
+    }
+}
+
+// 整数の定数は点のまま, それ以外は1ulp広げる
+impl Scalar for Interval {
+    fn from_rat(c: C) -> Self {
+        let v = *c.numer() as f64 / *c.denom() as f64;
+        if c.is_integer() && v.abs() < 2f64.powi(53) {
+            Interval::point(v)
+        } else {
+            Interval {
+                lo: down(v),
+                hi: up(v),
+            }
+        }
+    }
+    fn sin(&self) -> Self {
+        Interval::sin(*self)
+    }
+    fn cos(&self) -> Self {
+        Interval::cos(*self)
+    }
+    fn tan(&self) -> Self {
+        Interval::tan(*self)
+    }
+    fn ln(&self) -> Self {
+        self.log()
+    }
+    fn exp(&self) -> Self {
+        Interval::exp(*self)
+    }
+    fn pow(&self, other: &Self) -> Self {
+        Interval::pow(*self, *other)
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}, {}]", self.lo, self.hi)
+    }
+}
+
+impl Add for Interval {
+    type Output = Interval;
+    fn add(self, other: Interval) -> Interval {
+        Interval {
+            lo: add_dn(self.lo, other.lo),
+            hi: add_up(self.hi, other.hi),
+        }
+    }
+}
+
+impl Sub for Interval {
+    type Output = Interval;
+    fn sub(self, other: Interval) -> Interval {
+        Interval {
+            lo: add_dn(self.lo, -other.hi),
+            hi: add_up(self.hi, -other.lo),
+        }
+    }
+}
+
+impl Neg for Interval {
+    type Output = Interval;
+    fn neg(self) -> Interval {
+        Interval {
+            lo: -self.hi,
+            hi: -self.lo,
+        }
+    }
+}
+
+impl Mul for Interval {
+    type Output = Interval;
+    fn mul(self, other: Interval) -> Interval {
+        if self.is_empty() || other.is_empty() {
+            return Interval::EMPTY;
+        }
+        let ps = [
+            (self.lo, other.lo),
+            (self.lo, other.hi),
+            (self.hi, other.lo),
+            (self.hi, other.hi),
+        ];
+        Interval {
+            lo: ps
+                .iter()
+                .map(|(a, b)| mul_dn(*a, *b))
+                .fold(f64::INFINITY, f64::min),
+            hi: ps
+                .iter()
+                .map(|(a, b)| mul_up(*a, *b))
+                .fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+// 0を含む区間で割ると全体になる
+impl Div for Interval {
+    type Output = Interval;
+    fn div(self, other: Interval) -> Interval {
+        if self.is_empty() || other.is_empty() || (other.lo == 0. && other.hi == 0.) {
+            return Interval::EMPTY;
+        }
+        if other.contains_zero() {
+            return Interval::ENTIRE;
+        }
+        let qs = [
+            (self.lo, other.lo),
+            (self.lo, other.hi),
+            (self.hi, other.lo),
+            (self.hi, other.hi),
+        ];
+        Interval {
+            lo: qs
+                .iter()
+                .map(|(a, b)| div_dn(*a, *b))
+                .fold(f64::INFINITY, f64::min),
+            hi: qs
+                .iter()
+                .map(|(a, b)| div_up(*a, *b))
+                .fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+// 大域的最小化の結果. lower <= min f <= upper が保証される
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalMin {
+    pub lower: f64,
+    pub upper: f64,
+    // upperを与えた点. varsに書いた順
+    pub x: Vec<f64>,
+    pub boxes: usize,
+    pub converged: bool,
+}
+
+// 分割する箱の数の上限
+const MAX_BOXES: usize = 100_000;
+
+impl Expr {
+    // 各変数が区間を動くときの値域を含む区間. valsはvarsに書いた順
+    pub fn eval_interval(&self, vars: &str, vals: &[Interval], e: &Env) -> Interval {
+        let (parsed, slots) = parse_vars_slots(vars, e);
+        assert!(
+            parsed.len() == vals.len(),
+            "number of variables and values differ"
+        );
+        self.eval_interval_internal(&parsed, &sort_by_slots(&slots, vals))
+    }
+    pub fn eval_interval_internal(&self, vars: &[Var], vals: &[Interval]) -> Interval {
+        self.eval_generic_internal(vars, vals)
+    }
+
+    // 箱の上での各偏微分の絶対値の上界. Lipschitz定数に使える. varsに書いた順
+    pub fn lipschitz(self: &Rc<Expr>, vars: &str, bounds: &[Interval], env: &Env) -> Vec<f64> {
+        let (parsed, slots) = parse_vars_slots(vars, env);
+        assert!(
+            parsed.len() == bounds.len(),
+            "number of variables and bounds differ"
+        );
+        let first = vars.split_whitespace().next().unwrap();
+        let g = Deriv::new(self.clone(), env, first)
+            .grad_interval(&parsed, &sort_by_slots(&slots, bounds));
+        slots.iter().map(|s| g[*s].mag()).collect()
+    }
+
+    // 分枝限定法で箱の上の最小値を tol 以内に挟む
+    pub fn minimize_interval(
+        self: &Rc<Expr>,
+        vars: &str,
+        bounds: &[Interval],
+        tol: f64,
+        env: &Env,
+    ) -> GlobalMin {
+        let (parsed, slots) = parse_vars_slots(vars, env);
+        assert!(
+            parsed.len() == bounds.len(),
+            "number of variables and bounds differ"
+        );
+        // 箱は変数idの順で持つ
+        let bounds = &sort_by_slots(&slots, bounds)[..];
+        let first = vars.split_whitespace().next().unwrap();
+        let deriv = Deriv::new(self.clone(), env, first);
+        // 自然な拡張と平均値形式の共通部分を下界にする
+        let bound = |b: &[Interval]| -> (Interval, Vec<Interval>) {
+            let natural = self.eval_interval_internal(&parsed, b);
+            let grad = deriv.grad_interval(&parsed, b);
+            let m: Vec<Interval> = b.iter().map(|i| Interval::point(i.mid())).collect();
+            let mut mv = self.eval_interval_internal(&parsed, &m);
+            for ((g, i), c) in grad.iter().zip(b).zip(&m) {
+                mv = mv + *g * (*i - *c);
+            }
+            let both = natural.intersect(&mv);
+            (if both.is_empty() { natural } else { both }, grad)
+        };
+        let mid = |b: &[Interval]| -> Vec<f64> { b.iter().map(|i| i.mid()).collect() };
+        // 点での値の上界. 丸め誤差があっても min f <= upper になる
+        let at = |p: &[f64]| -> f64 {
+            let pts: Vec<Interval> = p.iter().map(|x| Interval::point(*x)).collect();
+            let v = self.eval_interval_internal(&parsed, &pts);
+            if v.is_empty() || v.hi.is_nan() {
+                f64::INFINITY
+            } else {
+                v.hi
+            }
+        };
+        let mut best_x = mid(bounds);
+        let mut upper = at(&best_x);
+        let (f0, _) = bound(bounds);
+        let mut boxes = vec![(f0.lo, bounds.to_vec())];
+        let mut count = 1;
+        loop {
+            boxes.retain(|(lo, _)| *lo <= upper);
+            let lower = boxes.iter().map(|(lo, _)| *lo).fold(upper, f64::min);
+            if upper - lower <= tol || boxes.is_empty() || MAX_BOXES <= count {
+                return GlobalMin {
+                    lower,
+                    upper,
+                    x: slots.iter().map(|s| best_x[*s]).collect(),
+                    boxes: count,
+                    converged: upper - lower <= tol,
+                };
+            }
+            let i = (0..boxes.len())
+                .min_by(|i, j| boxes[*i].0.partial_cmp(&boxes[*j].0).unwrap())
+                .unwrap();
+            let (_, b) = boxes.swap_remove(i);
+            // 最も広い辺で二分する
+            let k = (0..b.len())
+                .max_by(|i, j| b[*i].width().partial_cmp(&b[*j].width()).unwrap())
+                .unwrap();
+            let (l, r) = b[k].split();
+            for half in [l, r].iter() {
+                let mut c = b.clone();
+                c[k] = *half;
+                count += 1;
+                let (f, grad) = bound(&c);
+                if f.is_empty() {
+                    continue;
+                }
+                // 単調な方向は端に寄せて上界を探す
+                let x: Vec<f64> = c
+                    .iter()
+                    .zip(&grad)
+                    .map(|(i, g)| {
+                        if 0. < g.lo {
+                            i.lo
+                        } else if g.hi < 0. {
+                            i.hi
+                        } else {
+                            i.mid()
+                        }
+                    })
+                    .collect();
+                for p in [x, mid(&c)].iter() {
+                    let v = at(p);
+                    if v < upper {
+                        upper = v;
+                        best_x = p.clone();
+                    }
+                }
+                if f.lo <= upper {
+                    boxes.push((f.lo, c));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn interval_arithmetic() {
+    let a = Interval::point(0.1) + Interval::point(0.2);
+    assert!(a.contains(0.1 + 0.2) && a.lo < a.hi);
+    // 誤差のない演算では広げない
+    assert_eq!(
+        Interval::point(2.) - Interval::point(1.),
+        Interval::point(1.)
+    );
+    assert_eq!(
+        Interval::point(3.) / Interval::point(4.),
+        Interval::point(0.75)
+    );
+    let m = Interval::new(-2., 3.) * Interval::new(-1., 4.);
+    assert!(m.lo <= -8. && -8. - 1e-12 < m.lo && 12. <= m.hi && m.hi < 12. + 1e-12);
+    assert_eq!(
+        Interval::new(1., 2.) / Interval::new(-1., 1.),
+        Interval::ENTIRE
+    );
+    assert!((Interval::new(1., 2.) / Interval::point(0.)).is_empty());
+
+    // 周期関数は区間内の極値を拾う
+    assert_eq!(Interval::new(0., 4.).sin().hi, 1.);
+    assert!(Interval::new(0., 4.).sin().lo < 4f64.sin());
+    assert_eq!(Interval::new(-0.1, 0.1).cos().hi, 1.);
+    assert_eq!(Interval::new(3., 3.5).cos().lo, -1.);
+    let s = Interval::new(0.1, 0.2).sin();
+    assert!(s.contains(0.1f64.sin()) && s.contains(0.2f64.sin()) && s.width() < 0.1);
+    // 極と定義域
+    assert_eq!(Interval::new(1., 2.).tan(), Interval::ENTIRE);
+    assert!(Interval::new(-1., 1.).tan().contains(1f64.tan()));
+    let l = Interval::new(-1., 1.).log();
+    assert!(l.lo == f64::NEG_INFINITY && 0. <= l.hi && l.hi < 1e-15);
+    assert!(Interval::new(-2., -1.).log().is_empty());
+    let p = Interval::new(-2., 3.).powi(2);
+    assert!(p.lo == 0. && 9. <= p.hi);
+    assert!(Interval::new(-2., 3.).powi(3).contains(-8.));
+
+    // 任意の点での値が区間に入る
+    let e = &Environment::new();
+    let f = parse_expr(
+        "sin(x * y) + exp(x) / (y^2 + 1) - log(x^2 + 1) * tan(y / 4) + (x^2)^(1/3)",
+        e,
+    );
+    let bx = [Interval::new(-1.5, 2.), Interval::new(-3., 1.)];
+    let r = f.eval_interval("x y", &bx, e);
+    let mut rng = Rng::new(3);
+    for _ in 0..2000 {
+        let p = vec![rng.range(-1.5, 2.), rng.range(-3., 1.)];
+        let v = f.eval("x y", &p, e);
+        assert!(r.contains(v), "{} {}", r, v);
+    }
+    // 依存性の問題で広くはなるが真の値域 [-1, 0] を含む
+    let r = parse_expr("x^2 - 2 * x", e).eval_interval("x", &[Interval::new(0., 2.)], e);
+    assert!(r.contains(-1.) && r.contains(0.));
+}
+
+#[test]
+fn interval_derivatives_and_global_min() {
+    let e = &Environment::new();
+    let f = parse_expr("x^2 * y + sin(y)", e);
+    let bx = [Interval::new(-1., 2.), Interval::new(0., 1.)];
+    let parsed = parse_vars("x y", e);
+    let g = Deriv::new(f.clone(), e, "x").grad_interval(&parsed, &bx);
+    // df/dx = 2xy, df/dy = x^2 + cos(y)
+    assert!(g[0].contains(-2.) && g[0].contains(4.), "{:?}", g);
+    assert!(g[1].contains(1f64.cos()) && g[1].contains(5.));
+    let l = f.lipschitz("x y", &bx, e);
+    assert!(4. <= l[0] && 5. <= l[1]);
+
+    // six-hump camel. 最小値は -1.0316284...
+    let camel = parse_expr(
+        "(4 - 21 / 10 * x^2 + x^4 / 3) * x^2 + x * y + (4 * y^2 - 4) * y^2",
+        e,
+    );
+    let bx = [Interval::new(-3., 3.), Interval::new(-2., 2.)];
+    let r = camel.minimize_interval("x y", &bx, 1e-6, e);
+    assert!(r.converged, "{:?}", r);
+    let exact = -1.031_628_453_489_877;
+    assert!(r.lower <= exact && exact <= r.upper && r.upper - r.lower <= 1e-6);
+    assert!((r.x[0].abs() - 0.089_842).abs() < 1e-3 && (r.x[1].abs() - 0.712_656).abs() < 1e-3);
+    // upperはr.xでの値の区間の上端
+    let at: Vec<Interval> = r.x.iter().map(|x| Interval::point(*x)).collect();
+    assert_eq!(r.upper, camel.eval_interval("x y", &at, e).hi);
+    assert!(camel.eval("x y", &r.x, e) <= r.upper);
+
+    // 変数を作った順と書いた順が違っても書いた順で対応させる
+    let f = parse_expr("y * y + x", e);
+    let pts = [Interval::point(1.), Interval::point(10.)];
+    assert_eq!(f.eval_interval("x y", &pts, e), Interval::point(101.));
+    let bx = [Interval::new(1., 2.), Interval::new(3., 4.)];
+    let l = f.lipschitz("x y", &bx, e);
+    assert!(l[0] == 1. && 8. <= l[1] && l[1] < 8. + 1e-12, "{:?}", l);
+    let r = f.minimize_interval("x y", &bx, 1e-9, e);
+    assert!(r.converged && r.lower <= 10. && 10. <= r.upper, "{:?}", r);
+    assert!((r.x[0] - 1.).abs() < 1e-6 && (r.x[1] - 3.).abs() < 1e-6);
+}
@@ -1,22 +1,42 @@
-mod diff;
-mod expr;
-mod parse;
-mod parser_combinator;
+pub mod bigfloat;
+pub mod complex;
+pub mod diff;
+pub mod dual;
+pub mod equation;
+pub mod expand;
+pub mod expr;
+pub mod fit;
+pub mod gradcheck;
+pub mod integrate;
+pub mod interval;
+pub mod limit;
+pub mod ode;
+pub mod optimize;
+pub mod parse;
+pub mod parser_combinator;
+pub mod poly;
+pub mod quad;
+pub mod ratfunc;
+pub mod scalar;
+pub mod series;
+pub mod solve;
+pub mod sparse;
+pub mod subs;
+pub mod taylor;
+pub mod uncertainty;
 
-use chrono::Duration;
-use diff::*;
-use expr::*;
-use parse::*;
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::diff::*;
+    use super::expr::*;
+    use super::parse::*;
 
     #[test]
     fn large_example_chain() {
         let e = &Environment::new();
         let size = 15;
         let sec_max = 5;
-        let v = match variables().parse(&"x", e) {
+        let v = match variables().parse("x", e) {
             Ok((_, _, mut vars)) => {
                 assert!(vars.len() == 1);
                 vars.pop().unwrap()
@@ -64,6 +84,7 @@ mod tests {
             d.forward_eval(v, &var, &vec![x], e);
             cnt += 1;
         }
+        println!("derivative graph optimize: {} times", cnt);
     }
 
     #[test]
@@ -72,7 +93,7 @@ mod tests {
         let e = &Environment::new();
         // 大きすぎるとstack overflow
         let size = 100;
-        let v = match variables().parse(&"x", e) {
+        let v = match variables().parse("x", e) {
             Ok((_, _, mut vars)) => {
                 assert!(vars.len() == 1);
                 vars.pop().unwrap()
@@ -286,27 +307,3 @@ mod tests {
     //     }
     // }
 }
-fn read_line() -> String {
-    use std::io::Read;
-    let stdin = std::io::stdin();
-    let stdin = stdin.lock();
-    let token: String = stdin
-        .bytes()
-        .map(|c| c.expect("failed to read char") as char)
-        .take_while(|c| !c.is_whitespace())
-        .collect();
-    token.parse().ok().expect("failed to parse token")
-}
-
-fn read<T: std::str::FromStr>() -> T {
-    use std::io::Read;
-    let stdin = std::io::stdin();
-    let stdin = stdin.lock();
-    let token: String = stdin
-        .bytes()
-        .map(|c| c.expect("failed to read char") as char)
-        .skip_while(|c| c.is_whitespace())
-        .take_while(|c| !c.is_whitespace())
-        .collect();
-    token.parse().ok().expect("failed to parse token")
-}
@@ -0,0 +1,520 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Bop, Env, Expr, HashMap, Rc, Uop, Var, C};
+#[cfg(test)]
+use super::parse::*;
+use std::cmp::Ordering::{self, Equal, Greater, Less};
+
+// 近づく向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Left,
+    Right,
+    Both,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Limit {
+    Finite(Rc<Expr>),
+    PosInf,
+    NegInf,
+}
+
+// 極限が存在しないか, 求められなかった式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoLimit(pub Expr);
+
+use Limit::{Finite, NegInf, PosInf};
+
+// L'Hôpitalの定理を重ねて適用する回数の上限
+const MAX_DEPTH: usize = 6;
+// 級数展開で先頭の項を探す次数
+const SERIES_ORDER: usize = 8;
+
+fn inf(s: Ordering) -> Option<Limit> {
+    match s {
+        Greater => Some(PosInf),
+        Less => Some(NegInf),
+        Equal => None,
+    }
+}
+
+fn prod(s: Ordering, t: Ordering) -> Ordering {
+    if s == Equal || t == Equal {
+        Equal
+    } else if s == t {
+        Greater
+    } else {
+        Less
+    }
+}
+
+// 無限大の符号を反転する
+fn flip(l: Limit) -> Limit {
+    match l {
+        PosInf => NegInf,
+        NegInf => PosInf,
+        f => f,
+    }
+}
+
+fn same(a: &Limit, b: &Limit, env: &Env) -> bool {
+    match (a, b) {
+        (Finite(x), Finite(y)) => {
+            let d = Expr::new_binop(Bop::Sub, x.clone(), y.clone(), env).reduce(env);
+            d.is_zero() || (d.free_vars().is_empty() && d.eval_internal(&vec![], &vec![]) == 0.)
+        }
+        _ => a == b,
+    }
+}
+
+// 片側極限. left なら point の左から近づく
+struct Lim<'a> {
+    v: Var,
+    name: String,
+    point: Rc<Expr>,
+    left: bool,
+    env: &'a Env,
+}
+
+impl<'a> Lim<'a> {
+    fn bin(&self, op: Bop, a: &Rc<Expr>, b: &Rc<Expr>) -> Rc<Expr> {
+        Expr::new_binop(op, a.clone(), b.clone(), self.env).reduce(self.env)
+    }
+
+    fn zero(&self) -> Rc<Expr> {
+        Expr::new_num(0, self.env)
+    }
+
+    fn is_zero(&self, c: &Rc<Expr>) -> bool {
+        c.is_zero() || (c.free_vars().is_empty() && c.eval_internal(&vec![], &vec![]) == 0.)
+    }
+
+    fn sign(&self, c: &Rc<Expr>) -> Option<Ordering> {
+        if c.is_zero() {
+            Some(Equal)
+        } else if c.free_vars().is_empty() {
+            c.eval_internal(&vec![], &vec![]).partial_cmp(&0.)
+        } else if c.is_positive(self.env) {
+            Some(Greater)
+        } else if Expr::new_unop(Uop::Neg, c.clone(), self.env)
+            .reduce(self.env)
+            .is_positive(self.env)
+        {
+            Some(Less)
+        } else {
+            None
+        }
+    }
+
+    fn lsign(&self, l: &Limit) -> Option<Ordering> {
+        match l {
+            Finite(c) => self.sign(c),
+            PosInf => Some(Greater),
+            NegInf => Some(Less),
+        }
+    }
+
+    // (v - point)^k の符号
+    fn side(&self, k: usize, s: Ordering) -> Ordering {
+        if self.left && k % 2 == 1 {
+            s.reverse()
+        } else {
+            s
+        }
+    }
+
+    fn value(&self, e: &Rc<Expr>) -> Rc<Expr> {
+        let mut map = HashMap::new();
+        map.insert(self.v, self.point.clone());
+        e.subs_vars(&map, true, self.env)
+    }
+
+    // point で割り算やlogが特異にならない. このときTaylor係数を安全に求められる
+    fn regular(&self, e: &Rc<Expr>) -> bool {
+        match &**e {
+            Expr::Num(_) | Expr::Var(_) => true,
+            Expr::UnOp { op: Uop::Log, exp } => {
+                self.regular(exp) && !self.is_zero(&self.value(exp))
+            }
+            Expr::UnOp { exp, .. } => self.regular(exp),
+            Expr::BinOp {
+                op: Bop::Pow,
+                exp1,
+                exp2,
+            } => match **exp2 {
+                Expr::Num(n) if n.is_integer() && C::from(0) <= n => self.regular(exp1),
+                _ => self.regular(exp1) && self.regular(exp2) && !self.is_zero(&self.value(exp1)),
+            },
+            Expr::BinOp {
+                op: Bop::Div,
+                exp1,
+                exp2,
+            } => self.regular(exp1) && self.regular(exp2) && !self.is_zero(&self.value(exp2)),
+            Expr::BinOp { exp1, exp2, .. } => self.regular(exp1) && self.regular(exp2),
+        }
+    }
+
+    // 割り算を外に括り出して (分子, 分母) にする. 0 で割る式は作らない
+    fn fraction(&self, e: &Rc<Expr>) -> (Rc<Expr>, Rc<Expr>) {
+        let env = self.env;
+        let one = || Expr::new_num(1, env);
+        match &**e {
+            Expr::UnOp { op: Uop::Neg, exp } => {
+                let (n, d) = self.fraction(exp);
+                (Expr::new_unop(Uop::Neg, n, env).reduce(env), d)
+            }
+            Expr::BinOp { op, exp1, exp2 } => {
+                let (n1, d1) = self.fraction(exp1);
+                let (n2, d2) = self.fraction(exp2);
+                match op {
+                    Bop::Add | Bop::Sub => {
+                        let n = self.bin(
+                            *op,
+                            &self.bin(Bop::Mul, &n1, &d2),
+                            &self.bin(Bop::Mul, &n2, &d1),
+                        );
+                        (n, self.bin(Bop::Mul, &d1, &d2))
+                    }
+                    Bop::Mul => (self.bin(Bop::Mul, &n1, &n2), self.bin(Bop::Mul, &d1, &d2)),
+                    Bop::Div => (self.bin(Bop::Mul, &n1, &d2), self.bin(Bop::Mul, &d1, &n2)),
+                    Bop::Pow => match **exp2 {
+                        Expr::Num(k) if k.is_integer() => {
+                            let (n, d) = if k < C::from(0) { (d1, n1) } else { (n1, d1) };
+                            let k = Expr::new_num(k.numer().abs(), env);
+                            (self.bin(Bop::Pow, &n, &k), self.bin(Bop::Pow, &d, &k))
+                        }
+                        _ => (e.clone(), one()),
+                    },
+                }
+            }
+            _ => (e.clone(), one()),
+        }
+    }
+
+    // 0 に近づく式 e の point の近くでの符号. 0 でない最初の微分係数から決める
+    fn side_sign(&self, e: &Rc<Expr>, depth: usize) -> Option<Ordering> {
+        let mut d = e.clone();
+        for k in 1..=SERIES_ORDER {
+            d = d.diff(&self.name, self.env).reduce(self.env);
+            match self.lsign(&self.lim(&d, depth + 1)?)? {
+                Equal => continue,
+                s => return Some(self.side(k, s)),
+            }
+        }
+        None
+    }
+
+    fn lim(&self, e: &Rc<Expr>, depth: usize) -> Option<Limit> {
+        if MAX_DEPTH < depth {
+            return None;
+        }
+        let env = self.env;
+        match &**e {
+            Expr::Num(_) => Some(Finite(e.clone())),
+            Expr::Var(v) if *v == self.v => Some(Finite(self.point.clone())),
+            Expr::Var(_) => Some(Finite(e.clone())),
+            Expr::UnOp { op, exp } => match (op, self.lim(exp, depth)?) {
+                (Uop::Neg, l @ PosInf) | (Uop::Neg, l @ NegInf) => Some(flip(l)),
+                (Uop::Exp, PosInf) => Some(PosInf),
+                (Uop::Exp, NegInf) => Some(Finite(self.zero())),
+                // 実数のlogなので引数は正の側から0に近づくとみなす
+                (Uop::Log, Finite(c)) if self.is_zero(&c) => Some(NegInf),
+                (Uop::Log, PosInf) => Some(PosInf),
+                (_, Finite(c)) => Some(Finite(Expr::new_unop(*op, c, env).reduce(env))),
+                // 三角関数は無限遠で振動する
+                _ => None,
+            },
+            Expr::BinOp { op, exp1, exp2 } => match op {
+                Bop::Add | Bop::Sub => self.add(e, *op, exp1, exp2, depth),
+                Bop::Mul => self.mul(e, exp1, exp2, depth),
+                Bop::Div => self.div(exp1, exp2, depth),
+                Bop::Pow => self.pow(exp1, exp2, depth),
+            },
+        }
+    }
+
+    fn add(
+        &self,
+        e: &Rc<Expr>,
+        op: Bop,
+        a: &Rc<Expr>,
+        b: &Rc<Expr>,
+        depth: usize,
+    ) -> Option<Limit> {
+        let la = self.lim(a, depth)?;
+        let lb = self.lim(b, depth)?;
+        let lb = if op == Bop::Sub { flip(lb) } else { lb };
+        match (la, lb) {
+            // 有限値は flip で変わらない
+            (Finite(x), Finite(y)) => Some(Finite(self.bin(op, &x, &y))),
+            (Finite(_), l) | (l, Finite(_)) => Some(l),
+            (l1, l2) if l1 == l2 => Some(l1),
+            // ∞ - ∞ は通分して 0 / 0 などにする
+            _ => {
+                let (n, d) = self.fraction(e);
+                if d.is_one() {
+                    None
+                } else {
+                    self.div(&n, &d, depth + 1)
+                }
+            }
+        }
+    }
+
+    fn mul(&self, e: &Rc<Expr>, a: &Rc<Expr>, b: &Rc<Expr>, depth: usize) -> Option<Limit> {
+        let env = self.env;
+        let (la, lb) = (self.lim(a, depth)?, self.lim(b, depth)?);
+        if let (Finite(x), Finite(y)) = (&la, &lb) {
+            return Some(Finite(self.bin(Bop::Mul, x, y)));
+        }
+        match (self.lsign(&la)?, self.lsign(&lb)?) {
+            // 0 * ∞ は割り算に直す. 通分できればそれを, できなければ逆数で割る形を試す
+            (Equal, _) | (_, Equal) => {
+                let (n, d) = self.fraction(e);
+                if !d.is_one() {
+                    return self.div(&n, &d, depth + 1);
+                }
+                let (small, big) = if let Finite(_) = la { (a, b) } else { (b, a) };
+                let one = Expr::new_num(1, env);
+                let r = Expr::new_binop(Bop::Div, one.clone(), small.clone(), env);
+                self.div(big, &r, depth + 1).or_else(|| {
+                    let r = Expr::new_binop(Bop::Div, one, big.clone(), env);
+                    self.div(small, &r, depth + 1)
+                })
+            }
+            (s, t) => inf(prod(s, t)),
+        }
+    }
+
+    fn div(&self, a: &Rc<Expr>, b: &Rc<Expr>, depth: usize) -> Option<Limit> {
+        let (la, lb) = (self.lim(a, depth)?, self.lim(b, depth)?);
+        match (&la, &lb) {
+            (Finite(x), Finite(y)) if !self.is_zero(y) => Some(Finite(self.bin(Bop::Div, x, y))),
+            (Finite(x), Finite(_)) if !self.is_zero(x) => {
+                inf(prod(self.sign(x)?, self.side_sign(b, depth)?))
+            }
+            // 0 / 0
+            (Finite(_), Finite(_)) => self.series(a, b).or_else(|| self.lhopital(a, b, depth)),
+            (Finite(_), _) => Some(Finite(self.zero())),
+            (_, Finite(y)) => {
+                let t = if self.is_zero(y) {
+                    self.side_sign(b, depth)?
+                } else {
+                    self.sign(y)?
+                };
+                inf(prod(self.lsign(&la)?, t))
+            }
+            // ∞ / ∞
+            _ => self.lhopital(a, b, depth),
+        }
+    }
+
+    fn pow(&self, a: &Rc<Expr>, b: &Rc<Expr>, depth: usize) -> Option<Limit> {
+        let env = self.env;
+        if let Expr::Num(n) = **b {
+            return match self.lim(a, depth)? {
+                // 0 の負冪は 1 / a^(-n) として向きを見る
+                Finite(c) if self.is_zero(&c) && n < C::from(0) => {
+                    let p = Expr::new_num_from_rat(-n, env);
+                    let p = Expr::new_binop(Bop::Pow, a.clone(), p, env);
+                    self.div(&Expr::new_num(1, env), &p, depth)
+                }
+                Finite(c) => Some(Finite(self.bin(Bop::Pow, &c, b))),
+                _ if n == C::from(0) => Some(Finite(Expr::new_num(1, env))),
+                _ if n < C::from(0) => Some(Finite(self.zero())),
+                PosInf => Some(PosInf),
+                NegInf if n.is_integer() && *n.numer() % 2 == 0 => Some(PosInf),
+                NegInf if n.is_integer() => Some(NegInf),
+                NegInf => None,
+            };
+        }
+        match (self.lim(a, depth)?, self.lim(b, depth)?) {
+            (Finite(x), Finite(y)) if !self.is_zero(&x) => Some(Finite(self.bin(Bop::Pow, &x, &y))),
+            // 0^0, 1^∞, ∞^0 などは exp(b log a) で求める
+            _ => {
+                let l = Expr::new_unop(Uop::Log, a.clone(), env);
+                let m = Expr::new_binop(Bop::Mul, b.clone(), l, env);
+                self.lim(&Expr::new_unop(Uop::Exp, m, env), depth)
+            }
+        }
+    }
+
+    // 分子と分母の級数の先頭の項を比べる
+    fn series(&self, a: &Rc<Expr>, b: &Rc<Expr>) -> Option<Limit> {
+        if !self.regular(a) || !self.regular(b) {
+            return None;
+        }
+        let ca = a.taylor_coeffs(&self.name, self.point.clone(), SERIES_ORDER, self.env);
+        let cb = b.taylor_coeffs(&self.name, self.point.clone(), SERIES_ORDER, self.env);
+        let (ca, cb) = (ca.ok()?, cb.ok()?);
+        let p = ca.iter().position(|c| !self.is_zero(c))?;
+        let q = cb.iter().position(|c| !self.is_zero(c))?;
+        let r = self.bin(Bop::Div, &ca[p], &cb[q]);
+        match p.cmp(&q) {
+            Greater => Some(Finite(self.zero())),
+            Equal => Some(Finite(r)),
+            Less => inf(self.side(q - p, self.sign(&r)?)),
+        }
+    }
+
+    fn lhopital(&self, a: &Rc<Expr>, b: &Rc<Expr>, depth: usize) -> Option<Limit> {
+        let env = self.env;
+        let da = a.diff(&self.name, env).reduce(env);
+        let db = b.diff(&self.name, env).reduce(env);
+        if db.is_zero() {
+            return None;
+        }
+        let q = Expr::new_binop(Bop::Div, da, db, env).together(env);
+        self.lim(&q, depth + 1)
+    }
+}
+
+impl Expr {
+    // v → point での極限. 不定形は級数展開とL'Hôpitalの定理で解く
+    pub fn limit(
+        self: &Rc<Expr>,
+        v: &str,
+        point: Rc<Expr>,
+        dir: Direction,
+        env: &Env,
+    ) -> Result<Limit, NoLimit> {
+        let var = match env.borrow().search_var(&String::from(v)) {
+            Some(var) => var,
+            None => return Err(NoLimit((**self).clone())),
+        };
+        let point = point.reduce(env);
+        let side = |left| {
+            let l = Lim {
+                v: var,
+                name: String::from(v),
+                point: point.clone(),
+                left,
+                env,
+            };
+            l.lim(self, 0)
+        };
+        let res = match dir {
+            Direction::Right => side(false),
+            Direction::Left => side(true),
+            Direction::Both => match (side(false), side(true)) {
+                (Some(r), Some(l)) if same(&r, &l, env) => Some(r),
+                _ => None,
+            },
+        };
+        res.ok_or_else(|| NoLimit((**self).clone()))
+    }
+
+    // 除去可能な特異点でも値を返す. 素朴な評価が有限でなければ, 変数を一つずつ極限で近づける
+    pub fn eval_removable(self: &Rc<Expr>, vars: &str, vals: &Vec<f64>, env: &Env) -> f64 {
+        let vs = parse_vars(vars, env);
+        let y = self.eval_internal(&vs, vals);
+        if y.is_finite() {
+            return y;
+        }
+        let rats: Option<Vec<Rc<Expr>>> = vals
+            .iter()
+            .map(|x| C::approximate_float(*x).map(|c| Expr::new_num_from_rat(c, env)))
+            .collect();
+        let rats = match rats {
+            Some(r) => r,
+            None => return y,
+        };
+        for (i, v) in vs.iter().enumerate() {
+            let map: HashMap<Var, Rc<Expr>> = vs
+                .iter()
+                .zip(&rats)
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, (w, r))| (*w, r.clone()))
+                .collect();
+            let f = self.subs_vars(&map, false, env);
+            let name = env.borrow().vars[v].clone();
+            match f.limit(&name, rats[i].clone(), Direction::Both, env) {
+                Ok(Finite(c)) => return c.eval_internal(&vec![], &vec![]),
+                Ok(PosInf) => return f64::INFINITY,
+                Ok(NegInf) => return f64::NEG_INFINITY,
+                Err(_) => {}
+            }
+        }
+        y
+    }
+}
+
+#[test]
+fn limits() {
+    let e = &Environment::new();
+    let zero = Expr::new_num(0, e);
+    let at = |s: &str, p: &Rc<Expr>, d: Direction| parse_expr(s, e).limit("x", p.clone(), d, e);
+    let finite = |s: &str, p: &Rc<Expr>, d: Direction| match at(s, p, d) {
+        Ok(Finite(c)) => c.eval_internal(&vec![], &vec![]),
+        r => panic!("{}: {:?}", s, r),
+    };
+    let cases = [
+        ("sin(x) / x", 1.),
+        ("(1 - cos(x)) / x^2", 0.5),
+        ("(exp(x) - 1 - x) / x^2", 0.5),
+        ("tan(x) / sin(x)", 1.),
+        ("log(1 + x) / x", 1.),
+        ("x * log(x)", 0.),
+        ("x ^ x", 1.),
+        ("(1 + x) ^ (1 / x)", 1f64.exp()),
+        ("(sin(x) - x) / (x * log(1 + x^2))", -1. / 6.),
+    ];
+    for (s, v) in cases.iter() {
+        let got = finite(s, &zero, Direction::Right);
+        assert!((got - v).abs() < 1e-12, "{}: {} {}", s, got, v);
+    }
+    let one = Expr::new_num(1, e);
+    assert_eq!(finite("(x^2 - 1) / (x - 1)", &one, Direction::Both), 2.);
+    assert_eq!(finite("log(x) / (x - 1)", &one, Direction::Both), 1.);
+
+    // 無限大と片側極限
+    assert_eq!(at("1 / x", &zero, Direction::Right), Ok(PosInf));
+    assert_eq!(at("1 / x", &zero, Direction::Left), Ok(NegInf));
+    assert!(at("1 / x", &zero, Direction::Both).is_err());
+    assert_eq!(at("1 / x^2", &zero, Direction::Both), Ok(PosInf));
+    assert_eq!(at("(x - 1) / x^3", &zero, Direction::Left), Ok(PosInf));
+    assert_eq!(at("log(x) / x", &zero, Direction::Right), Ok(NegInf));
+    assert_eq!(
+        at("exp(1 / x)", &zero, Direction::Left),
+        Ok(Finite(zero.clone()))
+    );
+    assert!(at("sin(1 / x)", &zero, Direction::Right).is_err());
+
+    // 他の変数はそのまま残る
+    let y = parse_expr("y", e);
+    assert_eq!(at("sin(y * x) / x", &zero, Direction::Both), Ok(Finite(y)));
+
+    // 知らない変数は環境に追加しない
+    let f = parse_expr("y + 1", e);
+    assert_eq!(
+        f.limit("z", zero.clone(), Direction::Both, e),
+        Err(NoLimit((*f).clone()))
+    );
+    assert!(e.borrow().search_var(&String::from("z")).is_none());
+}
+
+#[test]
+fn eval_removable_singularity() {
+    let e = &Environment::new();
+    let f = parse_expr("sin(x) / x", e);
+    assert_eq!(f.eval_removable("x", &vec![0.], e), 1.);
+    assert_eq!(
+        f.eval_removable("x", &vec![0.5], e),
+        f.eval("x", &vec![0.5], e)
+    );
+    // 素朴な式では 0 / 0 になる導関数
+    let d = f.diff("x", e).reduce(e);
+    assert!(d.eval("x", &vec![0.], e).is_nan());
+    assert_eq!(d.eval_removable("x", &vec![0.], e), 0.);
+
+    let g = parse_expr("(exp(x * y) - 1) / x", e);
+    let v = g.eval_removable("x y", &vec![0., 2.5], e);
+    assert!((v - 2.5).abs() < 1e-12, "{}", v);
+    let v = g
+        .diff("x", e)
+        .reduce(e)
+        .eval_removable("x y", &vec![0., 2.5], e);
+    assert!((v - 2.5 * 2.5 / 2.).abs() < 1e-12, "{}", v);
+}
@@ -0,0 +1,638 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Bop, Env, Expr, Rc, Var};
+#[cfg(test)]
+use super::parse::*;
+use super::solve::lin_solve;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    // Dormand-Prince 5(4)
+    Rk45,
+    // 可変刻みBDF2. 最初の一歩は後退Euler
+    Bdf,
+    // Rosenbrock 2(3). 線形陰的なので反復しない
+    Rosenbrock,
+}
+
+// 誤差は各成分を atol + rtol |y| で割った二乗平均で測る
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OdeOptions {
+    pub rtol: f64,
+    pub atol: f64,
+    pub max_steps: usize,
+    pub first_step: Option<f64>,
+}
+
+impl Default for OdeOptions {
+    fn default() -> Self {
+        OdeOptions {
+            rtol: 1e-6,
+            atol: 1e-9,
+            max_steps: 100_000,
+            first_step: None,
+        }
+    }
+}
+
+impl OdeOptions {
+    pub fn new() -> Self {
+        OdeOptions::default()
+    }
+
+    pub fn rtol(mut self, tol: f64) -> Self {
+        self.rtol = tol;
+        self
+    }
+
+    pub fn atol(mut self, tol: f64) -> Self {
+        self.atol = tol;
+        self
+    }
+
+    pub fn max_steps(mut self, n: usize) -> Self {
+        self.max_steps = n;
+        self
+    }
+
+    pub fn first_step(mut self, h: f64) -> Self {
+        self.first_step = Some(h);
+        self
+    }
+}
+
+// 受理した各ステップでの値. y[i] は状態を与えた順に並べる
+#[derive(Debug, Clone, PartialEq)]
+pub struct OdeSolution {
+    pub t: Vec<f64>,
+    pub y: Vec<Vec<f64>>,
+    // sensitivities[i][j][k] は t[i] での dy_j/dp_k. 求めないときは空
+    pub sensitivities: Vec<Vec<Vec<f64>>>,
+    pub steps: usize,
+    pub rejected: usize,
+    pub success: bool,
+}
+
+// Rosenbrock法の係数. ROS_D = 1 / (2 + sqrt(2))
+const ROS_D: f64 = 1. - std::f64::consts::FRAC_1_SQRT_2;
+const ROS_E32: f64 = 6. + std::f64::consts::SQRT_2;
+
+// Dormand-Prince
+const DP_C: [f64; 7] = [0., 0.2, 0.3, 0.8, 8. / 9., 1., 1.];
+const DP_A: [[f64; 6]; 7] = [
+    [0., 0., 0., 0., 0., 0.],
+    [0.2, 0., 0., 0., 0., 0.],
+    [3. / 40., 9. / 40., 0., 0., 0., 0.],
+    [44. / 45., -56. / 15., 32. / 9., 0., 0., 0.],
+    [
+        19372. / 6561.,
+        -25360. / 2187.,
+        64448. / 6561.,
+        -212. / 729.,
+        0.,
+        0.,
+    ],
+    [
+        9017. / 3168.,
+        -355. / 33.,
+        46732. / 5247.,
+        49. / 176.,
+        -5103. / 18656.,
+        0.,
+    ],
+    [
+        35. / 384.,
+        0.,
+        500. / 1113.,
+        125. / 192.,
+        -2187. / 6784.,
+        11. / 84.,
+    ],
+];
+// 5次と4次の重みの差
+const DP_E: [f64; 7] = [
+    71. / 57600.,
+    0.,
+    -71. / 16695.,
+    71. / 1920.,
+    -17253. / 339_200.,
+    22. / 525.,
+    -1. / 40.,
+];
+
+fn axpy(y: &[f64], h: f64, k: &[f64]) -> Vec<f64> {
+    y.iter().zip(k).map(|(a, b)| a + h * b).collect()
+}
+
+// dy/dt = f(t, y; p). 感度を求めるときは dy/dp も状態に加えた系を作る
+#[derive(Debug, Clone)]
+pub struct Ode {
+    rhs: Vec<Rc<Expr>>,
+    vars: Vec<Var>,
+    tslot: usize,
+    yslots: Vec<usize>,
+    pslots: Vec<usize>,
+    deriv: Deriv,
+    // 元の系の次元. 残りは感度
+    dim: usize,
+    sensitivity: bool,
+}
+
+impl Ode {
+    // states, params はそれぞれ与えた順に値を並べる
+    pub fn new(rhs: Vec<Rc<Expr>>, t: &str, states: &str, params: &str, env: &Env) -> Self {
+        Ode::build(rhs, t, states, params, false, env)
+    }
+
+    // s_jk = dy_j/dp_k は s' = (df/dy) s + df/dp, s(0) = 0 に従う
+    // s_jk は env に ∂y/∂p#0 のような名前の変数として残る. 既にある変数とはぶつからない
+    pub fn with_sensitivities(
+        rhs: Vec<Rc<Expr>>,
+        t: &str,
+        states: &str,
+        params: &str,
+        env: &Env,
+    ) -> Self {
+        Ode::build(rhs, t, states, params, true, env)
+    }
+
+    fn build(
+        mut rhs: Vec<Rc<Expr>>,
+        t: &str,
+        states: &str,
+        params: &str,
+        sensitivity: bool,
+        env: &Env,
+    ) -> Self {
+        let ext = |s: &str| -> Vec<Var> {
+            s.split_whitespace()
+                .map(|v| env.borrow_mut().extend_var(String::from(v)))
+                .collect()
+        };
+        let (tv, mut ys, ps) = (ext(t), ext(states), ext(params));
+        assert!(tv.len() == 1, "one time variable is needed");
+        let dim = rhs.len();
+        assert!(dim == ys.len(), "number of equations and states differ");
+        if sensitivity {
+            let ynames: Vec<&str> = states.split_whitespace().collect();
+            let pnames: Vec<&str> = params.split_whitespace().collect();
+            let s: Vec<Vec<Rc<Expr>>> = ynames
+                .iter()
+                .map(|y| {
+                    pnames
+                        .iter()
+                        .map(|p| {
+                            let v = env.borrow_mut().fresh_var(&format!("∂{}/∂{}#", y, p));
+                            env.borrow_mut().extend_expr(Expr::Var(v))
+                        })
+                        .collect()
+                })
+                .collect();
+            let mut extra = vec![];
+            for f in rhs.iter() {
+                let dfdy: Vec<Rc<Expr>> = ynames.iter().map(|y| f.diff(y, env)).collect();
+                for (k, p) in pnames.iter().enumerate() {
+                    let mut e = f.diff(p, env);
+                    for (d, row) in dfdy.iter().zip(&s) {
+                        let term = Expr::new_binop(Bop::Mul, d.clone(), row[k].clone(), env);
+                        e = Expr::new_binop(Bop::Add, e, term, env);
+                    }
+                    extra.push(e.reduce(env));
+                }
+            }
+            rhs.extend(extra);
+            for row in &s {
+                for e in row {
+                    if let Expr::Var(v) = **e {
+                        ys.push(v);
+                    }
+                }
+            }
+        }
+        let mut vars: Vec<Var> = tv.iter().chain(&ys).chain(&ps).cloned().collect();
+        vars.sort();
+        vars.dedup();
+        let slots = |vs: &[Var]| -> Vec<usize> {
+            vs.iter().map(|v| vars.binary_search(v).unwrap()).collect()
+        };
+        let (tslot, yslots, pslots) = (slots(&tv)[0], slots(&ys), slots(&ps));
+        let first = states.split_whitespace().next().unwrap();
+        Ode {
+            deriv: Deriv::new_multi(rhs.clone(), env, first),
+            rhs,
+            vars,
+            tslot,
+            yslots,
+            pslots,
+            dim,
+            sensitivity,
+        }
+    }
+
+    fn bind(&self, t: f64, y: &[f64], p: &[f64]) -> Vec<f64> {
+        let mut vals = vec![0.; self.vars.len()];
+        vals[self.tslot] = t;
+        for (s, v) in self.yslots.iter().zip(y) {
+            vals[*s] = *v;
+        }
+        for (s, v) in self.pslots.iter().zip(p) {
+            vals[*s] = *v;
+        }
+        vals
+    }
+
+    // 感度を含む全状態での右辺
+    pub fn rhs(&self, t: f64, y: &[f64], p: &[f64]) -> Vec<f64> {
+        let vals = self.bind(t, y, p);
+        self.rhs
+            .iter()
+            .map(|f| f.eval_internal(&self.vars, &vals))
+            .collect()
+    }
+
+    // (df/dy, df/dt)
+    pub fn jacobian(&self, t: f64, y: &[f64], p: &[f64]) -> (Vec<Vec<f64>>, Vec<f64>) {
+        let vals = self.bind(t, y, p);
+        let mut ft = vec![];
+        let jac = (0..self.rhs.len())
+            .map(|i| {
+                let mut seed = vec![0.; self.rhs.len()];
+                seed[i] = 1.;
+                let g = self.deriv.vjp_internal(&self.vars, &vals, &seed);
+                ft.push(g[self.tslot]);
+                self.yslots.iter().map(|s| g[*s]).collect()
+            })
+            .collect();
+        (jac, ft)
+    }
+
+    fn err_norm(&self, e: &[f64], y: &[f64], z: &[f64], opts: &OdeOptions) -> f64 {
+        let s: f64 = e
+            .iter()
+            .zip(y.iter().zip(z))
+            .map(|(e, (a, b))| (e / (opts.atol + opts.rtol * a.abs().max(b.abs()))).powi(2))
+            .sum();
+        (s / e.len() as f64).sqrt()
+    }
+
+    // I - c J
+    fn iteration_matrix(jac: &[Vec<f64>], c: f64) -> Vec<Vec<f64>> {
+        jac.iter()
+            .enumerate()
+            .map(|(i, row)| {
+                row.iter()
+                    .enumerate()
+                    .map(|(j, v)| if i == j { 1. - c * v } else { -c * v })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // 一歩進めた値と誤差推定. f0 は (t, y) での右辺
+    fn rk45_step(&self, t: f64, y: &[f64], f0: &[f64], h: f64, p: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        let mut ks = vec![f0.to_vec()];
+        for i in 1..7 {
+            let mut yi = y.to_vec();
+            for (k, a) in ks.iter().zip(&DP_A[i]) {
+                yi = axpy(&yi, h * a, k);
+            }
+            ks.push(self.rhs(t + DP_C[i] * h, &yi, p));
+        }
+        let mut z = y.to_vec();
+        for (k, a) in ks.iter().zip(&DP_A[6]) {
+            z = axpy(&z, h * a, k);
+        }
+        let mut err = vec![0.; y.len()];
+        for (k, e) in ks.iter().zip(&DP_E) {
+            err = axpy(&err, h * e, k);
+        }
+        (z, err)
+    }
+
+    // Shampine-Reichelt の2(3)次. 解は2次で, 3次の値との差を誤差とする
+    fn rosenbrock_step(
+        &self,
+        t: f64,
+        y: &[f64],
+        f0: &[f64],
+        h: f64,
+        p: &[f64],
+    ) -> Option<(Vec<f64>, Vec<f64>)> {
+        let (jac, ft) = self.jacobian(t, y, p);
+        let w = Ode::iteration_matrix(&jac, ROS_D * h);
+        let k1 = lin_solve(w.clone(), axpy(f0, ROS_D * h, &ft))?;
+        let f1 = self.rhs(t + h / 2., &axpy(y, h / 2., &k1), p);
+        let d1: Vec<f64> = f1.iter().zip(&k1).map(|(f, k)| f - k).collect();
+        let k2 = axpy(&lin_solve(w.clone(), d1)?, 1., &k1);
+        let z = axpy(y, h, &k2);
+        let f2 = self.rhs(t + h, &z, p);
+        let rhs3: Vec<f64> = (0..y.len())
+            .map(|i| f2[i] - ROS_E32 * (k2[i] - f1[i]) - 2. * (k1[i] - f0[i]) + ROS_D * h * ft[i])
+            .collect();
+        let k3 = lin_solve(w, rhs3)?;
+        let err = (0..y.len())
+            .map(|i| h / 6. * (k1[i] - 2. * k2[i] + k3[i]))
+            .collect();
+        Some((z, err))
+    }
+
+    // hist は受理した過去の (t, y) で古い順. 二つ揃えばBDF2にする
+    #[allow(clippy::too_many_arguments)]
+    fn bdf_step(
+        &self,
+        t: f64,
+        y: &[f64],
+        f0: &[f64],
+        h: f64,
+        p: &[f64],
+        hist: &[(f64, Vec<f64>)],
+        opts: &OdeOptions,
+    ) -> Option<(Vec<f64>, Vec<f64>)> {
+        // 予測子は過去の y だけを補外する. f0 を使うと硬い成分で誤差推定が膨らむ
+        let mut pts: Vec<(f64, &[f64])> = hist.iter().map(|(s, v)| (*s, &v[..])).collect();
+        pts.push((t, y));
+        let pred: Vec<f64> = if pts.len() == 1 {
+            axpy(y, h, f0)
+        } else {
+            (0..y.len())
+                .map(|i| {
+                    pts.iter()
+                        .enumerate()
+                        .map(|(j, (tj, yj))| {
+                            let l: f64 = pts
+                                .iter()
+                                .enumerate()
+                                .filter(|(m, _)| *m != j)
+                                .map(|(_, (tm, _))| (t + h - tm) / (tj - tm))
+                                .product();
+                            l * yj[i]
+                        })
+                        .sum()
+                })
+                .collect()
+        };
+        // z - a y - b y_prev - beta h f(t + h, z) = 0. factor はMilneの誤差推定の係数
+        let (a, b, beta, factor) = match hist.len() {
+            0 => (1., 0., 1., 0.5),
+            1 => (1., 0., 1., 1. / 3.),
+            _ => {
+                let w = h / (t - hist[hist.len() - 1].0);
+                let d = 1. + 2. * w;
+                ((1. + w).powi(2) / d, -w * w / d, (1. + w) / d, 2. / 11.)
+            }
+        };
+        let yp: &[f64] = match hist.last() {
+            Some((_, yp)) => yp,
+            None => y,
+        };
+        let (jac, _) = self.jacobian(t + h, &pred, p);
+        let m = Ode::iteration_matrix(&jac, beta * h);
+        let mut z = pred.clone();
+        for _ in 0..8 {
+            let fz = self.rhs(t + h, &z, p);
+            let g: Vec<f64> = (0..y.len())
+                .map(|i| -(z[i] - a * y[i] - b * yp[i] - beta * h * fz[i]))
+                .collect();
+            let dz = lin_solve(m.clone(), g)?;
+            z = axpy(&z, 1., &dz);
+            if self.err_norm(&dz, y, &z, opts) <= 1e-3 {
+                let err = z.iter().zip(&pred).map(|(a, b)| factor * (a - b)).collect();
+                return Some((z, err));
+            }
+        }
+        None
+    }
+
+    fn record(&self, sol: &mut OdeSolution, t: f64, y: &[f64]) {
+        sol.t.push(t);
+        sol.y.push(y[..self.dim].to_vec());
+        if self.sensitivity {
+            // パラメータがなければ各行は空
+            let np = self.pslots.len();
+            let s = &y[self.dim..];
+            sol.sensitivities.push(
+                (0..self.dim)
+                    .map(|j| s[j * np..(j + 1) * np].to_vec())
+                    .collect(),
+            );
+        }
+    }
+
+    pub fn solve(
+        &self,
+        y0: &[f64],
+        p: &[f64],
+        span: (f64, f64),
+        method: Method,
+        opts: &OdeOptions,
+    ) -> OdeSolution {
+        assert!(y0.len() == self.dim && p.len() == self.pslots.len());
+        let (mut t, end) = span;
+        let dir = if end < t { -1. } else { 1. };
+        let mut y = y0.to_vec();
+        y.resize(self.yslots.len(), 0.);
+        let mut f = self.rhs(t, &y, p);
+        let mut sol = OdeSolution {
+            t: vec![],
+            y: vec![],
+            sensitivities: vec![],
+            steps: 0,
+            rejected: 0,
+            success: false,
+        };
+        self.record(&mut sol, t, &y);
+        let mut h = match opts.first_step {
+            Some(h) => h.abs(),
+            None => {
+                let zero = vec![0.; y.len()];
+                let (d0, d1) = (
+                    self.err_norm(&y, &y, &zero, opts),
+                    self.err_norm(&f, &y, &zero, opts),
+                );
+                if d0 < 1e-5 || d1 < 1e-5 {
+                    1e-6
+                } else {
+                    0.01 * d0 / d1
+                }
+            }
+        }
+        .min((end - t).abs());
+        let mut hist: Vec<(f64, Vec<f64>)> = vec![];
+        while (end - t) * dir > 0. && sol.steps + sol.rejected < opts.max_steps {
+            // 終点に合わせる
+            let last = (end - t).abs() <= h * (1. + 1e-12);
+            if last {
+                h = (end - t).abs();
+            }
+            let hs = dir * h;
+            let (step, order) = match method {
+                Method::Rk45 => (Some(self.rk45_step(t, &y, &f, hs, p)), 4),
+                Method::Rosenbrock => (self.rosenbrock_step(t, &y, &f, hs, p), 2),
+                Method::Bdf => (
+                    self.bdf_step(t, &y, &f, hs, p, &hist, opts),
+                    if hist.len() == 2 { 2 } else { 1 },
+                ),
+            };
+            let (z, err) = match step {
+                Some((z, e)) => {
+                    let err = self.err_norm(&e, &y, &z, opts);
+                    (z, err)
+                }
+                // Newton反復が収束しない
+                None => (vec![], f64::INFINITY),
+            };
+            let fac = 0.9 * err.powf(-1. / (order as f64 + 1.));
+            if err <= 1. {
+                if method == Method::Bdf {
+                    hist.push((t, y.clone()));
+                    if 2 < hist.len() {
+                        hist.remove(0);
+                    }
+                }
+                t = if last { end } else { t + hs };
+                y = z;
+                f = self.rhs(t, &y, p);
+                sol.steps += 1;
+                self.record(&mut sol, t, &y);
+                // BDF2の刻み幅比は 1 + sqrt(2) 未満にしておく
+                let grow = if method == Method::Bdf { 2. } else { 5. };
+                h *= fac.min(grow).max(0.2);
+            } else {
+                sol.rejected += 1;
+                h *= if err.is_finite() { fac.max(0.2) } else { 0.25 };
+                if h <= 1e-14 * t.abs().max(1.) {
+                    break;
+                }
+            }
+        }
+        sol.success = (end - t) * dir <= 0.;
+        sol
+    }
+}
+
+#[test]
+fn ode_methods_and_sensitivities() {
+    let e = &Environment::new();
+    // y' = -k y, y(0) = 1 なら dy/dk = -t exp(-k t)
+    let ode = Ode::with_sensitivities(vec![parse_expr("0 - k * y", e)], "t", "y", "k", e);
+    for (method, tol) in [
+        (Method::Rk45, 1e-7),
+        (Method::Bdf, 1e-4),
+        (Method::Rosenbrock, 1e-4),
+    ]
+    .iter()
+    {
+        let opts = OdeOptions::new().rtol(1e-8).atol(1e-10);
+        let s = ode.solve(&[1.], &[2.], (0., 1.), *method, &opts);
+        assert!(s.success && *s.t.last().unwrap() == 1., "{:?}", method);
+        let (y, dy) = (
+            s.y.last().unwrap()[0],
+            s.sensitivities.last().unwrap()[0][0],
+        );
+        assert!((y - (-2f64).exp()).abs() < *tol, "{:?} {}", method, y);
+        assert!((dy + (-2f64).exp()).abs() < *tol, "{:?} {}", method, dy);
+    }
+
+    // 振動子. 後ろ向きにも積分できる
+    let fs = vec![parse_expr("v", e), parse_expr("0 - w^2 * x", e)];
+    let ode = Ode::with_sensitivities(fs, "t", "x v", "w", e);
+    let pi = std::f64::consts::PI;
+    let opts = OdeOptions::new().rtol(1e-10).atol(1e-12);
+    let s = ode.solve(&[1., 0.], &[1.], (0., -pi), Method::Rk45, &opts);
+    let last = s.y.last().unwrap();
+    assert!(
+        (last[0] + 1.).abs() < 1e-8 && last[1].abs() < 1e-8,
+        "{:?}",
+        last
+    );
+    // x = cos(w t) だから dx/dw = -t sin(w t)
+    let sens = s.sensitivities.last().unwrap();
+    assert!(
+        sens[0][0].abs() < 1e-7 && (sens[1][0] + pi).abs() < 1e-7,
+        "{:?}",
+        sens
+    );
+
+    // 有限差分と比べる
+    let lv = vec![
+        parse_expr("a * p - p * q", e),
+        parse_expr("p * q - b * q", e),
+    ];
+    let ode = Ode::with_sensitivities(lv.clone(), "t", "p q", "a b", e);
+    let plain = Ode::new(lv, "t", "p q", "a b", e);
+    let s = ode.solve(&[1., 0.5], &[1., 0.7], (0., 2.), Method::Rk45, &opts);
+    let sens = s.sensitivities.last().unwrap();
+    let h = 1e-6;
+    let end = |p: &[f64]| {
+        let s = plain.solve(&[1., 0.5], p, (0., 2.), Method::Rk45, &opts);
+        s.y.last().unwrap().clone()
+    };
+    for (k, p) in [[1. + h, 0.7], [1., 0.7 + h]].iter().enumerate() {
+        let mut m = p.to_vec();
+        m[k] -= 2. * h;
+        let (u, l) = (end(p), end(&m));
+        for j in 0..2 {
+            assert!((sens[j][k] - (u[j] - l[j]) / (2. * h)).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn ode_stiff() {
+    let e = &Environment::new();
+    let ode = Ode::new(vec![parse_expr("1000 * (cos(t) - y)", e)], "t", "y", "", e);
+    let l: f64 = 1000.;
+    let exact = (l * l * 10f64.cos() + l * 10f64.sin()) / (l * l + 1.)
+        - l * l / (l * l + 1.) * (-10. * l).exp();
+    let opts = OdeOptions::new().rtol(1e-4).atol(1e-6);
+    let explicit = ode.solve(&[0.], &[], (0., 10.), Method::Rk45, &opts);
+    assert!(explicit.success);
+    for method in [Method::Bdf, Method::Rosenbrock].iter() {
+        let s = ode.solve(&[0.], &[], (0., 10.), *method, &opts);
+        assert!(s.success);
+        assert!(
+            (s.y.last().unwrap()[0] - exact).abs() < 1e-4,
+            "{:?}",
+            s.y.last()
+        );
+        // 陽的法は安定性で刻み幅が抑えられる
+        assert!(
+            5 * s.steps < explicit.steps,
+            "{:?} {} {}",
+            method,
+            s.steps,
+            explicit.steps
+        );
+    }
+}
+
+#[test]
+fn ode_sensitivity_names() {
+    let e = &Environment::new();
+    // 同じ名前の変数があっても感度の変数は別に作る
+    Expr::new_var(String::from("∂y/∂k#0"), e);
+    let ode = Ode::with_sensitivities(vec![parse_expr("0 - k * y", e)], "t", "y", "k", e);
+    assert!(!ode
+        .vars
+        .contains(&e.borrow().search_var(&String::from("∂y/∂k#0")).unwrap()));
+    let s = ode.solve(&[1.], &[2.], (0., 1.), Method::Rk45, &OdeOptions::new());
+    let dy = s.sensitivities.last().unwrap()[0][0];
+    assert!((dy + (-2f64).exp()).abs() < 1e-5, "{}", dy);
+    assert!(e.borrow().search_var(&String::from("∂y/∂k#1")).is_some());
+}
+
+#[test]
+fn ode_sensitivity_no_params() {
+    let e = &Environment::new();
+    // パラメータがなければ感度は空で, 解は感度なしと同じ
+    let fs = vec![parse_expr("0 - y", e), parse_expr("y", e)];
+    let ode = Ode::with_sensitivities(fs.clone(), "t", "y z", "", e);
+    let opts = OdeOptions::new();
+    let s = ode.solve(&[1., 0.], &[], (0., 1.), Method::Rk45, &opts);
+    let plain =
+        Ode::new(fs, "t", "y z", "", e).solve(&[1., 0.], &[], (0., 1.), Method::Rk45, &opts);
+    assert!(s.success);
+    assert_eq!(s.y, plain.y);
+    assert_eq!(s.sensitivities.last().unwrap(), &vec![vec![]; 2]);
+}
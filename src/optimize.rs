@@ -0,0 +1,526 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Env, Expr, Rc, Var};
+#[cfg(test)]
+use super::parse::*;
+use super::solve::{dot, max_abs};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    GradientDescent,
+    // memory組の (s, y) で逆Hessianを近似する
+    Lbfgs { memory: usize },
+    // Hessianを使った信頼領域Newton法 (dogleg)
+    TrustRegion,
+}
+
+// 反復を終えた理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    GradientTol,
+    FunctionTol,
+    StepTol,
+    MaxIterations,
+    Callback,
+    LineSearchFailed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptimizeResult {
+    pub x: Vec<f64>,
+    pub f: f64,
+    // 射影勾配の最大値
+    pub grad_norm: f64,
+    pub iterations: usize,
+    pub stop: Stop,
+}
+
+impl OptimizeResult {
+    pub fn converged(&self) -> bool {
+        matches!(
+            self.stop,
+            Stop::GradientTol | Stop::FunctionTol | Stop::StepTol
+        )
+    }
+}
+
+// コールバックに渡す各反復の状態
+#[derive(Debug, Clone, PartialEq)]
+pub struct Iterate<'a> {
+    pub iteration: usize,
+    pub x: &'a [f64],
+    pub f: f64,
+    pub grad_norm: f64,
+}
+
+// boundsは変数idの順の (下限, 上限). 無限大なら制約なし
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptimizeOptions {
+    pub gtol: f64,
+    pub ftol: f64,
+    pub xtol: f64,
+    pub max_iter: usize,
+    pub bounds: Option<Vec<(f64, f64)>>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        OptimizeOptions {
+            gtol: 1e-8,
+            ftol: 1e-15,
+            xtol: 1e-15,
+            max_iter: 1000,
+            bounds: None,
+        }
+    }
+}
+
+impl OptimizeOptions {
+    pub fn new() -> Self {
+        OptimizeOptions::default()
+    }
+
+    pub fn gtol(mut self, tol: f64) -> Self {
+        self.gtol = tol;
+        self
+    }
+
+    pub fn ftol(mut self, tol: f64) -> Self {
+        self.ftol = tol;
+        self
+    }
+
+    pub fn xtol(mut self, tol: f64) -> Self {
+        self.xtol = tol;
+        self
+    }
+
+    pub fn max_iter(mut self, n: usize) -> Self {
+        self.max_iter = n;
+        self
+    }
+
+    pub fn bounds(mut self, bounds: Vec<(f64, f64)>) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
+}
+
+// a = L L^T. 正定値でなければNone
+fn cholesky(a: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = a.len();
+    let mut l = vec![vec![0.; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let s = a[i][j] - dot(&l[i][..j], &l[j][..j]);
+            if i == j {
+                if s <= 0. || s.is_nan() {
+                    return None;
+                }
+                l[i][i] = s.sqrt();
+            } else {
+                l[i][j] = s / l[j][j];
+            }
+        }
+    }
+    Some(l)
+}
+
+fn cholesky_solve(l: &[Vec<f64>], b: &[f64]) -> Vec<f64> {
+    let n = b.len();
+    let mut y = vec![0.; n];
+    for i in 0..n {
+        y[i] = (b[i] - dot(&l[i][..i], &y[..i])) / l[i][i];
+    }
+    let mut x = vec![0.; n];
+    for i in (0..n).rev() {
+        let s: f64 = (i + 1..n).map(|k| l[k][i] * x[k]).sum();
+        x[i] = (y[i] - s) / l[i][i];
+    }
+    x
+}
+
+enum Step {
+    Accepted(Vec<f64>, f64),
+    // 信頼領域を縮めてやり直す
+    Rejected,
+    Failed,
+}
+
+// 目的関数の勾配とHessianは一つの計算グラフから求める
+#[derive(Debug, Clone)]
+pub struct Optimizer {
+    objective: Rc<Expr>,
+    vars: Vec<Var>,
+    deriv: Deriv,
+}
+
+impl Optimizer {
+    pub fn new(objective: Rc<Expr>, vars: &str, env: &Env) -> Self {
+        let first = vars.split_whitespace().next().unwrap();
+        Optimizer {
+            deriv: Deriv::new(objective.clone(), env, first),
+            objective,
+            vars: parse_vars(vars, env),
+        }
+    }
+
+    pub fn value(&self, x: &Vec<f64>) -> f64 {
+        self.objective.eval_internal(&self.vars, x)
+    }
+
+    pub fn gradient(&self, x: &[f64]) -> Vec<f64> {
+        self.deriv.vjp_internal(&self.vars, x, &[1.])
+    }
+
+    // Hessian-vector積を単位ベクトルごとに求める
+    pub fn hessian(&self, x: &[f64]) -> Vec<Vec<f64>> {
+        let n = x.len();
+        (0..n)
+            .map(|j| {
+                let mut e = vec![0.; n];
+                e[j] = 1.;
+                self.deriv.hvp_internal(&self.vars, x, &e)
+            })
+            .collect()
+    }
+
+    pub fn minimize(&self, x0: &[f64], method: Method, opts: &OptimizeOptions) -> OptimizeResult {
+        self.minimize_with(x0, method, opts, &mut |_| true)
+    }
+
+    // callbackがfalseを返したら止める
+    pub fn minimize_with(
+        &self,
+        x0: &[f64],
+        method: Method,
+        opts: &OptimizeOptions,
+        callback: &mut dyn FnMut(&Iterate) -> bool,
+    ) -> OptimizeResult {
+        let bounds = match &opts.bounds {
+            Some(b) => b.clone(),
+            None => vec![(f64::NEG_INFINITY, f64::INFINITY); x0.len()],
+        };
+        let project = |x: &[f64]| -> Vec<f64> {
+            x.iter()
+                .zip(&bounds)
+                .map(|(v, (lo, hi))| v.max(*lo).min(*hi))
+                .collect()
+        };
+        // 境界に張り付いて外向きの勾配を持つ変数は動かさない
+        let active = |x: &[f64], g: &[f64]| -> Vec<bool> {
+            (0..x.len())
+                .map(|i| (x[i] <= bounds[i].0 && 0. < g[i]) || (bounds[i].1 <= x[i] && g[i] < 0.))
+                .collect()
+        };
+        let mut x = project(x0);
+        let mut f = self.value(&x);
+        let mut g = self.gradient(&x);
+        let mut memory: VecDeque<(Vec<f64>, Vec<f64>)> = VecDeque::new();
+        let mut t = 1.;
+        let mut radius = 1.;
+        let mut iterations = 0;
+        let stop = loop {
+            let xg: Vec<f64> = x.iter().zip(&g).map(|(a, b)| a - b).collect();
+            let pg: Vec<f64> = x.iter().zip(&project(&xg)).map(|(a, b)| a - b).collect();
+            let grad_norm = max_abs(&pg);
+            let it = Iterate {
+                iteration: iterations,
+                x: &x,
+                f,
+                grad_norm,
+            };
+            if !callback(&it) {
+                break Stop::Callback;
+            }
+            if grad_norm <= opts.gtol {
+                break Stop::GradientTol;
+            }
+            if opts.max_iter <= iterations {
+                break Stop::MaxIterations;
+            }
+            iterations += 1;
+            let act = active(&x, &g);
+            let gf: Vec<f64> = g
+                .iter()
+                .zip(&act)
+                .map(|(v, a)| if *a { 0. } else { *v })
+                .collect();
+            let step = match method {
+                Method::GradientDescent => {
+                    let d: Vec<f64> = gf.iter().map(|v| -v).collect();
+                    match self.search(&x, f, &g, &d, 2. * t, &project) {
+                        Some((xt, ft, tt)) => {
+                            t = tt;
+                            Step::Accepted(xt, ft)
+                        }
+                        None => Step::Failed,
+                    }
+                }
+                Method::Lbfgs { memory: m } => {
+                    let mut d = two_loop(&gf, &memory);
+                    for (di, a) in d.iter_mut().zip(&act) {
+                        if *a {
+                            *di = 0.;
+                        }
+                    }
+                    let t0 = if memory.is_empty() {
+                        1f64.min(1. / max_abs(&gf))
+                    } else {
+                        1.
+                    };
+                    if 0. <= dot(&d, &g) {
+                        memory.clear();
+                        d = gf.iter().map(|v| -v).collect();
+                    }
+                    match self.search(&x, f, &g, &d, t0, &project) {
+                        Some((xt, ft, _)) => {
+                            let gt = self.gradient(&xt);
+                            let s: Vec<f64> = xt.iter().zip(&x).map(|(a, b)| a - b).collect();
+                            let y: Vec<f64> = gt.iter().zip(&g).map(|(a, b)| a - b).collect();
+                            // 曲率条件を満たす組だけ覚える
+                            if 1e-12 * dot(&s, &s).sqrt() * dot(&y, &y).sqrt() < dot(&s, &y) {
+                                memory.push_back((s, y));
+                                if m < memory.len() {
+                                    memory.pop_front();
+                                }
+                            }
+                            Step::Accepted(xt, ft)
+                        }
+                        None => Step::Failed,
+                    }
+                }
+                Method::TrustRegion => self.trust_step(&x, f, &gf, &act, &mut radius, &project),
+            };
+            match step {
+                Step::Accepted(xt, ft) => {
+                    let dx = xt.iter().zip(&x).map(|(a, b)| (a - b).abs());
+                    let small_step = dx.fold(0., f64::max) <= opts.xtol * (1. + max_abs(&x));
+                    let small_df = (f - ft).abs() <= opts.ftol * (1. + f.abs());
+                    x = xt;
+                    f = ft;
+                    g = self.gradient(&x);
+                    if small_df {
+                        break Stop::FunctionTol;
+                    }
+                    if small_step {
+                        break Stop::StepTol;
+                    }
+                }
+                Step::Rejected => {
+                    if radius <= opts.xtol * (1. + max_abs(&x)) {
+                        break Stop::StepTol;
+                    }
+                }
+                Step::Failed => break Stop::LineSearchFailed,
+            }
+        };
+        let xg: Vec<f64> = x.iter().zip(&g).map(|(a, b)| a - b).collect();
+        let pg: Vec<f64> = x.iter().zip(&project(&xg)).map(|(a, b)| a - b).collect();
+        OptimizeResult {
+            grad_norm: max_abs(&pg),
+            x,
+            f,
+            iterations,
+            stop,
+        }
+    }
+
+    // 射影付きのArmijo直線探索. (x, f, 歩幅) を返す
+    fn search(
+        &self,
+        x: &[f64],
+        f: f64,
+        g: &[f64],
+        d: &[f64],
+        t0: f64,
+        project: &dyn Fn(&[f64]) -> Vec<f64>,
+    ) -> Option<(Vec<f64>, f64, f64)> {
+        let mut t = t0;
+        for _ in 0..60 {
+            let xd: Vec<f64> = x.iter().zip(d).map(|(a, b)| a + t * b).collect();
+            let xt = project(&xd);
+            let s: Vec<f64> = xt.iter().zip(x).map(|(a, b)| a - b).collect();
+            let dec = dot(g, &s);
+            if dec < 0. {
+                let ft = self.value(&xt);
+                if ft.is_finite() && ft <= f + 1e-4 * dec {
+                    return Some((xt, ft, t));
+                }
+            }
+            t /= 2.;
+        }
+        None
+    }
+
+    // 動ける変数だけで信頼領域部分問題をdoglegで解く
+    fn trust_step(
+        &self,
+        x: &[f64],
+        f: f64,
+        g: &[f64],
+        act: &[bool],
+        radius: &mut f64,
+        project: &dyn Fn(&[f64]) -> Vec<f64>,
+    ) -> Step {
+        let n = x.len();
+        let mut h = self.hessian(x);
+        for i in (0..n).filter(|i| act[*i]) {
+            for (j, row) in h.iter_mut().enumerate() {
+                row[i] = if i == j { 1. } else { 0. };
+            }
+            h[i] = (0..n).map(|j| if i == j { 1. } else { 0. }).collect();
+        }
+        let hv = |v: &[f64]| -> Vec<f64> { h.iter().map(|r| dot(r, v)).collect() };
+        let gnorm = dot(g, g).sqrt();
+        if gnorm == 0. {
+            return Step::Failed;
+        }
+        let ghg = dot(g, &hv(g));
+        let scaled = |c: f64| -> Vec<f64> { g.iter().map(|v| -c * v).collect() };
+        // Cauchy点
+        let cauchy = if 0. < ghg {
+            scaled((gnorm * gnorm / ghg).min(*radius / gnorm))
+        } else {
+            scaled(*radius / gnorm)
+        };
+        let p = match cholesky(&h) {
+            Some(l) => {
+                let pn = cholesky_solve(&l, &scaled(1.));
+                if dot(&pn, &pn).sqrt() <= *radius {
+                    pn
+                } else if dot(&cauchy, &cauchy).sqrt() >= *radius * (1. - 1e-12) {
+                    cauchy
+                } else {
+                    // |pu + tau (pn - pu)| = radius
+                    let w: Vec<f64> = pn.iter().zip(&cauchy).map(|(a, b)| a - b).collect();
+                    let (a, b) = (dot(&w, &w), 2. * dot(&cauchy, &w));
+                    let c = dot(&cauchy, &cauchy) - *radius * *radius;
+                    let tau = (-b + (b * b - 4. * a * c).sqrt()) / (2. * a);
+                    cauchy.iter().zip(&w).map(|(u, v)| u + tau * v).collect()
+                }
+            }
+            None => cauchy,
+        };
+        let xp: Vec<f64> = x.iter().zip(&p).map(|(a, b)| a + b).collect();
+        let xt = project(&xp);
+        let s: Vec<f64> = xt.iter().zip(x).map(|(a, b)| a - b).collect();
+        let pred = -(dot(g, &s) + dot(&s, &hv(&s)) / 2.);
+        let ft = self.value(&xt);
+        let rho = if 0. < pred && ft.is_finite() {
+            (f - ft) / pred
+        } else {
+            -1.
+        };
+        let snorm = dot(&s, &s).sqrt();
+        if rho < 0.25 {
+            *radius = snorm.min(*radius) / 4.;
+        } else if 0.75 < rho && *radius * 0.99 <= snorm {
+            *radius *= 2.;
+        }
+        if 1e-4 < rho {
+            Step::Accepted(xt, ft)
+        } else {
+            Step::Rejected
+        }
+    }
+}
+
+// L-BFGSの二重ループで -H g を求める
+fn two_loop(g: &[f64], memory: &VecDeque<(Vec<f64>, Vec<f64>)>) -> Vec<f64> {
+    let mut q = g.to_vec();
+    let mut alphas = vec![];
+    for (s, y) in memory.iter().rev() {
+        let a = dot(s, &q) / dot(y, s);
+        for (qi, yi) in q.iter_mut().zip(y) {
+            *qi -= a * yi;
+        }
+        alphas.push(a);
+    }
+    if let Some((s, y)) = memory.back() {
+        let gamma = dot(s, y) / dot(y, y);
+        for qi in q.iter_mut() {
+            *qi *= gamma;
+        }
+    }
+    for ((s, y), a) in memory.iter().zip(alphas.iter().rev()) {
+        let b = dot(y, &q) / dot(y, s);
+        for (qi, si) in q.iter_mut().zip(s) {
+            *qi += (a - b) * si;
+        }
+    }
+    q.iter().map(|v| -v).collect()
+}
+
+#[test]
+fn minimize_unconstrained() {
+    let e = &Environment::new();
+    let rosen = Optimizer::new(parse_expr("(1 - x)^2 + 100 * (y - x^2)^2", e), "x y", e);
+    let opts = OptimizeOptions::new();
+    for method in [Method::Lbfgs { memory: 5 }, Method::TrustRegion].iter() {
+        let r = rosen.minimize(&[-1.2, 1.], *method, &opts);
+        assert!(r.converged(), "{:?} {:?}", method, r);
+        assert!((r.x[0] - 1.).abs() < 1e-6 && (r.x[1] - 1.).abs() < 1e-6);
+    }
+    // 最急降下法は遅いが単調に減る
+    let r = rosen.minimize(
+        &[-1.2, 1.],
+        Method::GradientDescent,
+        &opts.clone().max_iter(50),
+    );
+    assert_eq!(r.stop, Stop::MaxIterations);
+    assert!(r.f < rosen.value(&vec![-1.2, 1.]));
+
+    let q = Optimizer::new(parse_expr("(x - 1)^2 + 2 * (y + 2)^2 + x * y", e), "x y", e);
+    // 勾配 0 の点: 2(x-1) + y = 0, 4(y+2) + x = 0
+    let (x, y) = (16. / 7., -18. / 7.);
+    for method in [
+        Method::GradientDescent,
+        Method::Lbfgs { memory: 3 },
+        Method::TrustRegion,
+    ]
+    .iter()
+    {
+        let r = q.minimize(&[0., 0.], *method, &opts);
+        assert!(r.converged(), "{:?} {:?}", method, r);
+        assert!(
+            (r.x[0] - x).abs() < 1e-6 && (r.x[1] - y).abs() < 1e-6,
+            "{:?}",
+            r
+        );
+    }
+}
+
+#[test]
+fn minimize_with_bounds_and_callback() {
+    let e = &Environment::new();
+    let f = Optimizer::new(parse_expr("(x - 2)^2 + (y + 1)^2 + x * y", e), "x y", e);
+    let opts = OptimizeOptions::new().bounds(vec![(0., 1.), (0., 1.)]);
+    for method in [
+        Method::GradientDescent,
+        Method::Lbfgs { memory: 5 },
+        Method::TrustRegion,
+    ]
+    .iter()
+    {
+        let r = f.minimize(&[0.5, 0.5], *method, &opts);
+        assert!(r.converged(), "{:?} {:?}", method, r);
+        assert!((r.x[0] - 1.).abs() < 1e-8 && r.x[1].abs() < 1e-8, "{:?}", r);
+    }
+
+    let mut seen = vec![];
+    let r = f.minimize_with(
+        &[5., 5.],
+        Method::GradientDescent,
+        &OptimizeOptions::new(),
+        &mut |it| {
+            seen.push(it.f);
+            it.iteration < 3
+        },
+    );
+    assert_eq!(r.stop, Stop::Callback);
+    assert_eq!(r.iterations, 3);
+    assert!(seen.windows(2).all(|w| w[1] < w[0]));
+}
@@ -1,4 +1,7 @@
-use super::expr::{Bop, Env, Environment, Expr, Uop, Var, C};
+use super::equation::Equation;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Bop, Env, Expr, Uop};
 pub use super::parser_combinator::*;
 use std::rc::Rc;
 
@@ -32,6 +35,7 @@ fn number_parser() {
 }
 
 fn variable<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
+    // i は env で虚数単位にしていればその変数になる
     identifier.map(|(s, env)| (Expr::new_var(s, env), env))
 }
 pub fn variables<'a>() -> impl Parser<'a, Vec<Rc<Expr>>> {
@@ -63,25 +67,14 @@ fn func<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
         parenthesized_expr(),
     )
     .map(|(name, (exp, env))| {
-        let op;
-        match name {
-            "sin" => {
-                op = Uop::Sin;
-            }
-            "cos" => {
-                op = Uop::Cos;
-            }
-            "tan" => {
-                op = Uop::Tan;
-            }
-            "log" => {
-                op = Uop::Log;
-            }
-            "exp" => {
-                op = Uop::Exp;
-            }
+        let op = match name {
+            "sin" => Uop::Sin,
+            "cos" => Uop::Cos,
+            "tan" => Uop::Tan,
+            "log" => Uop::Log,
+            "exp" => Uop::Exp,
             _ => unimplemented!(),
-        }
+        };
         (Expr::new_unop(op, exp, env), env)
     })
 }
@@ -94,9 +87,9 @@ fn unary<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
         either(func(), primary()).map(move |(mut res, env)| {
             if vec_c_r.iter().filter(|(c, _e)| *c == '-').count() % 2 != 0 {
                 res = Expr::new_unop(Uop::Neg, res, env);
-                return (res, env);
+                (res, env)
             } else {
-                return (res, env);
+                (res, env)
             }
         })
     })
@@ -116,7 +109,7 @@ fn unary_parser() {
 fn factor<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
     unary().and_then(|(one, env)| {
         zero_or_more(right(whitespace_wrap(match_literal("^")), unary())).map(move |mut unaries| {
-            if unaries.len() == 0 {
+            if unaries.is_empty() {
                 (one.clone(), env)
             } else {
                 let env = unaries.last().unwrap().1;
@@ -152,7 +145,7 @@ fn term<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
             factor(),
         )))
         .map(move |mut factors| {
-            if factors.len() == 0 {
+            if factors.is_empty() {
                 (one.clone(), env)
             } else {
                 let env = factors.last().unwrap().1 .1;
@@ -209,7 +202,7 @@ pub fn expr<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
             term(),
         )))
         .map(move |mut terms| {
-            if terms.len() == 0 {
+            if terms.is_empty() {
                 (one.clone(), env)
             } else {
                 let env = terms.last().unwrap().1 .1;
@@ -233,6 +226,12 @@ pub fn expr<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
     })
 }
 
+// lhs = rhs
+pub fn equation<'a>() -> impl Parser<'a, Equation> {
+    pair(expr(), right(match_literal("="), expr()))
+        .map(|((lhs, _), (rhs, _))| Equation::new(lhs, rhs))
+}
+
 fn parenthesized_expr<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
     right(
         match_literal("("),
@@ -240,6 +239,15 @@ fn parenthesized_expr<'a>() -> impl Parser<'a, (Rc<Expr>, &'a Env)> {
     )
 }
 
+// テストで使う. パースできなければpanicする
+#[cfg(test)]
+pub(crate) fn parse_expr(s: &str, e: &Env) -> Rc<Expr> {
+    match expr().parse(s, e) {
+        Ok((_, _, (expr, _))) => expr,
+        Err(_) => panic!("failed to parse {}", s),
+    }
+}
+
 #[test]
 fn expr_parser() {
     let e = &Environment::new();
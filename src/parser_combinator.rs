@@ -78,7 +78,7 @@ pub fn identifier<'a>(input: &'a str, env: &'a Env) -> ParseResult<'a, (String,
         _ => return Err(input),
     }
 
-    while let Some(next) = chars.next() {
+    for next in chars {
         if next.is_alphanumeric() || next == '-' {
             matched.push(next);
         } else {
@@ -0,0 +1,877 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Bop, Env, Expr, One, Uop, Var, Zero, C};
+#[cfg(test)]
+use super::parse::*;
+use num_bigint::BigInt;
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, Signed, ToPrimitive};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::rc::Rc;
+
+// 単項式: (変数, 次数)の列. 変数の順に並び, 次数は1以上
+pub type Monomial = Vec<(Var, u32)>;
+
+// Cを係数とする疎な多変数多項式
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Poly {
+    // 係数0の項は持たない
+    pub terms: BTreeMap<Monomial, C>,
+}
+
+// 多項式でない部分式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotPolynomial(pub Expr);
+
+// 次数があふれたらNone
+fn mono_mul(a: &[(Var, u32)], b: &[(Var, u32)]) -> Option<Monomial> {
+    let mut res = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if j == b.len() || (i < a.len() && a[i].0 < b[j].0) {
+            res.push(a[i]);
+            i += 1;
+        } else if i == a.len() || b[j].0 < a[i].0 {
+            res.push(b[j]);
+            j += 1;
+        } else {
+            res.push((a[i].0, a[i].1.checked_add(b[j].1)?));
+            i += 1;
+            j += 1;
+        }
+    }
+    Some(res)
+}
+
+impl Poly {
+    pub fn zero() -> Self {
+        Poly::default()
+    }
+
+    pub fn one() -> Self {
+        Poly::constant(C::one())
+    }
+
+    pub fn constant(c: C) -> Self {
+        let mut p = Poly::zero();
+        p.add_term(vec![], c);
+        p
+    }
+
+    pub fn var(v: Var) -> Self {
+        let mut p = Poly::zero();
+        p.add_term(vec![(v, 1)], C::one());
+        p
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    // 定数ならその値
+    pub fn constant_value(&self) -> Option<C> {
+        match self.terms.len() {
+            0 => Some(C::zero()),
+            1 => self.terms.get(&vec![]).copied(),
+            _ => None,
+        }
+    }
+
+    pub fn add_term(&mut self, m: Monomial, c: C) {
+        self.checked_add_term(m, c).expect("coefficient overflow")
+    }
+
+    pub fn scale(&self, c: C) -> Poly {
+        self.checked_scale(c).expect("coefficient overflow")
+    }
+
+    pub fn pow(&self, n: u32) -> Poly {
+        self.checked_pow(n).expect("coefficient overflow")
+    }
+
+    // 以下の checked_* は係数か次数があふれたらNone
+    pub fn checked_add_term(&mut self, m: Monomial, c: C) -> Option<()> {
+        let s = self.terms.get(&m).unwrap_or(&C::zero()).checked_add(&c)?;
+        if s.is_zero() {
+            self.terms.remove(&m);
+        } else {
+            self.terms.insert(m, s);
+        }
+        Some(())
+    }
+
+    pub fn checked_scale(&self, c: C) -> Option<Poly> {
+        if c.is_zero() {
+            return Some(Poly::zero());
+        }
+        let terms = self
+            .terms
+            .iter()
+            .map(|(m, d)| Some((m.clone(), d.checked_mul(&c)?)))
+            .collect::<Option<_>>()?;
+        Some(Poly { terms })
+    }
+
+    pub fn checked_add(&self, other: &Poly) -> Option<Poly> {
+        let mut res = self.clone();
+        for (m, c) in &other.terms {
+            res.checked_add_term(m.clone(), *c)?;
+        }
+        Some(res)
+    }
+
+    pub fn checked_mul(&self, other: &Poly) -> Option<Poly> {
+        let mut res = Poly::zero();
+        for (m1, c1) in &self.terms {
+            for (m2, c2) in &other.terms {
+                res.checked_add_term(mono_mul(m1, m2)?, c1.checked_mul(c2)?)?;
+            }
+        }
+        Some(res)
+    }
+
+    pub fn checked_pow(&self, mut n: u32) -> Option<Poly> {
+        let mut res = Poly::one();
+        let mut base = self.clone();
+        while 0 < n {
+            if n & 1 == 1 {
+                res = res.checked_mul(&base)?;
+            }
+            n >>= 1;
+            // 使わない冪は作らない
+            if 0 < n {
+                base = base.checked_mul(&base)?;
+            }
+        }
+        Some(res)
+    }
+
+    pub fn degree(&self, v: Var) -> u32 {
+        self.terms
+            .keys()
+            .map(|m| m.iter().find(|(w, _)| *w == v).map_or(0, |(_, k)| *k))
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn total_degree(&self) -> u32 {
+        self.terms
+            .keys()
+            .map(|m| m.iter().map(|(_, k)| k).sum())
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn vars(&self) -> BTreeSet<Var> {
+        self.terms.keys().flatten().map(|(v, _)| *v).collect()
+    }
+
+    pub fn derivative(&self, v: Var) -> Poly {
+        let mut res = Poly::zero();
+        for (m, c) in &self.terms {
+            if let Some(i) = m.iter().position(|(w, _)| *w == v) {
+                let k = m[i].1;
+                let mut m = m.clone();
+                if k == 1 {
+                    m.remove(i);
+                } else {
+                    m[i].1 = k - 1;
+                }
+                res.add_term(m, *c * C::from(i64::from(k)));
+            }
+        }
+        res
+    }
+
+    // 積分定数0の原始関数
+    pub fn antiderivative(&self, v: Var) -> Poly {
+        let mut res = Poly::zero();
+        for (m, c) in &self.terms {
+            let mut m = m.clone();
+            let k = match m.iter().position(|(w, _)| *w == v) {
+                Some(i) => {
+                    m[i].1 += 1;
+                    m[i].1
+                }
+                None => {
+                    m.push((v, 1));
+                    m.sort();
+                    1
+                }
+            };
+            res.add_term(m, *c / C::from(i64::from(k)));
+        }
+        res
+    }
+
+    // eval_internalと同じく, varsはソート済みでvalsと対応する
+    pub fn eval(&self, vars: &[Var], vals: &[f64]) -> f64 {
+        let mut res = 0.;
+        for (m, c) in &self.terms {
+            let mut t = *c.numer() as f64 / *c.denom() as f64;
+            for (v, k) in m {
+                match vars.binary_search(v) {
+                    Ok(i) => t *= vals[i].powi(*k as i32),
+                    Err(_) => panic!("var {} is not specified", v.id),
+                }
+            }
+            res += t;
+        }
+        res
+    }
+
+    // 係数がi64からあふれるときもNotPolynomialを返す
+    pub fn from_expr(expr: &Expr) -> Result<Poly, NotPolynomial> {
+        let res = match expr {
+            Expr::Num(c) => Some(Poly::constant(*c)),
+            Expr::Var(v) => Some(Poly::var(*v)),
+            Expr::UnOp { op: Uop::Neg, exp } => Poly::from_expr(exp)?.checked_scale(-C::one()),
+            Expr::UnOp { .. } => None,
+            Expr::BinOp { op, exp1, exp2 } => {
+                let left = Poly::from_expr(exp1)?;
+                match op {
+                    Bop::Add => left.checked_add(&Poly::from_expr(exp2)?),
+                    Bop::Sub => Poly::from_expr(exp2)?
+                        .checked_scale(-C::one())
+                        .and_then(|r| left.checked_add(&r)),
+                    Bop::Mul => left.checked_mul(&Poly::from_expr(exp2)?),
+                    // 定数でしか割れない
+                    Bop::Div => match Poly::from_expr(exp2)?.constant_value() {
+                        Some(c) if !c.is_zero() => {
+                            C::one().checked_div(&c).and_then(|k| left.checked_scale(k))
+                        }
+                        _ => None,
+                    },
+                    Bop::Pow => match **exp2 {
+                        Expr::Num(n) if n.is_integer() && C::zero() <= n => {
+                            u32::try_from(*n.numer())
+                                .ok()
+                                .and_then(|k| left.checked_pow(k))
+                        }
+                        _ => None,
+                    },
+                }
+            }
+        };
+        res.ok_or_else(|| NotPolynomial(expr.clone()))
+    }
+
+    // 展開しても係数がi64に収まるとわかるときだけ展開する
+    pub fn from_expr_small(expr: &Expr) -> Option<Poly> {
+        match size_bound(expr) {
+            Some((norm, den)) if norm * den <= SMALL && den <= SMALL => Poly::from_expr(expr).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn to_expr(&self, env: &Env) -> Rc<Expr> {
+        let mut res: Option<Rc<Expr>> = None;
+        // 辞書式順序で大きい方から並べる
+        let mut terms: Vec<_> = self.terms.iter().collect();
+        terms.sort_by(|a, b| lex_cmp(b.0, a.0));
+        for (m, c) in terms {
+            let neg = *c < C::zero() && res.is_some();
+            let c = if neg { -*c } else { *c };
+            let mut mono: Option<Rc<Expr>> = None;
+            for (v, k) in m {
+                let mut f = env.borrow_mut().extend_expr(Expr::Var(*v));
+                if 1 < *k {
+                    f = Expr::new_binop(Bop::Pow, f, Expr::new_num(i64::from(*k), env), env);
+                }
+                mono = Some(match mono {
+                    Some(p) => Expr::new_binop(Bop::Mul, p, f, env),
+                    None => f,
+                });
+            }
+            let term = match mono {
+                None => Expr::new_num_from_rat(c, env),
+                Some(mono) if c.is_one() => mono,
+                Some(mono) if c == -C::one() => Expr::new_unop(Uop::Neg, mono, env),
+                Some(mono) => Expr::new_binop(Bop::Mul, Expr::new_num_from_rat(c, env), mono, env),
+            };
+            res = Some(match res {
+                Some(r) if neg => Expr::new_binop(Bop::Sub, r, term, env),
+                Some(r) => Expr::new_binop(Bop::Add, r, term, env),
+                None => term,
+            });
+        }
+        res.unwrap_or_else(|| Expr::new_num(0, env))
+    }
+}
+
+// from_expr_small で許す係数の大きさ. 途中の積が i64 に収まる
+const SMALL: f64 = (1u64 << 24) as f64;
+
+// 展開した係数の絶対値の和と, 分母の積の上界. 定数以外で割るものは諦める
+fn size_bound(expr: &Expr) -> Option<(f64, f64)> {
+    let rat = |c: &C| (*c.numer() as f64).abs() / *c.denom() as f64;
+    match expr {
+        Expr::Num(c) => Some((rat(c), *c.denom() as f64)),
+        Expr::Var(_) => Some((1., 1.)),
+        Expr::UnOp { op: Uop::Neg, exp } => size_bound(exp),
+        Expr::UnOp { .. } => None,
+        Expr::BinOp { op, exp1, exp2 } => {
+            let (n1, d1) = size_bound(exp1)?;
+            match (op, &**exp2) {
+                (Bop::Div, Expr::Num(c)) if !c.is_zero() => {
+                    Some((n1 / rat(c), d1 * (*c.numer() as f64).abs()))
+                }
+                (Bop::Div, _) => None,
+                (Bop::Pow, Expr::Num(n)) if n.is_integer() && C::zero() <= *n => {
+                    let k = *n.numer() as f64;
+                    Some((n1.powf(k), d1.powf(k)))
+                }
+                (Bop::Pow, _) => None,
+                (Bop::Add, _) | (Bop::Sub, _) => {
+                    let (n2, d2) = size_bound(exp2)?;
+                    Some((n1 + n2, d1 * d2))
+                }
+                (Bop::Mul, _) => {
+                    let (n2, d2) = size_bound(exp2)?;
+                    Some((n1 * n2, d1 * d2))
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn igcd(mut a: i64, mut b: i64) -> i64 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a.abs()
+}
+
+// 辞書式順序. idの小さい変数ほど優先される
+fn lex_cmp(a: &[(Var, u32)], b: &[(Var, u32)]) -> Ordering {
+    for i in 0.. {
+        match (a.get(i), b.get(i)) {
+            (None, None) => return Ordering::Equal,
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (Some((va, ka)), Some((vb, kb))) => {
+                if va != vb {
+                    // 先に出てきた変数を持つ方が大きい
+                    return vb.cmp(va);
+                } else if ka != kb {
+                    return ka.cmp(kb);
+                }
+            }
+        }
+    }
+    unreachable!()
+}
+
+fn mono_div(a: &[(Var, u32)], b: &[(Var, u32)]) -> Option<Monomial> {
+    let mut res = a.to_vec();
+    for (v, k) in b {
+        let i = res.iter().position(|(w, _)| w == v)?;
+        if res[i].1 < *k {
+            return None;
+        } else if res[i].1 == *k {
+            res.remove(i);
+        } else {
+            res[i].1 -= k;
+        }
+    }
+    Some(res)
+}
+
+impl Poly {
+    pub fn monomial(v: Var, k: u32) -> Self {
+        let mut p = Poly::zero();
+        p.add_term(if k == 0 { vec![] } else { vec![(v, k)] }, C::one());
+        p
+    }
+
+    // 辞書式順序での先頭項
+    pub fn leading_term(&self) -> Option<(&Monomial, &C)> {
+        self.terms.iter().max_by(|a, b| lex_cmp(a.0, b.0))
+    }
+
+    // vについての係数 p = Σ c_k v^k
+    pub fn coeffs(&self, v: Var) -> BTreeMap<u32, Poly> {
+        let mut res: BTreeMap<u32, Poly> = BTreeMap::new();
+        for (m, c) in &self.terms {
+            let k = m.iter().find(|(w, _)| *w == v).map_or(0, |(_, k)| *k);
+            let rest = m.iter().filter(|(w, _)| *w != v).copied().collect();
+            res.entry(k).or_default().add_term(rest, *c);
+        }
+        res
+    }
+
+    // 割り切れるときだけ商を返す
+    pub fn div_exact(&self, d: &Poly) -> Option<Poly> {
+        let (dm, dc) = match d.leading_term() {
+            Some((m, c)) => (m.clone(), *c),
+            None => panic!("zero div"),
+        };
+        let mut r = self.clone();
+        let mut q = Poly::zero();
+        while let Some((m, c)) = r.leading_term() {
+            let mut t = Poly::zero();
+            t.add_term(mono_div(m, &dm)?, *c / dc);
+            r = &r - &(&t * d);
+            q = &q + &t;
+        }
+        Some(q)
+    }
+
+    // 係数の有理数としての内容. 先頭係数の符号を持つ
+    pub fn rat_content(&self) -> C {
+        let mut num = 0;
+        let mut den = 1;
+        for c in self.terms.values() {
+            num = igcd(num, *c.numer());
+            den = den / igcd(den, *c.denom()) * *c.denom();
+        }
+        match self.leading_term() {
+            Some((_, c)) if *c < C::zero() => C::new(-num, den),
+            Some(_) => C::new(num, den),
+            None => C::one(),
+        }
+    }
+
+    // 整数係数で原始的, 先頭係数が正になるように定数倍する
+    pub fn normalize(&self) -> Poly {
+        self.scale(C::one() / self.rat_content())
+    }
+
+    // vについての内容 (係数のgcd)
+    pub fn content_in(&self, v: Var) -> Poly {
+        self.coeffs(v).values().fold(Poly::zero(), |g, c| g.gcd(c))
+    }
+
+    // vについての擬剰余 lc(b)^k a mod b
+    pub fn prem(&self, b: &Poly, v: Var) -> Poly {
+        let db = b.degree(v);
+        let lb = b.coeffs(v).remove(&db).expect("zero div");
+        let mut r = self.clone();
+        while !r.is_zero() && db <= r.degree(v) {
+            let dr = r.degree(v);
+            let lr = r.coeffs(v).remove(&dr).unwrap();
+            let shift = &lr * &Poly::monomial(v, dr - db);
+            r = &(&r * &lb) - &(&shift * b);
+        }
+        r
+    }
+
+    // 有理数係数の多変数多項式のgcd. 途中の係数は大きくなるので整数係数に直してから求める
+    pub fn gcd(&self, other: &Poly) -> Poly {
+        let g = ZPoly::from_poly(self).gcd(&ZPoly::from_poly(other));
+        // 両方を割り切るのでまず収まるが, 収まらなければ約分を諦める
+        g.to_poly().unwrap_or_else(Poly::one)
+    }
+
+    // 無平方分解. self = c Π f_i^i
+    pub fn square_free(&self) -> (C, Vec<(Poly, u32)>) {
+        if self.is_zero() {
+            return (C::zero(), vec![]);
+        }
+        let mut factors = BTreeMap::new();
+        self.normalize().square_free_rec(&mut factors);
+        let factors: Vec<(Poly, u32)> = factors.into_iter().map(|(i, f)| (f, i)).collect();
+        let prod = factors
+            .iter()
+            .fold(Poly::one(), |acc, (f, i)| &acc * &f.pow(*i));
+        let c = *self.leading_term().unwrap().1 / *prod.leading_term().unwrap().1;
+        (c, factors)
+    }
+
+    fn square_free_rec(&self, factors: &mut BTreeMap<u32, Poly>) {
+        let v = match self.vars().into_iter().next() {
+            Some(v) => v,
+            None => return,
+        };
+        let cont = self.content_in(v);
+        cont.square_free_rec(factors);
+        // Yunのアルゴリズム
+        let a = self.div_exact(&cont).unwrap();
+        let b = a.derivative(v);
+        let g = a.gcd(&b);
+        let mut w = a.div_exact(&g).unwrap();
+        let mut z = &b.div_exact(&g).unwrap() - &w.derivative(v);
+        let mut i = 1;
+        while w.constant_value().is_none() {
+            let g = w.gcd(&z);
+            if g.constant_value().is_none() {
+                let f = factors.entry(i).or_insert_with(Poly::one);
+                *f = &*f * &g;
+            }
+            w = w.div_exact(&g).unwrap();
+            let y = z.div_exact(&g).unwrap();
+            z = &y - &w.derivative(v);
+            i += 1;
+        }
+    }
+}
+
+fn bgcd(a: &BigInt, b: &BigInt) -> BigInt {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while !b.is_zero() {
+        let t = &a % &b;
+        a = b;
+        b = t;
+    }
+    a
+}
+
+// BigInt係数の多項式. gcdの擬剰余列だけで使う
+#[derive(Debug, Clone, Default)]
+struct ZPoly {
+    terms: BTreeMap<Monomial, BigInt>,
+}
+
+impl ZPoly {
+    fn one() -> Self {
+        let mut p = ZPoly::default();
+        p.add_term(vec![], BigInt::one());
+        p
+    }
+
+    // 分母を払う. 定数倍はgcdに効かない
+    fn from_poly(p: &Poly) -> Self {
+        let den = p.terms.values().fold(BigInt::one(), |l, c| {
+            let d = BigInt::from(*c.denom());
+            let g = bgcd(&l, &d);
+            l / g * d
+        });
+        let terms = p
+            .terms
+            .iter()
+            .map(|(m, c)| {
+                let k = &den / BigInt::from(*c.denom());
+                (m.clone(), k * BigInt::from(*c.numer()))
+            })
+            .collect();
+        ZPoly { terms }
+    }
+
+    fn to_poly(&self) -> Option<Poly> {
+        let mut p = Poly::zero();
+        for (m, c) in &self.terms {
+            p.add_term(m.clone(), C::from(c.to_i64()?));
+        }
+        Some(p)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    fn is_constant(&self) -> bool {
+        self.terms.keys().all(|m| m.is_empty())
+    }
+
+    fn add_term(&mut self, m: Monomial, c: BigInt) {
+        let s = self.terms.remove(&m).unwrap_or_default() + c;
+        if !s.is_zero() {
+            self.terms.insert(m, s);
+        }
+    }
+
+    fn sub(&self, other: &ZPoly) -> ZPoly {
+        let mut res = self.clone();
+        for (m, c) in &other.terms {
+            res.add_term(m.clone(), -c);
+        }
+        res
+    }
+
+    fn mul(&self, other: &ZPoly) -> ZPoly {
+        let mut res = ZPoly::default();
+        for (m1, c1) in &self.terms {
+            for (m2, c2) in &other.terms {
+                res.add_term(mono_mul(m1, m2).expect("degree overflow"), c1 * c2);
+            }
+        }
+        res
+    }
+
+    fn vars(&self) -> BTreeSet<Var> {
+        self.terms.keys().flatten().map(|(v, _)| *v).collect()
+    }
+
+    fn degree(&self, v: Var) -> u32 {
+        self.terms
+            .keys()
+            .map(|m| m.iter().find(|(w, _)| *w == v).map_or(0, |(_, k)| *k))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn coeffs(&self, v: Var) -> BTreeMap<u32, ZPoly> {
+        let mut res: BTreeMap<u32, ZPoly> = BTreeMap::new();
+        for (m, c) in &self.terms {
+            let k = m.iter().find(|(w, _)| *w == v).map_or(0, |(_, k)| *k);
+            let rest = m.iter().filter(|(w, _)| *w != v).copied().collect();
+            res.entry(k).or_default().add_term(rest, c.clone());
+        }
+        res
+    }
+
+    // 係数のgcdで割り, 先頭係数を正にする
+    fn primitive(&self) -> ZPoly {
+        let g = self.terms.values().fold(BigInt::zero(), |g, c| bgcd(&g, c));
+        let neg = match self.terms.iter().max_by(|a, b| lex_cmp(a.0, b.0)) {
+            Some((_, c)) => c.is_negative(),
+            None => return self.clone(),
+        };
+        let g = if neg { -g } else { g };
+        let terms = self
+            .terms
+            .iter()
+            .map(|(m, c)| (m.clone(), c / &g))
+            .collect();
+        ZPoly { terms }
+    }
+
+    // dが原始的なら商も整数係数になる
+    fn div_exact(&self, d: &ZPoly) -> Option<ZPoly> {
+        let (dm, dc) = match d.terms.iter().max_by(|a, b| lex_cmp(a.0, b.0)) {
+            Some((m, c)) => (m.clone(), c.clone()),
+            None => panic!("zero div"),
+        };
+        let mut r = self.clone();
+        let mut q = ZPoly::default();
+        while let Some((m, c)) = r.terms.iter().max_by(|a, b| lex_cmp(a.0, b.0)) {
+            if !(c % &dc).is_zero() {
+                return None;
+            }
+            let mut t = ZPoly::default();
+            t.add_term(mono_div(m, &dm)?, c / &dc);
+            r = r.sub(&t.mul(d));
+            q.terms.append(&mut t.terms);
+        }
+        Some(q)
+    }
+
+    fn content_in(&self, v: Var) -> ZPoly {
+        self.coeffs(v)
+            .values()
+            .fold(ZPoly::default(), |g, c| g.gcd(c))
+    }
+
+    fn prem(&self, b: &ZPoly, v: Var) -> ZPoly {
+        let db = b.degree(v);
+        let lb = b.coeffs(v).remove(&db).expect("zero div");
+        let mut r = self.clone();
+        while !r.is_zero() && db <= r.degree(v) {
+            let dr = r.degree(v);
+            let lr = r.coeffs(v).remove(&dr).unwrap();
+            let mut shift = ZPoly::default();
+            let m = if dr == db { vec![] } else { vec![(v, dr - db)] };
+            shift.add_term(m, BigInt::one());
+            r = r.mul(&lb).sub(&lr.mul(&shift).mul(b));
+        }
+        r
+    }
+
+    // 内容と原始PRSで再帰的に求める. 整数の内容も毎回落とす
+    fn gcd(&self, other: &ZPoly) -> ZPoly {
+        if self.is_zero() {
+            return other.primitive();
+        } else if other.is_zero() {
+            return self.primitive();
+        } else if self.is_constant() || other.is_constant() {
+            return ZPoly::one();
+        }
+        let v = *self.vars().union(&other.vars()).next().unwrap();
+        let (ca, cb) = (self.content_in(v), other.content_in(v));
+        let c = ca.gcd(&cb);
+        let mut a = self.div_exact(&ca).unwrap().primitive();
+        let mut b = other.div_exact(&cb).unwrap().primitive();
+        if a.degree(v) < b.degree(v) {
+            std::mem::swap(&mut a, &mut b);
+        }
+        while 0 < b.degree(v) {
+            let r = a.prem(&b, v);
+            a = b;
+            b = if r.is_zero() {
+                r
+            } else {
+                r.div_exact(&r.content_in(v)).unwrap().primitive()
+            };
+        }
+        if b.is_zero() {
+            c.mul(&a).primitive()
+        } else {
+            c
+        }
+    }
+}
+
+impl Add for &Poly {
+    type Output = Poly;
+    fn add(self, other: &Poly) -> Poly {
+        self.checked_add(other).expect("coefficient overflow")
+    }
+}
+
+impl Sub for &Poly {
+    type Output = Poly;
+    fn sub(self, other: &Poly) -> Poly {
+        let mut res = self.clone();
+        for (m, c) in &other.terms {
+            res.add_term(m.clone(), -*c);
+        }
+        res
+    }
+}
+
+impl Neg for &Poly {
+    type Output = Poly;
+    fn neg(self) -> Poly {
+        self.scale(-C::one())
+    }
+}
+
+impl Mul for &Poly {
+    type Output = Poly;
+    fn mul(self, other: &Poly) -> Poly {
+        self.checked_mul(other).expect("coefficient overflow")
+    }
+}
+
+#[cfg(test)]
+fn parse_poly(s: &str, e: &Env) -> Poly {
+    match expr().parse(s, e) {
+        Ok((_, _, (expr, _))) => Poly::from_expr(&expr).expect("not a polynomial"),
+        Err(_) => panic!("failed to parse {}", s),
+    }
+}
+
+#[cfg(test)]
+fn contains_log(expr: &Expr) -> bool {
+    match expr {
+        Expr::UnOp { op: Uop::Log, .. } => true,
+        Expr::UnOp { exp, .. } => contains_log(exp),
+        Expr::BinOp { exp1, exp2, .. } => contains_log(exp1) || contains_log(exp2),
+        _ => false,
+    }
+}
+
+#[test]
+fn poly_arith() {
+    let e = &Environment::new();
+    let p = parse_poly("x + 1", e);
+    assert_eq!(p.pow(3), parse_poly("x^3 + 3*x^2 + 3*x + 1", e));
+    let q = parse_poly("x - y", e);
+    assert_eq!(&p * &q, parse_poly("x^2 - x*y + x - y", e));
+    assert_eq!(&(&p + &q) - &q, p);
+    assert_eq!(
+        parse_poly("(x^2 + y) / 2", e).eval(&[Var::new(0), Var::new(1)], &[2., 4.]),
+        4.
+    );
+    assert_eq!(p.pow(3).to_expr(e).eval("x", &vec![2.], e), 27.);
+
+    let roundtrip = parse_poly("x^2*y - 3/4*y + 2", e);
+    assert_eq!(Poly::from_expr(&roundtrip.to_expr(e)), Ok(roundtrip));
+
+    // 指数や係数があふれるものも多項式にしない
+    for s in [
+        "sin(x)",
+        "x ^ y",
+        "1 / x",
+        "x ^ (1/2)",
+        "(x + 1) ^ 4294967297",
+        "(x + 1) ^ 70",
+        "x ^ 4000000000 * x ^ 4000000000",
+    ]
+    .iter()
+    {
+        match expr().parse(s, e) {
+            Ok((_, _, (expr, _))) => assert!(Poly::from_expr(&expr).is_err()),
+            Err(_) => panic!(""),
+        }
+    }
+}
+
+#[test]
+fn poly_derivative() {
+    let e = &Environment::new();
+    let p = parse_poly("x^3*y + 2*x*y^2 + 5", e);
+    let x = e.borrow().search_var(&String::from("x")).unwrap();
+    assert_eq!(p.derivative(x), parse_poly("3*x^2*y + 2*y^2", e));
+    assert_eq!(p.total_degree(), 4);
+    assert_eq!(p.degree(x), 3);
+
+    // Expr::diffも多項式ならPoly経由でlogを作らない
+    let res = expr().parse("(x + y)^4 * x", e);
+    let d = match res {
+        Ok((_, _, (expr, env))) => expr.diff("x", env),
+        Err(_) => panic!(""),
+    };
+    assert!(!contains_log(&d));
+    assert_eq!(
+        Poly::from_expr(&d),
+        Ok(parse_poly("4*(x+y)^3*x + (x+y)^4", e))
+    );
+    // 多項式でなくても定数冪ならlogを作らない
+    let res = expr().parse("sin(x^2)", e);
+    let d = match res {
+        Ok((_, _, (expr, env))) => expr.diff("x", env).reduce(env),
+        Err(_) => panic!(""),
+    };
+    assert!(!contains_log(&d));
+    assert_eq!(d.eval("x", &vec![1.], e), 2. * 1f64.cos());
+}
+
+#[test]
+fn poly_gcd() {
+    let e = &Environment::new();
+    let a = parse_poly("x^2 - 1", e);
+    let b = parse_poly("x^2 - 2*x + 1", e);
+    assert_eq!(a.gcd(&b), parse_poly("x - 1", e));
+    assert_eq!(a.gcd(&Poly::zero()), a);
+    assert_eq!(
+        parse_poly("2*x + 2", e).gcd(&parse_poly("4*x + 4", e)),
+        parse_poly("x + 1", e)
+    );
+    let a = parse_poly("(x + y)^2 * (x - y) * (y + 1)", e);
+    let b = parse_poly("(x + y) * (x^2 + y) * (y + 1)^2", e);
+    assert_eq!(a.gcd(&b), parse_poly("(x + y) * (y + 1)", e));
+    assert_eq!(
+        a.div_exact(&parse_poly("x + y", e)),
+        Some(parse_poly("(x + y) * (x - y) * (y + 1)", e))
+    );
+    assert_eq!(a.div_exact(&parse_poly("x + 2", e)), None);
+    assert_eq!(
+        parse_poly("x + y", e).gcd(&parse_poly("x - y", e)),
+        Poly::one()
+    );
+    // 擬剰余の係数が膨らまない
+    let a = parse_poly("(x^6 + 3*x^5 - 2*x^2 + 7) * (x^2 + 1)", e);
+    let b = parse_poly("(x^5 - 4*x^3 + x + 2) * (x^2 + 1)", e);
+    assert_eq!(a.gcd(&b), parse_poly("x^2 + 1", e));
+}
+
+#[test]
+fn poly_square_free() {
+    let e = &Environment::new();
+    let p = parse_poly("3 * (x + 1)^2 * (x - 2)^3 * x", e);
+    let (c, factors) = p.square_free();
+    assert_eq!(c, C::from(3));
+    assert_eq!(
+        factors,
+        vec![
+            (parse_poly("x", e), 1),
+            (parse_poly("x + 1", e), 2),
+            (parse_poly("x - 2", e), 3)
+        ]
+    );
+    let p = parse_poly("(-1) * (x*y + 1)^2 * (y - 1)^2 * (x + y)", e);
+    let (c, factors) = p.square_free();
+    assert_eq!(c, -C::one());
+    assert_eq!(
+        factors,
+        vec![
+            (parse_poly("x + y", e), 1),
+            (parse_poly("(x*y + 1) * (y - 1)", e), 2)
+        ]
+    );
+}
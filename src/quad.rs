@@ -0,0 +1,343 @@
+#[cfg(test)]
+use super::expr::Environment;
+#[cfg(test)]
+use super::expr::C;
+use super::expr::{parse_vars, Env, Expr, Var};
+#[cfg(test)]
+use super::parse::*;
+
+// 数値積分の結果. errorは推定誤差
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quadrature {
+    pub value: f64,
+    pub error: f64,
+    pub evals: usize,
+    pub converged: bool,
+}
+
+// 区間の分割数の上限
+const MAX_INTERVALS: usize = 500;
+// Simpson法の再帰の深さの上限
+const MAX_DEPTH: usize = 50;
+// Simpson法の評価回数の上限
+const MAX_EVALS: usize = 100_000;
+
+// Gauss-Kronrod 15点. XGK[1], XGK[3], XGK[5], XGK[7] がGauss 7点
+const XGK: [f64; 8] = [
+    0.991_455_371_120_812_6,
+    0.949_107_912_342_758_5,
+    0.864_864_423_359_769_1,
+    0.741_531_185_599_394_4,
+    0.586_087_235_467_691_1,
+    0.405_845_151_377_397_2,
+    0.207_784_955_007_898_5,
+    0.,
+];
+const WGK: [f64; 8] = [
+    0.022_935_322_010_529_22,
+    0.063_092_092_629_978_55,
+    0.104_790_010_322_250_2,
+    0.140_653_259_715_525_9,
+    0.169_004_726_639_267_9,
+    0.190_350_578_064_785_4,
+    0.204_432_940_075_298_9,
+    0.209_482_141_084_727_8,
+];
+const WG: [f64; 4] = [
+    0.129_484_966_168_869_7,
+    0.279_705_391_489_276_7,
+    0.381_830_050_505_118_9,
+    0.417_959_183_673_469_4,
+];
+
+// 積分変数の値だけ差し替えてeval_internalする. 変数列は一度だけ作る
+struct Sampler<'a> {
+    expr: &'a Expr,
+    vars: Vec<Var>,
+    vals: Vec<f64>,
+    slots: Vec<Option<usize>>,
+    evals: usize,
+}
+
+impl<'a> Sampler<'a> {
+    fn new(expr: &'a Expr, vs: &str, vars: &str, vals: &[f64], env: &Env) -> Self {
+        let fixed = if vars.trim().is_empty() {
+            vec![]
+        } else {
+            parse_vars(vars, env)
+        };
+        assert!(
+            vals.len() == fixed.len(),
+            "number of variables and values differ"
+        );
+        // envにない積分変数は式に現れないので値を置かない
+        let ivars: Vec<Option<Var>> = vs
+            .split_whitespace()
+            .map(|v| env.borrow().search_var(&String::from(v)))
+            .collect();
+        let mut all: Vec<(Var, f64)> = fixed.into_iter().zip(vals.iter().cloned()).collect();
+        all.extend(ivars.iter().flatten().map(|v| (*v, 0.)));
+        all.sort_by_key(|a| a.0);
+        let vars: Vec<Var> = all.iter().map(|(v, _)| *v).collect();
+        let slots = ivars
+            .iter()
+            .map(|v| v.map(|v| vars.binary_search(&v).unwrap()))
+            .collect();
+        Sampler {
+            expr,
+            vals: all.iter().map(|(_, x)| *x).collect(),
+            vars,
+            slots,
+            evals: 0,
+        }
+    }
+
+    fn set(&mut self, dim: usize, x: f64) {
+        if let Some(s) = self.slots[dim] {
+            self.vals[s] = x;
+        }
+    }
+
+    fn eval(&mut self, dim: usize, x: f64) -> f64 {
+        self.set(dim, x);
+        self.evals += 1;
+        self.expr.eval_internal(&self.vars, &self.vals)
+    }
+}
+
+// [a, b] でのKronrod 15点の値と, Gauss 7点との差. fは (値, 内側の誤差) を返す
+fn gk15(f: &mut dyn FnMut(f64) -> (f64, f64), a: f64, b: f64) -> (f64, f64) {
+    let (c, h) = ((a + b) / 2., (b - a) / 2.);
+    let (mut k, mut g, mut inner) = (0., 0., 0.);
+    for i in 0..8 {
+        let pts = if i == 7 {
+            vec![c]
+        } else {
+            vec![c - h * XGK[i], c + h * XGK[i]]
+        };
+        for x in pts {
+            let (y, e) = f(x);
+            k += WGK[i] * y;
+            inner += WGK[i] * e;
+            if i % 2 == 1 {
+                g += WG[i / 2] * y;
+            }
+        }
+    }
+    (k * h, ((k - g) * h).abs() + inner * h.abs())
+}
+
+// 誤差の最も大きい区間を二分し続ける
+fn adaptive_gk(f: &mut dyn FnMut(f64) -> (f64, f64), a: f64, b: f64, tol: f64) -> (f64, f64, bool) {
+    let (v, e) = gk15(f, a, b);
+    let mut intervals = vec![(a, b, v, e)];
+    loop {
+        let err: f64 = intervals.iter().map(|i| i.3).sum();
+        let value: f64 = intervals.iter().map(|i| i.2).sum();
+        if err <= tol || !err.is_finite() || MAX_INTERVALS <= intervals.len() {
+            return (value, err, err <= tol);
+        }
+        let worst = (0..intervals.len())
+            .max_by(|i, j| intervals[*i].3.partial_cmp(&intervals[*j].3).unwrap())
+            .unwrap();
+        let (a, b, _, _) = intervals.swap_remove(worst);
+        let m = (a + b) / 2.;
+        let (v1, e1) = gk15(f, a, m);
+        let (v2, e2) = gk15(f, m, b);
+        intervals.push((a, m, v1, e1));
+        intervals.push((m, b, v2, e2));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn simpson(
+    s: &mut Sampler,
+    a: f64,
+    b: f64,
+    fa: f64,
+    fm: f64,
+    fb: f64,
+    whole: f64,
+    tol: f64,
+    depth: usize,
+) -> (f64, f64, bool) {
+    let m = (a + b) / 2.;
+    let (lm, rm) = ((a + m) / 2., (m + b) / 2.);
+    let (flm, frm) = (s.eval(0, lm), s.eval(0, rm));
+    let left = (m - a) / 6. * (fa + 4. * flm + fm);
+    let right = (b - m) / 6. * (fm + 4. * frm + fb);
+    let diff = left + right - whole;
+    // 上限に達したら残りの区間は分割せずに打ち切る
+    if diff.abs() <= 15. * tol || depth == 0 || MAX_EVALS <= s.evals {
+        // Richardson補外
+        return (
+            left + right + diff / 15.,
+            diff.abs() / 15.,
+            diff.abs() <= 15. * tol,
+        );
+    }
+    let (v1, e1, c1) = simpson(s, a, m, fa, flm, fm, left, tol / 2., depth - 1);
+    let (v2, e2, c2) = simpson(s, m, b, fm, frm, fb, right, tol / 2., depth - 1);
+    (v1 + v2, e1 + e2, c1 && c2)
+}
+
+fn nested_gk(s: &mut Sampler, dim: usize, bounds: &[(f64, f64)], tol: f64) -> (f64, f64, bool) {
+    let (a, b) = bounds[dim];
+    if dim + 1 == bounds.len() {
+        return adaptive_gk(&mut |x| (s.eval(dim, x), 0.), a, b, tol);
+    }
+    // 内側の許容誤差は外側の幅で割っておく
+    let inner_tol = tol / (2. * (b - a).abs().max(1.));
+    let mut converged = true;
+    let res = adaptive_gk(
+        &mut |x| {
+            s.set(dim, x);
+            let (v, e, c) = nested_gk(s, dim + 1, bounds, inner_tol);
+            converged &= c;
+            (v, e)
+        },
+        a,
+        b,
+        tol / 2.,
+    );
+    (res.0, res.1, res.2 && converged)
+}
+
+impl Expr {
+    // vについて [a, b] で適応的Gauss-Kronrod積分する. 他の変数は vars, vals で固定する
+    #[allow(clippy::too_many_arguments)]
+    pub fn quad_gk(
+        &self,
+        v: &str,
+        a: f64,
+        b: f64,
+        vars: &str,
+        vals: &[f64],
+        tol: f64,
+        env: &Env,
+    ) -> Quadrature {
+        self.quad_box(v, &[(a, b)], vars, vals, tol, env)
+    }
+
+    // 適応的Simpson法
+    #[allow(clippy::too_many_arguments)]
+    pub fn quad_simpson(
+        &self,
+        v: &str,
+        a: f64,
+        b: f64,
+        vars: &str,
+        vals: &[f64],
+        tol: f64,
+        env: &Env,
+    ) -> Quadrature {
+        let mut s = Sampler::new(self, v, vars, vals, env);
+        let (fa, fm, fb) = (s.eval(0, a), s.eval(0, (a + b) / 2.), s.eval(0, b));
+        let whole = (b - a) / 6. * (fa + 4. * fm + fb);
+        let (value, error, converged) = simpson(&mut s, a, b, fa, fm, fb, whole, tol, MAX_DEPTH);
+        Quadrature {
+            value,
+            error,
+            evals: s.evals,
+            converged,
+        }
+    }
+
+    // 直方体上の多重積分. vs の i 番目の変数を bounds[i] の範囲で積分する
+    pub fn quad_box(
+        &self,
+        vs: &str,
+        bounds: &[(f64, f64)],
+        vars: &str,
+        vals: &[f64],
+        tol: f64,
+        env: &Env,
+    ) -> Quadrature {
+        let mut s = Sampler::new(self, vs, vars, vals, env);
+        assert!(
+            s.slots.len() == bounds.len(),
+            "number of variables and bounds differ"
+        );
+        let (value, error, converged) = nested_gk(&mut s, 0, bounds, tol);
+        Quadrature {
+            value,
+            error,
+            evals: s.evals,
+            converged,
+        }
+    }
+}
+
+#[test]
+fn quadrature_one_dim() {
+    let e = &Environment::new();
+    let pi = std::f64::consts::PI;
+    let sin = parse_expr("sin(x)", e);
+    for q in [
+        sin.quad_gk("x", 0., pi, "", &[], 1e-12, e),
+        sin.quad_simpson("x", 0., pi, "", &[], 1e-12, e),
+    ]
+    .iter()
+    {
+        assert!(q.converged);
+        assert!((q.value - 2.).abs() <= q.error.max(1e-12));
+    }
+
+    // 記号的に積分できないもの
+    let f = parse_expr("exp(0 - x^2)", e);
+    let exact = 0.746_824_132_812_427;
+    let q = f.quad_gk("x", 0., 1., "", &[], 1e-10, e);
+    assert!((q.value - exact).abs() < 1e-10 && q.error < 1e-10);
+    let q = f.quad_simpson("x", 0., 1., "", &[], 1e-10, e);
+    assert!((q.value - exact).abs() < 1e-10 && q.error < 1e-10);
+
+    // 記号積分と一致する. 他の変数は名前で束縛する
+    let f = parse_expr("y * (x^3 + 1) / (x^2 + x)", e);
+    let g = f
+        .integrate_definite("x", C::new(1, 1), C::new(3, 1), e)
+        .unwrap();
+    let q = f.quad_gk("x", 1., 3., "y", &[2.], 1e-10, e);
+    let exact = g.eval("y", &vec![2.], e);
+    assert!((q.value - exact).abs() < 1e-9, "{} {}", q.value, exact);
+
+    // 端点の特異性も分割で追い込む
+    let q = parse_expr("1 / x^(1/2)", e).quad_gk("x", 0., 1., "", &[], 1e-6, e);
+    assert!((q.value - 2.).abs() < 1e-5, "{:?}", q);
+
+    // 達成できない許容誤差では評価回数の上限で止まる
+    let q = sin.quad_simpson("x", 0., pi, "", &[], 1e-17, e);
+    assert!(
+        !q.converged && q.evals <= MAX_EVALS + 2 * MAX_DEPTH,
+        "{:?}",
+        q
+    );
+    assert!((q.value - 2.).abs() <= q.error, "{:?}", q);
+}
+
+#[test]
+fn quadrature_box() {
+    let e = &Environment::new();
+    let f = parse_expr("x * y^2 + z", e);
+    let q = f.quad_box("x y", &[(0., 1.), (0., 2.)], "z", &[0.5], 1e-10, e);
+    assert!(q.converged);
+    assert!((q.value - (4. / 3. + 1.)).abs() < 1e-10, "{:?}", q);
+
+    let f = parse_expr("exp(x + y + z)", e);
+    let q = f.quad_box("x y z", &[(0., 1.), (0., 1.), (0., 1.)], "", &[], 1e-8, e);
+    let exact = (1f64.exp() - 1.).powi(3);
+    assert!((q.value - exact).abs() < 1e-8, "{:?}", q);
+
+    // 式に現れない積分変数はenvに加えない
+    let n = e.borrow().vars.len();
+    let q = parse_expr("x", e).quad_box("x w", &[(0., 1.), (0., 3.)], "", &[], 1e-10, e);
+    assert!((q.value - 1.5).abs() < 1e-10, "{:?}", q);
+    assert_eq!(e.borrow().vars.len(), n);
+    assert!(e.borrow().search_var(&String::from("w")).is_none());
+}
+
+#[test]
+#[should_panic(expected = "number of variables and values differ")]
+fn quadrature_missing_value() {
+    let e = &Environment::new();
+    parse_expr("x * y", e).quad_gk("x", 0., 1., "y", &[], 1e-10, e);
+}
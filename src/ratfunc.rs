@@ -0,0 +1,198 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Bop, Env, Expr, One, Uop, Zero, C};
+#[cfg(test)]
+use super::parse::*;
+use super::poly::{NotPolynomial, Poly};
+use std::rc::Rc;
+
+// 既約な有理関数 num / den. denは整数係数で原始的, 先頭係数が正
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RatFunc {
+    pub num: Poly,
+    pub den: Poly,
+}
+
+impl RatFunc {
+    pub fn new(num: Poly, den: Poly) -> Self {
+        if den.is_zero() {
+            panic!("zero div")
+        }
+        let g = num.gcd(&den);
+        let (num, den) = (num.div_exact(&g).unwrap(), den.div_exact(&g).unwrap());
+        let k = C::one() / den.rat_content();
+        RatFunc {
+            num: num.scale(k),
+            den: den.scale(k),
+        }
+    }
+
+    pub fn from_poly(p: Poly) -> Self {
+        RatFunc {
+            num: p,
+            den: Poly::one(),
+        }
+    }
+
+    pub fn add(&self, other: &RatFunc) -> RatFunc {
+        let num = &(&self.num * &other.den) + &(&other.num * &self.den);
+        RatFunc::new(num, &self.den * &other.den)
+    }
+
+    pub fn sub(&self, other: &RatFunc) -> RatFunc {
+        self.add(&other.neg())
+    }
+
+    pub fn neg(&self) -> RatFunc {
+        RatFunc {
+            num: -&self.num,
+            den: self.den.clone(),
+        }
+    }
+
+    pub fn mul(&self, other: &RatFunc) -> RatFunc {
+        RatFunc::new(&self.num * &other.num, &self.den * &other.den)
+    }
+
+    pub fn div(&self, other: &RatFunc) -> RatFunc {
+        RatFunc::new(&self.num * &other.den, &self.den * &other.num)
+    }
+
+    pub fn pow(&self, n: i64) -> RatFunc {
+        let k = n.unsigned_abs() as u32;
+        if n < 0 {
+            RatFunc::new(self.den.pow(k), self.num.pow(k))
+        } else {
+            RatFunc::new(self.num.pow(k), self.den.pow(k))
+        }
+    }
+
+    pub fn from_expr(expr: &Expr) -> Result<RatFunc, NotPolynomial> {
+        match expr {
+            Expr::Num(_) | Expr::Var(_) => Ok(RatFunc::from_poly(Poly::from_expr(expr)?)),
+            Expr::UnOp { op: Uop::Neg, exp } => Ok(RatFunc::from_expr(exp)?.neg()),
+            Expr::UnOp { .. } => Err(NotPolynomial(expr.clone())),
+            Expr::BinOp { op, exp1, exp2 } => {
+                let left = RatFunc::from_expr(exp1)?;
+                match op {
+                    Bop::Add => Ok(left.add(&RatFunc::from_expr(exp2)?)),
+                    Bop::Sub => Ok(left.sub(&RatFunc::from_expr(exp2)?)),
+                    Bop::Mul => Ok(left.mul(&RatFunc::from_expr(exp2)?)),
+                    Bop::Div => {
+                        let right = RatFunc::from_expr(exp2)?;
+                        if right.num.is_zero() {
+                            panic!("zero div")
+                        }
+                        Ok(left.div(&right))
+                    }
+                    // 指数がu32に収まらなければ扱わない
+                    Bop::Pow => match **exp2 {
+                        Expr::Num(n)
+                            if n.is_integer() && n.numer().unsigned_abs() <= u32::MAX as u64 =>
+                        {
+                            Ok(left.pow(*n.numer()))
+                        }
+                        _ => Err(NotPolynomial(expr.clone())),
+                    },
+                }
+            }
+        }
+    }
+
+    pub fn to_expr(&self, env: &Env) -> Rc<Expr> {
+        match self.den.constant_value() {
+            Some(c) => self.num.scale(C::one() / c).to_expr(env),
+            None => Expr::new_binop(Bop::Div, self.num.to_expr(env), self.den.to_expr(env), env),
+        }
+    }
+}
+
+// 有理関数として扱えない部分はそのままにして, 子に f を適用し直す
+fn map_children(expr: &Expr, env: &Env, f: &dyn Fn(&Expr) -> Rc<Expr>) -> Rc<Expr> {
+    match expr {
+        Expr::UnOp { op, exp } => Expr::new_unop(*op, f(exp), env),
+        Expr::BinOp { op, exp1, exp2 } => Expr::new_binop(*op, f(exp1), f(exp2), env),
+        _ => env.borrow_mut().extend_expr(expr.clone()),
+    }
+}
+
+impl Expr {
+    // 有理関数を既約な num / den にする. num, denは展開される
+    pub fn cancel(&self, env: &Env) -> Rc<Expr> {
+        match RatFunc::from_expr(self) {
+            Ok(r) => r.to_expr(env),
+            Err(_) => map_children(self, env, &|c| c.cancel(env)).reduce(env),
+        }
+    }
+
+    // 通分して既約にする. 分母は無平方分解した形で残す
+    pub fn together(&self, env: &Env) -> Rc<Expr> {
+        let r = match RatFunc::from_expr(self) {
+            Ok(r) => r,
+            Err(_) => return map_children(self, env, &|c| c.together(env)).reduce(env),
+        };
+        if r.den.constant_value().is_some() {
+            return r.to_expr(env);
+        }
+        let (c, factors) = r.den.square_free();
+        let mut den: Option<Rc<Expr>> = None;
+        for (f, i) in factors {
+            let mut f = f.to_expr(env);
+            if 1 < i {
+                f = Expr::new_binop(Bop::Pow, f, Expr::new_num(i64::from(i), env), env);
+            }
+            den = Some(match den {
+                Some(d) => Expr::new_binop(Bop::Mul, d, f, env),
+                None => f,
+            });
+        }
+        let num = r.num.scale(C::one() / c).to_expr(env);
+        match den {
+            Some(d) if !c.is_zero() => Expr::new_binop(Bop::Div, num, d, env),
+            _ => num,
+        }
+    }
+}
+
+#[test]
+fn cancel_rational() {
+    let e = &Environment::new();
+    let same = |s: &str, t: &str| {
+        assert_eq!(
+            parse_expr(s, e).cancel(e),
+            parse_expr(t, e).reduce(e),
+            "{}",
+            s
+        )
+    };
+    same("(x^2 - 1) / (x - 1)", "x + 1");
+    same("(x^2 - y^2) / (2*x + 2*y)", "1/2*x - 1/2*y");
+    same("1/x + 1/y", "(x + y) / (x*y)");
+    same("(x^3 - x) / (x^2 - 2*x + 1)", "(x^2 + x) / (x - 1)");
+    same("sin((x^2 - 1) / (x + 1)) + 1", "sin(x - 1) + 1");
+    let f = parse_expr("(x + 1) ^ 4294967297", e);
+    assert!(RatFunc::from_expr(&f).is_err());
+    // 互いに素な分母の和でも係数があふれない
+    let f = "(x^3 + 2*x + 5) / (x^4 - 3*x^3 + x - 7) + (x^2 - 1) / (x^3 + 4*x - 9)";
+    let c = parse_expr(f, e).cancel(e);
+    let x = String::from("x");
+    let (v, w) = (
+        c.eval(&x, &vec![2.], e),
+        parse_expr(f, e).eval(&x, &vec![2.], e),
+    );
+    assert!((v - w).abs() < 1e-12 * w.abs(), "{} {}", v, w);
+
+    // 商の微分が小さくなる
+    let d = parse_expr("(x^2 - 1) / (x - 1)", e).diff("x", e).reduce(e);
+    assert_eq!(d.cancel(e), Expr::new_num(1, e));
+}
+
+#[test]
+fn together_rational() {
+    let e = &Environment::new();
+    let r = parse_expr("1/(x + 1)^2 + 1/(x + 1)", e).together(e);
+    assert_eq!(r, parse_expr("(x + 2) / (x + 1)^2", e));
+    let r = parse_expr("1/x - 1/(x + 1)", e).together(e);
+    assert_eq!(r, parse_expr("1 / (x^2 + x)", e));
+    assert_eq!(3., r.eval("x", &vec![1.], e) * 6.);
+}
@@ -0,0 +1,273 @@
+#[cfg(test)]
+use super::complex::Complex;
+#[cfg(test)]
+use super::diff::Deriv;
+#[cfg(test)]
+use super::dual::Dual;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Bop, Env, Expr, Uop, Var, C};
+#[cfg(test)]
+use super::interval::Interval;
+#[cfg(test)]
+use super::parse::*;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+// 式を評価できる数. 四則演算と初等関数があれば自前の型も使える
+pub trait Scalar:
+    Clone
+    + PartialEq
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    // 有理数の定数. できるだけ正確に変換する
+    fn from_rat(c: C) -> Self;
+    fn sin(&self) -> Self;
+    fn cos(&self) -> Self;
+    fn tan(&self) -> Self;
+    fn ln(&self) -> Self;
+    fn exp(&self) -> Self;
+    fn pow(&self, other: &Self) -> Self;
+
+    fn zero() -> Self {
+        Self::from_rat(C::new(0, 1))
+    }
+
+    fn one() -> Self {
+        Self::from_rat(C::new(1, 1))
+    }
+}
+
+impl Scalar for f64 {
+    fn from_rat(c: C) -> Self {
+        *c.numer() as f64 / *c.denom() as f64
+    }
+    fn sin(&self) -> Self {
+        f64::sin(*self)
+    }
+    fn cos(&self) -> Self {
+        f64::cos(*self)
+    }
+    fn tan(&self) -> Self {
+        f64::tan(*self)
+    }
+    fn ln(&self) -> Self {
+        f64::ln(*self)
+    }
+    fn exp(&self) -> Self {
+        f64::exp(*self)
+    }
+    fn pow(&self, other: &Self) -> Self {
+        self.powf(*other)
+    }
+}
+
+impl Scalar for f32 {
+    fn from_rat(c: C) -> Self {
+        (*c.numer() as f64 / *c.denom() as f64) as f32
+    }
+    fn sin(&self) -> Self {
+        f32::sin(*self)
+    }
+    fn cos(&self) -> Self {
+        f32::cos(*self)
+    }
+    fn tan(&self) -> Self {
+        f32::tan(*self)
+    }
+    fn ln(&self) -> Self {
+        f32::ln(*self)
+    }
+    fn exp(&self) -> Self {
+        f32::exp(*self)
+    }
+    fn pow(&self, other: &Self) -> Self {
+        self.powf(*other)
+    }
+}
+
+impl Expr {
+    pub fn eval_generic<T: Scalar>(&self, vars: &str, vals: &[T], e: &Env) -> T {
+        self.eval_generic_internal(&parse_vars(vars, e), vals)
+    }
+
+    // eval_internal と同じく vars はソート済みで vals と対応する
+    pub fn eval_generic_internal<T: Scalar>(&self, vars: &[Var], vals: &[T]) -> T {
+        match self {
+            Expr::UnOp { op, exp } => {
+                let a = exp.eval_generic_internal(vars, vals);
+                match op {
+                    Uop::Sin => a.sin(),
+                    Uop::Cos => a.cos(),
+                    Uop::Tan => a.tan(),
+                    Uop::Log => a.ln(),
+                    Uop::Exp => a.exp(),
+                    Uop::Neg => -a,
+                }
+            }
+            Expr::BinOp { op, exp1, exp2 } => {
+                let a = exp1.eval_generic_internal(vars, vals);
+                let b = exp2.eval_generic_internal(vars, vals);
+                match op {
+                    Bop::Add => a + b,
+                    Bop::Sub => a - b,
+                    Bop::Mul => a * b,
+                    Bop::Div => a / b,
+                    Bop::Pow => a.pow(&b),
+                }
+            }
+            Expr::Var(vt) => match vars.binary_search(vt) {
+                Ok(i) => vals[i].clone(),
+                Err(_) => panic!("var {} is not specified", vt.id),
+            },
+            Expr::Num(n) => T::from_rat(*n),
+        }
+    }
+}
+
+// 小数部32bitの固定小数点数. 初等関数は f64 を経由する
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fixed(i64);
+
+#[cfg(test)]
+impl Fixed {
+    const ONE: f64 = (1u64 << 32) as f64;
+    fn from_f64(x: f64) -> Self {
+        Fixed((x * Fixed::ONE).round() as i64)
+    }
+    fn to_f64(self) -> f64 {
+        self.0 as f64 / Fixed::ONE
+    }
+}
+
+#[cfg(test)]
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0 + other.0)
+    }
+}
+
+#[cfg(test)]
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0 - other.0)
+    }
+}
+
+#[cfg(test)]
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(-self.0)
+    }
+}
+
+#[cfg(test)]
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((self.0 as i128 * other.0 as i128) >> 32) as i64)
+    }
+}
+
+#[cfg(test)]
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed((((self.0 as i128) << 32) / other.0 as i128) as i64)
+    }
+}
+
+#[cfg(test)]
+impl Scalar for Fixed {
+    fn from_rat(c: C) -> Self {
+        Fixed((((*c.numer() as i128) << 32) / *c.denom() as i128) as i64)
+    }
+    fn sin(&self) -> Self {
+        Fixed::from_f64(self.to_f64().sin())
+    }
+    fn cos(&self) -> Self {
+        Fixed::from_f64(self.to_f64().cos())
+    }
+    fn tan(&self) -> Self {
+        Fixed::from_f64(self.to_f64().tan())
+    }
+    fn ln(&self) -> Self {
+        Fixed::from_f64(self.to_f64().ln())
+    }
+    fn exp(&self) -> Self {
+        Fixed::from_f64(self.to_f64().exp())
+    }
+    fn pow(&self, other: &Self) -> Self {
+        Fixed::from_f64(self.to_f64().powf(other.to_f64()))
+    }
+}
+
+#[test]
+fn generic_eval() {
+    let e = &Environment::new();
+    let f = parse_expr("sin(x * y) + exp(x) / (y^2 + 1) - log(y) * x^3", e);
+    let (x, y) = (0.7, 1.3);
+    let v = f.eval("x y", &vec![x, y], e);
+    assert_eq!(f.eval_generic("x y", &[x, y], e), v);
+    let v32 = f.eval_generic("x y", &[x as f32, y as f32], e);
+    assert!((v32 as f64 - v).abs() < 1e-6);
+
+    // 他の評価と一致する
+    let c = f.eval_generic("x y", &[Complex::real(x), Complex::real(y)], e);
+    assert!((c.re - v).abs() < 1e-14 && c.im == 0.);
+    let iv = f.eval_generic("x y", &[Interval::point(x), Interval::point(y)], e);
+    assert!(iv.contains(v) && iv.width() < 1e-14);
+    assert_eq!(
+        iv,
+        f.eval_interval("x y", &[Interval::point(x), Interval::point(y)], e)
+    );
+    let d = f.eval_generic("x y", &[Dual { re: x, du: 1. }, Dual { re: y, du: 0. }], e);
+    assert_eq!(d, f.dual_eval("x y", &[x, y], &[1., 0.], e));
+
+    // 固定小数点数
+    let fx = f.eval_generic("x y", &[Fixed::from_f64(x), Fixed::from_f64(y)], e);
+    assert!((fx.to_f64() - v).abs() < 1e-8, "{} {}", fx.to_f64(), v);
+    assert_eq!(Fixed::from_rat(C::new(3, 4)).to_f64(), 0.75);
+}
+
+#[test]
+fn generic_deriv() {
+    let e = &Environment::new();
+    let f = parse_expr("x^2 * y + sin(x * y) / (1 + y^2)", e);
+    let d = Deriv::new(f, e, "x");
+    let vars = parse_vars("x y", e);
+    let (x, y) = (0.4, -1.1);
+    let g = d.backward_grad("x y", &vec![x, y], e);
+    assert_eq!(d.backward_grad_generic(&vars, &[x, y]), g);
+    let g32 = d.backward_grad_generic(&vars, &[x as f32, y as f32]);
+    assert!(g32
+        .iter()
+        .zip(&g)
+        .all(|(a, b)| (*a as f64 - b).abs() < 1e-5));
+    let xv = vars[0];
+    assert_eq!(
+        d.forward_eval_generic(xv, &vars, &[x, y]),
+        d.forward_eval(xv, "x y", &vec![x, y], e)
+    );
+
+    // 双対数で流すと勾配の方向微分, つまりHessianとベクトルの積になる
+    let dir = [0.5, 2.];
+    let duals = [Dual { re: x, du: dir[0] }, Dual { re: y, du: dir[1] }];
+    let gd = d.backward_grad_generic(&vars, &duals);
+    let hv = d.hvp_internal(&vars, &[x, y], &dir);
+    for k in 0..2 {
+        assert!((gd[k].re - g[k]).abs() < 1e-14 && (gd[k].du - hv[k]).abs() < 1e-12);
+    }
+    let fx = d.backward_grad_generic(&vars, &[Fixed::from_f64(x), Fixed::from_f64(y)]);
+    assert!(fx
+        .iter()
+        .zip(&g)
+        .all(|(a, b)| (a.to_f64() - b).abs() < 1e-7));
+}
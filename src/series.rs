@@ -0,0 +1,367 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Bop, Env, Expr, HashMap, Rc, Uop, Var, C};
+#[cfg(test)]
+use super::parse::*;
+#[cfg(test)]
+use super::poly::Poly;
+
+// (v - point)^k の係数 a_k を並べた打ち切り冪級数
+type Series = Vec<Rc<Expr>>;
+
+// 級数を求められなかった式. 0で割ることになった
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoSeries(pub Expr);
+
+// 係数の演算はすべてreduceする. 有理数があふれる係数は定数の式のまま残り, 0で割るときはNone
+fn arith(op: Bop, a: &Rc<Expr>, b: &Rc<Expr>, env: &Env) -> Option<Rc<Expr>> {
+    if op == Bop::Div && b.is_zero() {
+        return None;
+    }
+    Some(Expr::new_binop(op, a.clone(), b.clone(), env).reduce(env))
+}
+
+fn add(a: &Rc<Expr>, b: &Rc<Expr>, env: &Env) -> Option<Rc<Expr>> {
+    arith(Bop::Add, a, b, env)
+}
+
+fn sub(a: &Rc<Expr>, b: &Rc<Expr>, env: &Env) -> Option<Rc<Expr>> {
+    arith(Bop::Sub, a, b, env)
+}
+
+fn mul(a: &Rc<Expr>, b: &Rc<Expr>, env: &Env) -> Option<Rc<Expr>> {
+    arith(Bop::Mul, a, b, env)
+}
+
+fn div(a: &Rc<Expr>, b: &Rc<Expr>, env: &Env) -> Option<Rc<Expr>> {
+    arith(Bop::Div, a, b, env)
+}
+
+fn scale(c: C, a: &Rc<Expr>, env: &Env) -> Option<Rc<Expr>> {
+    mul(&Expr::new_num_from_rat(c, env), a, env)
+}
+
+fn unop(op: Uop, a: &Rc<Expr>, env: &Env) -> Rc<Expr> {
+    Expr::new_unop(op, a.clone(), env).reduce(env)
+}
+
+struct Taylor<'a> {
+    v: Var,
+    point: Rc<Expr>,
+    order: usize,
+    env: &'a Env,
+    memo: HashMap<*const Expr, Series>,
+}
+
+impl<'a> Taylor<'a> {
+    fn zero(&self) -> Rc<Expr> {
+        Expr::new_num(0, self.env)
+    }
+
+    fn constant(&self, c: Rc<Expr>) -> Series {
+        let mut res = vec![self.zero(); self.order + 1];
+        res[0] = c;
+        res
+    }
+
+    // Σ_{j=1..k} j a_j b_{k-j}
+    fn weighted_conv(&self, a: &Series, b: &Series, k: usize) -> Option<Rc<Expr>> {
+        let env = self.env;
+        (1..=k).try_fold(self.zero(), |acc, j| {
+            let t = mul(&a[j], &b[k - j], env)?;
+            add(&acc, &scale(C::from(j as i64), &t, env)?, env)
+        })
+    }
+
+    fn mul(&self, a: &Series, b: &Series) -> Option<Series> {
+        let env = self.env;
+        (0..=self.order)
+            .map(|k| {
+                (0..=k).try_fold(self.zero(), |acc, j| {
+                    add(&acc, &mul(&a[j], &b[k - j], env)?, env)
+                })
+            })
+            .collect()
+    }
+
+    // c = a / b: c_k = (a_k - Σ_{j=1..k} b_j c_{k-j}) / b_0
+    fn div(&self, a: &Series, b: &Series) -> Option<Series> {
+        let env = self.env;
+        let mut c: Series = vec![];
+        for k in 0..=self.order {
+            let s = (1..=k).try_fold(a[k].clone(), |acc, j| {
+                sub(&acc, &mul(&b[j], &c[k - j], env)?, env)
+            })?;
+            c.push(div(&s, &b[0], env)?);
+        }
+        Some(c)
+    }
+
+    // e = exp(a): e_k = (1/k) Σ j a_j e_{k-j}
+    fn exp(&self, a: &Series) -> Option<Series> {
+        let mut e = vec![unop(Uop::Exp, &a[0], self.env)];
+        for k in 1..=self.order {
+            let s = self.weighted_conv(a, &e, k)?;
+            e.push(scale(C::new(1, k as i64), &s, self.env)?);
+        }
+        Some(e)
+    }
+
+    // l = log(a): l_k = (a_k - (1/k) Σ_{j=1..k-1} j l_j a_{k-j}) / a_0
+    fn log(&self, a: &Series) -> Option<Series> {
+        let env = self.env;
+        let mut l = vec![unop(Uop::Log, &a[0], env)];
+        for k in 1..=self.order {
+            // l_k はまだ0として畳み込む
+            l.push(self.zero());
+            let s = scale(C::new(1, k as i64), &self.weighted_conv(&l, a, k)?, env)?;
+            l[k] = div(&sub(&a[k], &s, env)?, &a[0], env)?;
+        }
+        Some(l)
+    }
+
+    // s_k = (1/k) Σ j a_j c_{k-j}, c_k = -(1/k) Σ j a_j s_{k-j}
+    fn sin_cos(&self, a: &Series) -> Option<(Series, Series)> {
+        let env = self.env;
+        let mut s = vec![unop(Uop::Sin, &a[0], env)];
+        let mut c = vec![unop(Uop::Cos, &a[0], env)];
+        for k in 1..=self.order {
+            let sk = scale(C::new(1, k as i64), &self.weighted_conv(a, &c, k)?, env)?;
+            let ck = scale(C::new(-1, k as i64), &self.weighted_conv(a, &s, k)?, env)?;
+            s.push(sk);
+            c.push(ck);
+        }
+        Some((s, c))
+    }
+
+    // t = tan(a): t' = (1 + t^2) a'
+    fn tan(&self, a: &Series) -> Option<Series> {
+        let env = self.env;
+        let mut t = vec![unop(Uop::Tan, &a[0], env)];
+        let mut w = vec![add(&Expr::new_num(1, env), &mul(&t[0], &t[0], env)?, env)?];
+        for k in 1..=self.order {
+            let tk = scale(C::new(1, k as i64), &self.weighted_conv(a, &w, k)?, env)?;
+            t.push(tk);
+            let wk = (0..=k).try_fold(self.zero(), |acc, i| {
+                add(&acc, &mul(&t[i], &t[k - i], env)?, env)
+            })?;
+            w.push(wk);
+        }
+        Some(t)
+    }
+
+    // p = a^r (rは定数): p_k = (1/(k a_0)) Σ ((r+1) j - k) a_j p_{k-j}
+    fn pow_const(&self, a: &Series, r: &Rc<Expr>) -> Option<Series> {
+        let env = self.env;
+        let mut p = vec![Expr::new_binop(Bop::Pow, a[0].clone(), r.clone(), env).reduce(env)];
+        let r1 = add(r, &Expr::new_num(1, env), env)?;
+        for k in 1..=self.order {
+            let s = (1..=k).try_fold(self.zero(), |acc, j| {
+                let f = sub(
+                    &scale(C::from(j as i64), &r1, env)?,
+                    &Expr::new_num(k as i64, env),
+                    env,
+                )?;
+                add(&acc, &mul(&f, &mul(&a[j], &p[k - j], env)?, env)?, env)
+            })?;
+            let den = scale(C::from(k as i64), &a[0], env)?;
+            p.push(div(&s, &den, env)?);
+        }
+        Some(p)
+    }
+
+    fn is_constant(&self, a: &Series) -> bool {
+        a[1..].iter().all(|c| c.is_zero())
+    }
+
+    fn series(&mut self, expr: &Rc<Expr>) -> Result<Series, NoSeries> {
+        let ptr = Rc::as_ptr(expr);
+        if let Some(s) = self.memo.get(&ptr) {
+            return Ok(s.clone());
+        }
+        let env = self.env;
+        let res = match &**expr {
+            Expr::Num(_) => Some(self.constant(expr.clone())),
+            Expr::Var(v) if *v == self.v => {
+                let mut res = self.constant(self.point.clone());
+                if 0 < self.order {
+                    res[1] = Expr::new_num(1, env);
+                }
+                Some(res)
+            }
+            Expr::Var(_) => Some(self.constant(expr.clone())),
+            Expr::UnOp { op, exp } => {
+                let a = self.series(exp)?;
+                match op {
+                    Uop::Neg => Some(a.iter().map(|c| unop(Uop::Neg, c, env)).collect()),
+                    Uop::Exp => self.exp(&a),
+                    Uop::Log => self.log(&a),
+                    Uop::Sin => self.sin_cos(&a).map(|sc| sc.0),
+                    Uop::Cos => self.sin_cos(&a).map(|sc| sc.1),
+                    Uop::Tan => self.tan(&a),
+                }
+            }
+            Expr::BinOp { op, exp1, exp2 } => {
+                let a = self.series(exp1)?;
+                let b = self.series(exp2)?;
+                match op {
+                    Bop::Add => a.iter().zip(&b).map(|(x, y)| add(x, y, env)).collect(),
+                    Bop::Sub => a.iter().zip(&b).map(|(x, y)| sub(x, y, env)).collect(),
+                    Bop::Mul => self.mul(&a, &b),
+                    // 分母の定数項が0なら冪級数にならない
+                    Bop::Div => self.div(&a, &b),
+                    Bop::Pow if self.is_constant(&b) => match *b[0] {
+                        // 非負整数乗は掛け算で求める. a_0 = 0 でもよい
+                        Expr::Num(n) if n.is_integer() && C::from(0) <= n => {
+                            let one = self.constant(Expr::new_num(1, env));
+                            (0..*n.numer()).try_fold(one, |acc, _| self.mul(&acc, &a))
+                        }
+                        _ => self.pow_const(&a, &b[0]),
+                    },
+                    // a^b = exp(b log a)
+                    Bop::Pow => self
+                        .log(&a)
+                        .and_then(|l| self.mul(&b, &l))
+                        .and_then(|m| self.exp(&m)),
+                }
+            }
+        };
+        let res = res.ok_or_else(|| NoSeries((**expr).clone()))?;
+        self.memo.insert(ptr, res.clone());
+        Ok(res)
+    }
+}
+
+impl Expr {
+    // v = point でのTaylor係数 a_0, ..., a_order. n回微分せずに冪級数の演算で求める
+    pub fn taylor_coeffs(
+        self: &Rc<Expr>,
+        v: &str,
+        point: Rc<Expr>,
+        order: usize,
+        env: &Env,
+    ) -> Result<Vec<Rc<Expr>>, NoSeries> {
+        let v = env.borrow_mut().extend_var(String::from(v));
+        let mut t = Taylor {
+            v,
+            point,
+            order,
+            env,
+            memo: HashMap::new(),
+        };
+        t.series(self)
+    }
+
+    // v = point の周りで order 次まで打ち切ったTaylor多項式
+    pub fn series(
+        self: &Rc<Expr>,
+        v: &str,
+        point: Rc<Expr>,
+        order: usize,
+        env: &Env,
+    ) -> Result<Rc<Expr>, NoSeries> {
+        let x = Expr::new_var(String::from(v), env);
+        let h = Expr::new_binop(Bop::Sub, x, point.clone(), env).reduce(env);
+        let coeffs = self.taylor_coeffs(v, point, order, env)?;
+        let mut res = Expr::new_num(0, env);
+        for (k, c) in coeffs.iter().enumerate() {
+            let p = Expr::new_binop(Bop::Pow, h.clone(), Expr::new_num(k as i64, env), env);
+            let t = Expr::new_binop(Bop::Mul, c.clone(), p.reduce(env), env).reduce(env);
+            res = Expr::new_binop(Bop::Add, res, t, env).reduce(env);
+        }
+        Ok(res)
+    }
+
+    pub fn maclaurin(
+        self: &Rc<Expr>,
+        v: &str,
+        order: usize,
+        env: &Env,
+    ) -> Result<Rc<Expr>, NoSeries> {
+        self.series(v, Expr::new_num(0, env), order, env)
+    }
+}
+
+#[test]
+fn maclaurin_series() {
+    let e = &Environment::new();
+    let same = |s: &str, n: usize, t: &str| {
+        let lhs = Poly::from_expr(&parse_expr(s, e).maclaurin("x", n, e).unwrap());
+        assert_eq!(lhs, Poly::from_expr(&parse_expr(t, e)), "{}", s)
+    };
+    same("exp(x)", 4, "1 + x + 1/2*x^2 + 1/6*x^3 + 1/24*x^4");
+    same("sin(x)", 5, "x - 1/6*x^3 + 1/120*x^5");
+    same("tan(x)", 5, "x + 1/3*x^3 + 2/15*x^5");
+    same("log(1 + x)", 3, "x - 1/2*x^2 + 1/3*x^3");
+    same("1 / (1 - x)", 3, "1 + x + x^2 + x^3");
+    same("(1 + x) ^ (1/2)", 2, "1 + 1/2*x - 1/8*x^2");
+    same("x^3 + 2*x", 5, "2*x + x^3");
+    same("sin(x) / cos(x)", 5, "x + 1/3*x^3 + 2/15*x^5");
+
+    // 係数の有理数があふれる高次でも求まる
+    let s = parse_expr("exp(x)", e).maclaurin("x", 25, e).unwrap();
+    let x = String::from("x");
+    for v in [0.5, -1., 2.].iter() {
+        let (mut sum, mut t) = (0., 1.);
+        for k in 0..=25 {
+            sum += t;
+            t *= v / (k + 1) as f64;
+        }
+        let d = s.eval(&x, &vec![*v], e) - sum;
+        assert!(d.abs() < 1e-12 * sum.abs(), "{}", v);
+    }
+    // 21!は有理数に収まらないので21次の係数は定数の式になる
+    let c = parse_expr("exp(x)", e)
+        .taylor_coeffs("x", Expr::new_num(0, e), 25, e)
+        .unwrap();
+    assert!(c[20].is_const() && !c[21].is_const());
+    let f21: f64 = (1..=21).map(|k| k as f64).product();
+    assert!((c[21].eval(&x, &vec![0.], e) * f21 - 1.).abs() < 1e-12);
+
+    // 定数項0の級数で割るときはエラー
+    let f = parse_expr("sin(x) / x", e);
+    assert_eq!(f.maclaurin("x", 4, e), Err(NoSeries((*f).clone())));
+}
+
+#[test]
+fn taylor_series() {
+    let e = &Environment::new();
+    // 記号的な点の周り
+    let s = parse_expr("exp(x)", e)
+        .series("x", Expr::new_num(1, e), 3, e)
+        .unwrap();
+    let expected = parse_expr(
+        "exp(1) + exp(1)*(x - 1) + 1/2*exp(1)*(x - 1)^2 + 1/6*exp(1)*(x - 1)^3",
+        e,
+    );
+    let x = String::from("x");
+    for v in [0.5, 1., 1.3].iter() {
+        let d = s.eval(&x, &vec![*v], e) - expected.eval(&x, &vec![*v], e);
+        assert!(d.abs() < 1e-12);
+    }
+
+    // 係数はn回微分と一致する
+    let f = parse_expr("exp(sin(x)) * cos(x) + x ^ x", e);
+    let coeffs = f.taylor_coeffs("x", Expr::new_num(1, e), 4, e).unwrap();
+    let mut d = f.clone();
+    let mut fact = 1.;
+    for (k, c) in coeffs.iter().enumerate() {
+        if 0 < k {
+            d = d.diff("x", e).reduce(e);
+            fact *= k as f64;
+        }
+        let expected = d.eval(&x, &vec![1.], e) / fact;
+        assert!((c.eval(&x, &vec![1.], e) - expected).abs() < 1e-9);
+    }
+
+    // 有理数の点なら高次でも係数は厳密な有理数になる
+    let f = parse_expr("exp(x) * sin(x) / (1 - x)", e);
+    let coeffs = f.taylor_coeffs("x", Expr::new_num(0, e), 15, e).unwrap();
+    assert!(coeffs.iter().all(|c| c.is_const()));
+    let approx = coeffs
+        .iter()
+        .enumerate()
+        .map(|(k, c)| c.eval(&x, &vec![0.], e) * 0.1f64.powi(k as i32))
+        .sum::<f64>();
+    assert!((approx - f.eval(&x, &vec![0.1], e)).abs() < 1e-15);
+}
@@ -0,0 +1,312 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Env, Expr, Rc, Var};
+#[cfg(test)]
+use super::parse::*;
+
+// 反復を終えた理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Converged,
+    MaxIterations,
+    // Jacobianが特異で探索方向が求まらない
+    SingularJacobian,
+    // 直線探索で残差が減らない
+    LineSearchFailed,
+    // 残差かNewton方向が有限でない
+    NonFinite,
+}
+
+// xは変数idの順. historyは各反復の残差ノルム
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewtonResult {
+    pub x: Vec<f64>,
+    pub status: Status,
+    pub iterations: usize,
+    pub residual_norm: f64,
+    pub history: Vec<f64>,
+}
+
+impl NewtonResult {
+    pub fn converged(&self) -> bool {
+        self.status == Status::Converged
+    }
+}
+
+// 残差の最大値が tol 以下になれば収束とする
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NewtonOptions {
+    pub tol: f64,
+    pub max_iter: usize,
+    pub max_backtracks: usize,
+    // Armijo条件の係数
+    pub armijo: f64,
+}
+
+impl Default for NewtonOptions {
+    fn default() -> Self {
+        NewtonOptions {
+            tol: 1e-12,
+            max_iter: 100,
+            max_backtracks: 30,
+            armijo: 1e-4,
+        }
+    }
+}
+
+impl NewtonOptions {
+    pub fn new() -> Self {
+        NewtonOptions::default()
+    }
+
+    pub fn tol(mut self, tol: f64) -> Self {
+        self.tol = tol;
+        self
+    }
+
+    pub fn max_iter(mut self, n: usize) -> Self {
+        self.max_iter = n;
+        self
+    }
+
+    pub fn max_backtracks(mut self, n: usize) -> Self {
+        self.max_backtracks = n;
+        self
+    }
+}
+
+pub(crate) fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+pub(crate) fn norm(v: &[f64]) -> f64 {
+    dot(v, v).sqrt()
+}
+
+// NaNがあればNaNを返す
+pub(crate) fn max_abs(v: &[f64]) -> f64 {
+    v.iter().fold(0., |m: f64, x| {
+        if x.is_nan() || m < x.abs() {
+            x.abs()
+        } else {
+            m
+        }
+    })
+}
+
+// 部分ピボット付きGauss消去で a x = b を解く
+pub(crate) fn lin_solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    let scale = a.iter().map(|r| max_abs(r)).fold(0., f64::max);
+    for col in 0..n {
+        let p = (col..n)
+            .max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))
+            .unwrap();
+        if a[p][col].is_nan() || a[p][col].abs() <= scale * 1e-14 {
+            return None;
+        }
+        a.swap(col, p);
+        b.swap(col, p);
+        let (top, rest) = a.split_at_mut(col + 1);
+        let pivot = &top[col];
+        let bc = b[col];
+        for (row, br) in rest.iter_mut().zip(b[col + 1..].iter_mut()) {
+            let k = row[col] / pivot[col];
+            for (x, y) in row[col..].iter_mut().zip(&pivot[col..]) {
+                *x -= k * y;
+            }
+            *br -= k * bc;
+        }
+    }
+    let mut x = vec![0.; n];
+    for r in (0..n).rev() {
+        let s: f64 = (r + 1..n).map(|c| a[r][c] * x[c]).sum();
+        x[r] = (b[r] - s) / a[r][r];
+    }
+    Some(x)
+}
+
+// F(x) = 0 を解く. Jacobianの計算グラフは一度だけ作る
+#[derive(Debug, Clone)]
+pub struct Newton {
+    residuals: Vec<Rc<Expr>>,
+    vars: Vec<Var>,
+    deriv: Deriv,
+}
+
+impl Newton {
+    pub fn new(residuals: Vec<Rc<Expr>>, vars: &str, env: &Env) -> Self {
+        let parsed = parse_vars(vars, env);
+        assert!(
+            residuals.len() == parsed.len(),
+            "number of equations and unknowns differ"
+        );
+        let first = vars.split_whitespace().next().unwrap();
+        Newton {
+            deriv: Deriv::new_multi(residuals.clone(), env, first),
+            residuals,
+            vars: parsed,
+        }
+    }
+
+    pub fn residual(&self, x: &Vec<f64>) -> Vec<f64> {
+        self.residuals
+            .iter()
+            .map(|f| f.eval_internal(&self.vars, x))
+            .collect()
+    }
+
+    // i行目はi番目の残差の勾配
+    pub fn jacobian(&self, x: &[f64]) -> Vec<Vec<f64>> {
+        (0..self.residuals.len())
+            .map(|i| {
+                let mut seed = vec![0.; self.residuals.len()];
+                seed[i] = 1.;
+                self.deriv.vjp_internal(&self.vars, x, &seed)
+            })
+            .collect()
+    }
+
+    // 減衰Newton法. 1/2 |F|^2 が十分減るまで歩幅を半分にする
+    pub fn solve(&self, x0: &[f64], opts: &NewtonOptions) -> NewtonResult {
+        let mut x = x0.to_vec();
+        let mut f = self.residual(&x);
+        let mut history = vec![norm(&f)];
+        let mut status = Status::MaxIterations;
+        let mut iterations = 0;
+        while iterations < opts.max_iter {
+            if !max_abs(&f).is_finite() {
+                status = Status::NonFinite;
+                break;
+            } else if max_abs(&f) <= opts.tol {
+                status = Status::Converged;
+                break;
+            }
+            iterations += 1;
+            let rhs: Vec<f64> = f.iter().map(|v| -v).collect();
+            let dx = match lin_solve(self.jacobian(&x), rhs) {
+                Some(dx) => dx,
+                None => {
+                    status = Status::SingularJacobian;
+                    break;
+                }
+            };
+            if !max_abs(&dx).is_finite() {
+                status = Status::NonFinite;
+                break;
+            }
+            // Newton方向での 1/2 |F|^2 の方向微分は -|F|^2
+            let phi = norm(&f).powi(2) / 2.;
+            let mut t = 1.;
+            let mut accepted = None;
+            for _ in 0..=opts.max_backtracks {
+                let xt: Vec<f64> = x.iter().zip(&dx).map(|(a, d)| a + t * d).collect();
+                let ft = self.residual(&xt);
+                let phit = norm(&ft).powi(2) / 2.;
+                if phit.is_finite() && phit <= (1. - 2. * opts.armijo * t) * phi {
+                    accepted = Some((xt, ft));
+                    break;
+                }
+                t /= 2.;
+            }
+            match accepted {
+                Some((xt, ft)) => {
+                    x = xt;
+                    f = ft;
+                    history.push(norm(&f));
+                }
+                None => {
+                    status = Status::LineSearchFailed;
+                    break;
+                }
+            }
+        }
+        if status == Status::MaxIterations && max_abs(&f) <= opts.tol {
+            status = Status::Converged;
+        }
+        NewtonResult {
+            x,
+            status,
+            iterations,
+            residual_norm: norm(&f),
+            history,
+        }
+    }
+}
+
+impl Expr {
+    // スカラー方程式 self = 0 を x0 から解く
+    pub fn newton(
+        self: &Rc<Expr>,
+        v: &str,
+        x0: f64,
+        opts: &NewtonOptions,
+        env: &Env,
+    ) -> NewtonResult {
+        Newton::new(vec![self.clone()], v, env).solve(&[x0], opts)
+    }
+}
+
+#[test]
+fn newton_scalar() {
+    let e = &Environment::new();
+    let opts = NewtonOptions::new();
+    let r = parse_expr("x^2 - 2", e).newton("x", 1., &opts, e);
+    assert!(r.converged());
+    assert!((r.x[0] - 2f64.sqrt()).abs() < 1e-12);
+    // 二次収束する
+    assert!(r.iterations <= 6, "{:?}", r);
+
+    let r = parse_expr("cos(x) - x", e).newton("x", 0., &opts, e);
+    assert!(r.converged() && (r.x[0] - 0.739_085_133_215_160_6).abs() < 1e-12);
+
+    // tanh は減衰なしだと |x0| > 1.09 で発散する
+    let tanh = parse_expr("(exp(x) - exp(0 - x)) / (exp(x) + exp(0 - x))", e);
+    let r = tanh.newton("x", 2., &opts, e);
+    assert!(r.converged() && r.x[0].abs() < 1e-12, "{:?}", r);
+    assert!(r.history.windows(2).all(|w| w[1] < w[0]));
+
+    // 実根がない
+    let r = parse_expr("x^2 + 1", e).newton("x", 0.5, &opts.max_iter(20), e);
+    assert!(!r.converged());
+}
+
+#[test]
+fn newton_system() {
+    let e = &Environment::new();
+    let fs = vec![parse_expr("x^2 + y^2 - 4", e), parse_expr("x * y - 1", e)];
+    let solver = Newton::new(fs, "x y", e);
+    let r = solver.solve(&[2., 0.3], &NewtonOptions::new());
+    assert!(r.converged(), "{:?}", r);
+    let (x, y) = (r.x[0], r.x[1]);
+    assert!((x * x + y * y - 4.).abs() < 1e-12 && (x * y - 1.).abs() < 1e-12);
+    assert!(r.residual_norm <= 1e-12 && r.iterations == r.history.len() - 1);
+
+    // 数値微分のJacobianと一致する
+    let p = vec![0.7, -1.3];
+    let j = solver.jacobian(&p);
+    for k in 0..2 {
+        let h = 1e-6;
+        let (mut a, mut b) = (p.clone(), p.clone());
+        a[k] += h;
+        b[k] -= h;
+        let (fa, fb) = (solver.residual(&a), solver.residual(&b));
+        for i in 0..2 {
+            assert!((j[i][k] - (fa[i] - fb[i]) / (2. * h)).abs() < 1e-6);
+        }
+    }
+
+    let fs = vec![
+        parse_expr("x + y - 1", e),
+        parse_expr("2 * x + 2 * y - 3", e),
+    ];
+    let r = Newton::new(fs, "x y", e).solve(&[0., 0.], &NewtonOptions::new());
+    assert_eq!(r.status, Status::SingularJacobian);
+
+    // 定義域の外から始めると残差がNaNになる
+    let fs = vec![parse_expr("x^(1/2) + y - 3", e), parse_expr("x - y + 1", e)];
+    let r = Newton::new(fs, "x y", e).solve(&[-1., 0.], &NewtonOptions::new());
+    assert_eq!(r.status, Status::NonFinite);
+    assert!(!r.converged() && r.residual_norm.is_nan());
+}
@@ -0,0 +1,266 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, BTreeSet, Env, Var};
+#[cfg(test)]
+use super::expr::{Expr, Rc};
+#[cfg(test)]
+use super::parse::*;
+
+// 圧縮行形式. i行目の非零は indices[indptr[i]..indptr[i+1]] 列にある
+#[derive(Debug, Clone, PartialEq)]
+pub struct Csr {
+    pub nrows: usize,
+    pub ncols: usize,
+    pub indptr: Vec<usize>,
+    pub indices: Vec<usize>,
+    pub data: Vec<f64>,
+}
+
+impl Csr {
+    fn from_pattern(rows: &[BTreeSet<usize>], ncols: usize) -> Self {
+        let mut indptr = vec![0];
+        let mut indices = vec![];
+        for r in rows {
+            indices.extend(r.iter().cloned());
+            indptr.push(indices.len());
+        }
+        let nnz = indices.len();
+        Csr {
+            nrows: rows.len(),
+            ncols,
+            indptr,
+            indices,
+            data: vec![0.; nnz],
+        }
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        let row = &self.indices[self.indptr[i]..self.indptr[i + 1]];
+        match row.binary_search(&j) {
+            Ok(k) => self.data[self.indptr[i] + k],
+            Err(_) => 0.,
+        }
+    }
+
+    pub fn to_dense(&self) -> Vec<Vec<f64>> {
+        (0..self.nrows)
+            .map(|i| (0..self.ncols).map(|j| self.get(i, j)).collect())
+            .collect()
+    }
+}
+
+// 同じ行に非零を持つ列どうしが同じ色にならないように貪欲に塗る
+fn color_columns(rows: &[BTreeSet<usize>], ncols: usize) -> Vec<usize> {
+    let mut cols: Vec<Vec<usize>> = vec![vec![]; ncols];
+    for (i, r) in rows.iter().enumerate() {
+        for &j in r {
+            cols[j].push(i);
+        }
+    }
+    let mut colors = vec![usize::MAX; ncols];
+    for j in 0..ncols {
+        let used: BTreeSet<usize> = cols[j]
+            .iter()
+            .flat_map(|i| rows[*i].iter())
+            .map(|k| colors[*k])
+            .collect();
+        colors[j] = (0..).find(|c| !used.contains(c)).unwrap();
+    }
+    colors
+}
+
+fn transpose(rows: &[BTreeSet<usize>], ncols: usize) -> Vec<BTreeSet<usize>> {
+    let mut res = vec![BTreeSet::new(); ncols];
+    for (i, r) in rows.iter().enumerate() {
+        for &j in r {
+            res[j].insert(i);
+        }
+    }
+    res
+}
+
+fn ncolors(colors: &[usize]) -> usize {
+    colors.iter().map(|c| c + 1).max().unwrap_or(0)
+}
+
+// 列を塗ったならJVP, 行を塗ったならVJPを色の数だけ流す
+#[derive(Debug, Clone)]
+enum Coloring {
+    Columns(Vec<usize>),
+    Rows(Vec<usize>),
+}
+
+// パターンと彩色は一度だけ求めて, 評価のたびに使い回す
+#[derive(Debug, Clone)]
+pub struct SparseJacobian {
+    vars: Vec<Var>,
+    pub pattern: Csr,
+    coloring: Coloring,
+}
+
+impl SparseJacobian {
+    pub fn new(d: &Deriv, vars: &str, env: &Env) -> Self {
+        let vars = parse_vars(vars, env);
+        let rows = d.jacobian_pattern(&vars);
+        let pattern = Csr::from_pattern(&rows, vars.len());
+        let by_col = color_columns(&rows, vars.len());
+        let by_row = color_columns(&transpose(&rows, vars.len()), rows.len());
+        let coloring = if ncolors(&by_col) <= ncolors(&by_row) {
+            Coloring::Columns(by_col)
+        } else {
+            Coloring::Rows(by_row)
+        };
+        SparseJacobian {
+            vars,
+            pattern,
+            coloring,
+        }
+    }
+
+    // 必要なsweepの回数
+    pub fn sweeps(&self) -> usize {
+        match &self.coloring {
+            Coloring::Columns(c) | Coloring::Rows(c) => ncolors(c),
+        }
+    }
+
+    pub fn eval(&self, d: &Deriv, vals: &[f64]) -> Csr {
+        let mut res = self.pattern.clone();
+        let (indptr, indices) = (&res.indptr, &res.indices);
+        match &self.coloring {
+            Coloring::Columns(colors) => {
+                for c in 0..ncolors(colors) {
+                    let tangent: Vec<f64> = colors
+                        .iter()
+                        .map(|k| if *k == c { 1. } else { 0. })
+                        .collect();
+                    let jv = d.jvp_internal(&self.vars, vals, &tangent);
+                    for i in 0..res.nrows {
+                        for k in indptr[i]..indptr[i + 1] {
+                            if colors[indices[k]] == c {
+                                res.data[k] = jv[i];
+                            }
+                        }
+                    }
+                }
+            }
+            Coloring::Rows(colors) => {
+                for c in 0..ncolors(colors) {
+                    let cotangent: Vec<f64> = colors
+                        .iter()
+                        .map(|k| if *k == c { 1. } else { 0. })
+                        .collect();
+                    let vj = d.vjp_internal(&self.vars, vals, &cotangent);
+                    for i in (0..res.nrows).filter(|i| colors[*i] == c) {
+                        for k in indptr[i]..indptr[i + 1] {
+                            res.data[k] = vj[indices[k]];
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+// rootのHessian. 列を塗ってHessian-vector積を色の数だけ求める
+#[derive(Debug, Clone)]
+pub struct SparseHessian {
+    vars: Vec<Var>,
+    pub pattern: Csr,
+    colors: Vec<usize>,
+}
+
+impl SparseHessian {
+    pub fn new(d: &Deriv, vars: &str, env: &Env) -> Self {
+        let vars = parse_vars(vars, env);
+        let rows = d.hessian_pattern(&vars);
+        SparseHessian {
+            pattern: Csr::from_pattern(&rows, vars.len()),
+            colors: color_columns(&rows, vars.len()),
+            vars,
+        }
+    }
+
+    pub fn sweeps(&self) -> usize {
+        ncolors(&self.colors)
+    }
+
+    pub fn eval(&self, d: &Deriv, vals: &[f64]) -> Csr {
+        let mut res = self.pattern.clone();
+        for c in 0..self.sweeps() {
+            let dir: Vec<f64> = self
+                .colors
+                .iter()
+                .map(|k| if *k == c { 1. } else { 0. })
+                .collect();
+            let hv = d.hvp_internal(&self.vars, vals, &dir);
+            for (i, h) in hv.iter().enumerate() {
+                for k in res.indptr[i]..res.indptr[i + 1] {
+                    if self.colors[res.indices[k]] == c {
+                        res.data[k] = *h;
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+#[test]
+fn sparse_jacobian_and_hessian() {
+    let e = &Environment::new();
+    let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * (1. + b.abs());
+    let n = 12;
+    let names: Vec<String> = (0..n).map(|i| format!("q{}", i)).collect();
+    let vars = names.join(" ");
+    // 隣り合う変数だけが絡む残差
+    let residuals: Vec<Rc<Expr>> = (0..n)
+        .map(|i| {
+            let l = &names[(i + n - 1) % n];
+            let r = &names[(i + 1) % n];
+            let s = format!("{}^2 - {} * sin({})", names[i], r, l);
+            parse_expr(&s, e)
+        })
+        .collect();
+    let vals: Vec<f64> = (0..n).map(|i| 0.2 + 0.1 * i as f64).collect();
+
+    let d = Deriv::new_multi(residuals.clone(), e, &names[0]);
+    let jac = SparseJacobian::new(&d, &vars, e);
+    assert_eq!(jac.pattern.nnz(), 3 * n);
+    assert!(jac.sweeps() <= 4);
+    for _ in 0..2 {
+        let j = jac.eval(&d, &vals).to_dense();
+        for (row, f) in j.iter().zip(&residuals) {
+            for (a, b) in row.iter().zip(&f.dual_grad(&vars, &vals, e)) {
+                assert!(close(*a, *b), "{} {}", a, b);
+            }
+        }
+    }
+
+    // Σ r_i^2 のHessianは帯状
+    let mut src = String::from("0");
+    for i in 0..n {
+        let l = &names[(i + n - 1) % n];
+        let r = &names[(i + 1) % n];
+        src = format!("{} + ({}^2 - {} * sin({}))^2", src, names[i], r, l);
+    }
+    let f = parse_expr(&src, e);
+    let d = Deriv::new(f, e, &names[0]);
+    let hess = SparseHessian::new(&d, &vars, e);
+    assert!(hess.sweeps() < n);
+    let h = hess.eval(&d, &vals);
+    for j in 0..n {
+        let mut unit = vec![0.; n];
+        unit[j] = 1.;
+        let col = d.hvp(&vars, &vals, &unit, e);
+        for (i, c) in col.iter().enumerate() {
+            assert!(close(h.get(i, j), *c), "{} {}: {} {}", i, j, h.get(i, j), c);
+        }
+    }
+}
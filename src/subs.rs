@@ -0,0 +1,103 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{Env, Expr, HashMap, Rc, Var};
+#[cfg(test)]
+use super::parse::*;
+
+impl Expr {
+    // 部分式を置き換える. 置き換えた結果もEnvironmentで共有される
+    pub fn subs(&self, map: &HashMap<Rc<Expr>, Rc<Expr>>, reduce: bool, env: &Env) -> Rc<Expr> {
+        // 共有されている部分式はポインタで引く
+        let ptrs: HashMap<*const Expr, Rc<Expr>> = map
+            .iter()
+            .map(|(k, v)| {
+                let k = env.borrow_mut().extend_expr((**k).clone());
+                (Rc::as_ptr(&k), v.clone())
+            })
+            .collect();
+        let me = env.borrow_mut().extend_expr(self.clone());
+        let res = me.subs_internal(map, &ptrs, &mut HashMap::new(), env);
+        if reduce {
+            res.reduce(env)
+        } else {
+            res
+        }
+    }
+
+    pub fn subs_vars(&self, map: &HashMap<Var, Rc<Expr>>, reduce: bool, env: &Env) -> Rc<Expr> {
+        let map = map
+            .iter()
+            .map(|(v, to)| (env.borrow_mut().extend_expr(Expr::Var(*v)), to.clone()))
+            .collect();
+        self.subs(&map, reduce, env)
+    }
+
+    fn subs_internal(
+        self: &Rc<Expr>,
+        map: &HashMap<Rc<Expr>, Rc<Expr>>,
+        ptrs: &HashMap<*const Expr, Rc<Expr>>,
+        memo: &mut HashMap<*const Expr, Rc<Expr>>,
+        env: &Env,
+    ) -> Rc<Expr> {
+        let p = Rc::as_ptr(self);
+        if let Some(to) = ptrs.get(&p).or_else(|| memo.get(&p)) {
+            return to.clone();
+        }
+        let res = match &**self {
+            Expr::UnOp { op, exp } => {
+                let exp = exp.subs_internal(map, ptrs, memo, env);
+                Expr::new_unop(*op, exp, env)
+            }
+            Expr::BinOp { op, exp1, exp2 } => {
+                let exp1 = exp1.subs_internal(map, ptrs, memo, env);
+                let exp2 = exp2.subs_internal(map, ptrs, memo, env);
+                Expr::new_binop(*op, exp1, exp2, env)
+            }
+            // 葉は共有されていないこともあるので値で引く
+            leaf => match map.get(leaf) {
+                Some(to) => to.clone(),
+                None => env.borrow_mut().extend_expr(leaf.clone()),
+            },
+        };
+        memo.insert(p, res.clone());
+        res
+    }
+}
+
+#[test]
+fn subs_var_and_subexpr() {
+    let e = &Environment::new();
+    let f = parse_expr("x^2 + sin(x) * cos(x^2)", e);
+    let x = e.borrow().search_var(&String::from("x")).unwrap();
+    let mut map = HashMap::new();
+    map.insert(x, parse_expr("y + 1", e));
+    let g = f.subs_vars(&map, false, e);
+    assert_eq!(g, parse_expr("(y + 1)^2 + sin(y + 1) * cos((y + 1)^2)", e));
+    // 共有が保たれる
+    fn find(g: &Rc<Expr>, target: &Expr, found: &mut Vec<Rc<Expr>>) {
+        if **g == *target {
+            found.push(g.clone());
+        }
+        match &**g {
+            Expr::UnOp { exp, .. } => find(exp, target, found),
+            Expr::BinOp { exp1, exp2, .. } => {
+                find(exp1, target, found);
+                find(exp2, target, found);
+            }
+            _ => (),
+        }
+    }
+    let mut found = vec![];
+    find(&g, &parse_expr("y + 1", e), &mut found);
+    assert_eq!(found.len(), 3);
+    assert!(found.iter().all(|p| Rc::ptr_eq(p, &found[0])));
+
+    let mut map = HashMap::new();
+    map.insert(parse_expr("x^2", e), parse_expr("t", e));
+    assert_eq!(f.subs(&map, false, e), parse_expr("t + sin(x) * cos(t)", e));
+
+    let mut map = HashMap::new();
+    map.insert(x, Expr::new_num(0, e));
+    let g = parse_expr("x*y + exp(x)", e).subs_vars(&map, true, e);
+    assert_eq!(g, Expr::new_num(1, e));
+}
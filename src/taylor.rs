@@ -0,0 +1,259 @@
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars, Bop, Env, Expr, HashMap, Rc, Uop, Var};
+#[cfg(test)]
+use super::parse::*;
+
+// 数値の打ち切り冪級数 a_0 + a_1 h + ... + a_order h^order
+pub type Coeffs = Vec<f64>;
+
+// 環境にない変数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownVar(pub String);
+
+// Σ_{j=1..k} j a_j b_{k-j}
+fn weighted_conv(a: &[f64], b: &[f64], k: usize) -> f64 {
+    (1..=k).map(|j| j as f64 * a[j] * b[k - j]).sum()
+}
+
+fn mul(a: &[f64], b: &[f64]) -> Coeffs {
+    (0..a.len())
+        .map(|k| (0..=k).map(|j| a[j] * b[k - j]).sum())
+        .collect()
+}
+
+// c = a / b: c_k = (a_k - Σ_{j=1..k} b_j c_{k-j}) / b_0
+fn div(a: &[f64], b: &[f64]) -> Coeffs {
+    let mut c: Coeffs = vec![];
+    for k in 0..a.len() {
+        let s: f64 = (1..=k).map(|j| b[j] * c[k - j]).sum();
+        c.push((a[k] - s) / b[0]);
+    }
+    c
+}
+
+// e = exp(a): e_k = (1/k) Σ j a_j e_{k-j}
+fn exp(a: &[f64]) -> Coeffs {
+    let mut e = vec![a[0].exp()];
+    for k in 1..a.len() {
+        let ek = weighted_conv(a, &e, k) / k as f64;
+        e.push(ek);
+    }
+    e
+}
+
+// l = log(a): l_k = (a_k - (1/k) Σ_{j=1..k-1} j l_j a_{k-j}) / a_0
+fn log(a: &[f64]) -> Coeffs {
+    let mut l = vec![a[0].ln()];
+    for k in 1..a.len() {
+        let s: f64 = (1..k).map(|j| j as f64 * l[j] * a[k - j]).sum();
+        l.push((a[k] - s / k as f64) / a[0]);
+    }
+    l
+}
+
+fn sin_cos(a: &[f64]) -> (Coeffs, Coeffs) {
+    let mut s = vec![a[0].sin()];
+    let mut c = vec![a[0].cos()];
+    for k in 1..a.len() {
+        let sk = weighted_conv(a, &c, k) / k as f64;
+        let ck = -weighted_conv(a, &s, k) / k as f64;
+        s.push(sk);
+        c.push(ck);
+    }
+    (s, c)
+}
+
+// t = tan(a): t' = (1 + t^2) a'
+fn tan(a: &[f64]) -> Coeffs {
+    let mut t = vec![a[0].tan()];
+    let mut w = vec![1. + t[0] * t[0]];
+    for k in 1..a.len() {
+        t.push(weighted_conv(a, &w, k) / k as f64);
+        w.push((0..=k).map(|i| t[i] * t[k - i]).sum());
+    }
+    t
+}
+
+// p = a^r (rは定数): p_k = (1/(k a_0)) Σ ((r+1) j - k) a_j p_{k-j}
+fn pow_const(a: &[f64], r: f64) -> Coeffs {
+    let mut p = vec![a[0].powf(r)];
+    for k in 1..a.len() {
+        let s: f64 = (1..=k)
+            .map(|j| ((r + 1.) * j as f64 - k as f64) * a[j] * p[k - j])
+            .sum();
+        p.push(s / (k as f64 * a[0]));
+    }
+    p
+}
+
+// 整数乗は掛け算で求める. a_0 = 0 でもよい
+fn pow_int(a: &[f64], n: i64) -> Coeffs {
+    let mut one = vec![0.; a.len()];
+    one[0] = 1.;
+    let p = (0..n.abs()).fold(one.clone(), |acc, _| mul(&acc, a));
+    if n < 0 {
+        div(&one, &p)
+    } else {
+        p
+    }
+}
+
+struct TaylorEval<'a> {
+    v: Var,
+    vars: &'a Vec<Var>,
+    vals: &'a Vec<f64>,
+    order: usize,
+    memo: HashMap<*const Expr, Coeffs>,
+}
+
+impl<'a> TaylorEval<'a> {
+    fn constant(&self, c: f64) -> Coeffs {
+        let mut res = vec![0.; self.order + 1];
+        res[0] = c;
+        res
+    }
+
+    fn coeffs(&mut self, expr: &Rc<Expr>) -> Coeffs {
+        let ptr = Rc::as_ptr(expr);
+        if let Some(c) = self.memo.get(&ptr) {
+            return c.clone();
+        }
+        let res = match &**expr {
+            Expr::Num(_) => self.constant(expr.eval_internal(self.vars, self.vals)),
+            Expr::Var(v) => {
+                let mut res = self.constant(expr.eval_internal(self.vars, self.vals));
+                if *v == self.v && 0 < self.order {
+                    res[1] = 1.;
+                }
+                res
+            }
+            Expr::UnOp { op, exp: arg } => {
+                let a = self.coeffs(arg);
+                match op {
+                    Uop::Neg => a.iter().map(|c| -c).collect(),
+                    Uop::Exp => exp(&a),
+                    Uop::Log => log(&a),
+                    Uop::Sin => sin_cos(&a).0,
+                    Uop::Cos => sin_cos(&a).1,
+                    Uop::Tan => tan(&a),
+                }
+            }
+            Expr::BinOp { op, exp1, exp2 } => {
+                let a = self.coeffs(exp1);
+                let b = self.coeffs(exp2);
+                match op {
+                    Bop::Add => a.iter().zip(&b).map(|(x, y)| x + y).collect(),
+                    Bop::Sub => a.iter().zip(&b).map(|(x, y)| x - y).collect(),
+                    Bop::Mul => mul(&a, &b),
+                    Bop::Div => div(&a, &b),
+                    Bop::Pow if b[1..].iter().all(|c| *c == 0.) => match **exp2 {
+                        Expr::Num(n) if n.is_integer() && n.numer().abs() <= 64 => {
+                            pow_int(&a, *n.numer())
+                        }
+                        _ => pow_const(&a, b[0]),
+                    },
+                    // a^b = exp(b log a)
+                    Bop::Pow => exp(&mul(&b, &log(&a))),
+                }
+            }
+        };
+        self.memo.insert(ptr, res.clone());
+        res
+    }
+}
+
+impl Expr {
+    // vで展開したTaylor係数 a_0, ..., a_order を数値で求める. vars, valsはeval_internalと同じ
+    pub fn taylor_eval_internal(
+        self: &Rc<Expr>,
+        v: Var,
+        order: usize,
+        vars: &Vec<Var>,
+        vals: &Vec<f64>,
+    ) -> Coeffs {
+        let mut t = TaylorEval {
+            v,
+            vars,
+            vals,
+            order,
+            memo: HashMap::new(),
+        };
+        t.coeffs(self)
+    }
+
+    // f, df/dv, ..., d^order f/dv^order を一度の走査で求める
+    pub fn taylor_eval(
+        self: &Rc<Expr>,
+        v: &str,
+        order: usize,
+        vars: &str,
+        vals: &Vec<f64>,
+        e: &Env,
+    ) -> Result<Vec<f64>, UnknownVar> {
+        let v = match e.borrow().search_var(&String::from(v)) {
+            Some(var) => var,
+            None => return Err(UnknownVar(String::from(v))),
+        };
+        let coeffs = self.taylor_eval_internal(v, order, &parse_vars(vars, e), vals);
+        let mut fact = 1.;
+        Ok(coeffs
+            .iter()
+            .enumerate()
+            .map(|(k, c)| {
+                if 0 < k {
+                    fact *= k as f64;
+                }
+                c * fact
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn taylor_eval_derivatives() {
+    let e = &Environment::new();
+    let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * (1. + b.abs());
+
+    // 記号微分をk回繰り返したものと一致する
+    let f = parse_expr("exp(sin(x * y)) / (1 + x^2) + tan(x) * log(y) + x ^ y", e);
+    let ds = f.taylor_eval("x", 4, "x y", &vec![0.7, 1.3], e).unwrap();
+    let mut d = f.clone();
+    for (k, dk) in ds.iter().enumerate() {
+        if 0 < k {
+            d = d.diff("x", e).reduce(e);
+        }
+        let expected = d.eval("x y", &vec![0.7, 1.3], e);
+        assert!(close(*dk, expected), "{}: {} {}", k, dk, expected);
+    }
+
+    // 高次でも式が膨らまない
+    let ds = parse_expr("sin(2 * x)", e)
+        .taylor_eval("x", 30, "x", &vec![0.], e)
+        .unwrap();
+    for (k, dk) in ds.iter().enumerate() {
+        let expected = match k % 4 {
+            1 => 2f64.powi(k as i32),
+            3 => -(2f64.powi(k as i32)),
+            _ => 0.,
+        };
+        assert!(close(*dk, expected), "{}: {} {}", k, dk, expected);
+    }
+
+    // 0での整数乗, 負の整数乗
+    let ds = parse_expr("x^3 + x^(-2)", e)
+        .taylor_eval("x", 3, "x", &vec![0.], e)
+        .unwrap();
+    assert!(ds[0].is_infinite());
+    let ds = parse_expr("x^3 + (1 + x)^(-2)", e)
+        .taylor_eval("x", 3, "x", &vec![0.], e)
+        .unwrap();
+    for (dk, expected) in ds.iter().zip(&[1., -2., 6., -18.]) {
+        assert!(close(*dk, *expected), "{} {}", dk, expected);
+    }
+
+    // 知らない変数は環境に追加しない
+    let r = parse_expr("x^2", e).taylor_eval("z", 2, "x", &vec![1.], e);
+    assert_eq!(r, Err(UnknownVar(String::from("z"))));
+    assert!(e.borrow().search_var(&String::from("z")).is_none());
+}
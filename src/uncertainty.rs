@@ -0,0 +1,136 @@
+use super::diff::Deriv;
+#[cfg(test)]
+use super::expr::Environment;
+use super::expr::{parse_vars_slots, sort_by_slots, Env, Expr, Rc};
+#[cfg(test)]
+use super::parse::*;
+use std::fmt;
+
+// 一次の誤差伝播の結果. partials, contributions はvarsに書いた順
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uncertainty {
+    pub value: f64,
+    pub sigma: f64,
+    pub partials: Vec<f64>,
+    // 各変数の分散への寄与. 和がsigma^2になる. 相関があると負にもなる
+    pub contributions: Vec<f64>,
+}
+
+impl Uncertainty {
+    // 寄与の割合
+    pub fn fractions(&self) -> Vec<f64> {
+        let var = self.sigma * self.sigma;
+        self.contributions.iter().map(|c| c / var).collect()
+    }
+}
+
+impl fmt::Display for Uncertainty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ± {}", self.value, self.sigma)
+    }
+}
+
+impl Expr {
+    // 独立な変数の標準偏差から sigma_f^2 = Σ (df/dx_i)^2 sigma_i^2
+    pub fn propagate(
+        self: &Rc<Expr>,
+        vars: &str,
+        vals: &[f64],
+        sigmas: &[f64],
+        env: &Env,
+    ) -> Uncertainty {
+        let cov: Vec<Vec<f64>> = (0..sigmas.len())
+            .map(|i| {
+                let mut row = vec![0.; sigmas.len()];
+                row[i] = sigmas[i] * sigmas[i];
+                row
+            })
+            .collect();
+        self.propagate_cov(vars, vals, &cov, env)
+    }
+
+    // 共分散行列を与える. i番目の寄与は g_i Σ_j cov_ij g_j
+    // vals, cov の行と列は vars に書いた順
+    pub fn propagate_cov(
+        self: &Rc<Expr>,
+        vars: &str,
+        vals: &[f64],
+        cov: &[Vec<f64>],
+        env: &Env,
+    ) -> Uncertainty {
+        let (parsed, slots) = parse_vars_slots(vars, env);
+        assert!(
+            parsed.len() == vals.len() && cov.len() == vals.len(),
+            "number of variables and values differ"
+        );
+        let sorted = sort_by_slots(&slots, vals);
+        let first = vars.split_whitespace().next().unwrap();
+        let g = Deriv::new(self.clone(), env, first).vjp_internal(&parsed, &sorted, &[1.]);
+        let partials: Vec<f64> = slots.iter().map(|s| g[*s]).collect();
+        let contributions: Vec<f64> = cov
+            .iter()
+            .zip(&partials)
+            .map(|(row, gi)| gi * row.iter().zip(&partials).map(|(c, gj)| c * gj).sum::<f64>())
+            .collect();
+        Uncertainty {
+            value: self.eval_internal(&parsed, &sorted),
+            sigma: contributions.iter().sum::<f64>().max(0.).sqrt(),
+            partials,
+            contributions,
+        }
+    }
+}
+
+#[test]
+fn propagate_independent() {
+    let e = &Environment::new();
+    // 積の相対誤差は二乗和
+    let u = parse_expr("x * y", e).propagate("x y", &[2., 5.], &[0.02, 0.1], e);
+    assert!((u.value - 10.).abs() < 1e-12);
+    let rel = (0.01f64.powi(2) + 0.02f64.powi(2)).sqrt();
+    assert!((u.sigma / u.value - rel).abs() < 1e-12);
+    assert_eq!(u.partials, vec![5., 2.]);
+    let fr = u.fractions();
+    assert!((fr[0] - 0.2).abs() < 1e-12 && (fr[1] - 0.8).abs() < 1e-12);
+    assert_eq!(format!("{}", u), format!("10 ± {}", u.sigma));
+
+    // 振り子の周期から g = 4 pi^2 l / T^2. 定数倍は相対誤差に効かない
+    let g = parse_expr("4 * l / t^2", e);
+    let u = g.propagate("l t", &[1., 2.], &[0.001, 0.01], e);
+    let rel = (0.001f64.powi(2) + (2. * 0.01 / 2f64).powi(2)).sqrt();
+    assert!((u.sigma / u.value - rel).abs() < 1e-9, "{:?}", u);
+    assert!(u.contributions[1] > u.contributions[0]);
+}
+
+#[test]
+fn propagate_correlated() {
+    let e = &Environment::new();
+    let f = parse_expr("x - y", e);
+    let (sx, sy) = (0.3, 0.2);
+    // 完全相関なら差の誤差は打ち消し合う
+    let cov = vec![vec![sx * sx, sx * sy], vec![sx * sy, sy * sy]];
+    let u = f.propagate_cov("x y", &[1., 1.], &cov, e);
+    assert!((u.sigma - 0.1).abs() < 1e-12, "{:?}", u);
+    let s: f64 = u.contributions.iter().sum();
+    assert!((s - u.sigma * u.sigma).abs() < 1e-12 && u.contributions[1] < 0.);
+
+    // 相関がなければ propagate と一致する
+    let cov = vec![vec![sx * sx, 0.], vec![0., sy * sy]];
+    let a = f.propagate_cov("x y", &[1., 1.], &cov, e);
+    let b = f.propagate("x y", &[1., 1.], &[sx, sy], e);
+    assert_eq!(a, b);
+}
+
+#[test]
+fn propagate_written_order() {
+    let e = &Environment::new();
+    // y は x より先に作られていても vars に書いた順で対応させる
+    let f = parse_expr("y * y + x", e);
+    let u = f.propagate("x y", &[1., 10.], &[0.5, 0.1], e);
+    assert_eq!(u.value, 101.);
+    assert_eq!(u.partials, vec![1., 20.]);
+    assert!((u.contributions[0] - 0.25).abs() < 1e-12 && (u.contributions[1] - 4.).abs() < 1e-12);
+    let cov = vec![vec![0.25, 0.], vec![0., 0.01]];
+    let c = f.propagate_cov("x y", &[1., 10.], &cov, e);
+    assert!(c.partials == u.partials && (c.sigma - u.sigma).abs() < 1e-12);
+}